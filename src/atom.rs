@@ -0,0 +1,168 @@
+//! Renders lists of issues as Atom feeds, so that feed readers can follow them without a github
+//! token of their own.
+
+use std::fmt::Write;
+
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+
+/// Renders `items`, which were fetched from `path`, as an Atom feed with one entry per issue.
+pub(crate) fn render_feed(path: &str, items: &[Value]) -> Result<String, String> {
+    let entries = items
+        .iter()
+        .map(Entry::from_issue)
+        .collect::<Result<Vec<_>, _>>()?;
+    // github's timestamps are all UTC in the same format, so sort as strings.
+    let updated = match entries.iter().map(|entry| entry.updated).max() {
+        Some(updated) => updated.to_owned(),
+        None => time::OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .expect("Formatting the current time can't fail"),
+    };
+
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    let _ = writeln!(feed, "  <id>urn:github-issue-proxy:{}</id>", escape(path));
+    let _ = writeln!(feed, "  <title>{}</title>", escape(path));
+    let _ = writeln!(feed, "  <updated>{}</updated>", escape(&updated));
+    for entry in entries {
+        entry.write(&mut feed);
+    }
+    feed.push_str("</feed>\n");
+    Ok(feed)
+}
+
+struct Entry<'a> {
+    url: &'a str,
+    title: &'a str,
+    updated: &'a str,
+    published: Option<&'a str>,
+    author: Option<&'a str>,
+    author_url: Option<&'a str>,
+    labels: Vec<&'a str>,
+    body: Option<&'a str>,
+}
+
+impl<'a> Entry<'a> {
+    fn from_issue(issue: &'a Value) -> Result<Entry<'a>, String> {
+        let field = |name: &str| issue.get(name).and_then(Value::as_str);
+        let required = |name: &str| {
+            field(name).ok_or_else(|| {
+                format!(
+                    "format=atom only works for lists of issues, but an item had no {:?}",
+                    name
+                )
+            })
+        };
+        let user = |name: &str| issue.get("user")?.get(name)?.as_str();
+        Ok(Entry {
+            url: required("html_url")?,
+            title: required("title")?,
+            updated: required("updated_at")?,
+            published: field("created_at"),
+            author: user("login"),
+            author_url: user("html_url"),
+            labels: issue
+                .get("labels")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|label| label.get("name")?.as_str())
+                .collect(),
+            body: field("body").filter(|body| !body.is_empty()),
+        })
+    }
+
+    fn write(&self, feed: &mut String) {
+        feed.push_str("  <entry>\n");
+        let _ = writeln!(feed, "    <id>{}</id>", escape(self.url));
+        let _ = writeln!(feed, "    <title>{}</title>", escape(self.title));
+        let _ = writeln!(
+            feed,
+            "    <link rel=\"alternate\" href=\"{}\"/>",
+            escape(self.url)
+        );
+        let _ = writeln!(feed, "    <updated>{}</updated>", escape(self.updated));
+        if let Some(published) = self.published {
+            let _ = writeln!(feed, "    <published>{}</published>", escape(published));
+        }
+        // Atom requires an author for every entry, unless the feed has one.
+        feed.push_str("    <author>\n");
+        let _ = writeln!(
+            feed,
+            "      <name>{}</name>",
+            escape(self.author.unwrap_or("ghost"))
+        );
+        if let Some(author_url) = self.author_url {
+            let _ = writeln!(feed, "      <uri>{}</uri>", escape(author_url));
+        }
+        feed.push_str("    </author>\n");
+        for label in &self.labels {
+            let _ = writeln!(feed, "    <category term=\"{}\"/>", escape(label));
+        }
+        if let Some(body) = self.body {
+            let _ = writeln!(
+                feed,
+                "    <content type=\"text\">{}</content>",
+                escape(body)
+            );
+        }
+        feed.push_str("  </entry>\n");
+    }
+}
+
+/// Escapes `text` for use in XML content or attributes, dropping the control characters which XML
+/// doesn't allow at all.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{FFFE}' || c == '\u{FFFF}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_issues_as_entries() {
+        let items = serde_json::json!([
+            {
+                "html_url": "https://github.com/o/r/issues/2",
+                "title": "Fish & <chips>",
+                "updated_at": "2024-02-01T00:00:00Z",
+                "created_at": "2024-01-01T00:00:00Z",
+                "user": {"login": "octocat", "html_url": "https://github.com/octocat"},
+                "labels": [{"name": "bug"}],
+                "body": "Broken\u{0}",
+            },
+            {
+                "html_url": "https://github.com/o/r/issues/1",
+                "title": "Older",
+                "updated_at": "2023-12-01T00:00:00Z",
+                "user": null,
+            },
+        ]);
+        let feed = render_feed("repos/o/r/issues", items.as_array().unwrap()).unwrap();
+        assert!(feed.contains("  <updated>2024-02-01T00:00:00Z</updated>\n"));
+        assert!(feed.contains("<title>Fish &amp; &lt;chips&gt;</title>"));
+        assert!(feed.contains("<name>octocat</name>"));
+        assert!(feed.contains("<name>ghost</name>"));
+        assert!(feed.contains("<category term=\"bug\"/>"));
+        assert!(feed.contains("<content type=\"text\">Broken</content>"));
+        assert_eq!(feed.matches("<entry>").count(), 2);
+
+        let err = render_feed("repos/o/r/commits", &[serde_json::json!({"sha": "abc"})]);
+        assert!(err.unwrap_err().contains("html_url"));
+    }
+}
@@ -0,0 +1,488 @@
+//! Storage for cached responses.
+//!
+//! Responses are held in memory by default, optionally persisted to disk. Alternatively they can be
+//! stored in Redis so that several replicas of the proxy share one cache, or in SQLite.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::http::header::{HeaderMap, HeaderName, HeaderValue};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::disk_cache::DiskCache;
+use crate::{
+    take_aggregated_repos, CacheKey, CacheValue, PageEtag, Refresher, SerializedBody,
+    UpstreamRequest,
+};
+
+#[async_trait]
+pub(crate) trait CacheStore: Send + Sync {
+    async fn get(&self, key: &CacheKey) -> Option<Arc<CacheValue>>;
+
+    /// Stores `value`, keeping it for `retention` (which includes however long it may be served or
+    /// revalidated after going stale).
+    async fn insert(&self, key: CacheKey, value: Arc<CacheValue>, retention: Duration);
+
+    async fn remove(&self, key: &CacheKey);
+
+    async fn keys(&self) -> Vec<CacheKey>;
+
+    /// The keys with `tag` among their `tags`. By default this checks every key, so stores which
+    /// index their keys by tag should look them up instead.
+    async fn keys_tagged(&self, tag: &str) -> Vec<CacheKey> {
+        self.keys()
+            .await
+            .into_iter()
+            .filter(|key| tags(key).iter().any(|key_tag| key_tag == tag))
+            .collect()
+    }
+
+    /// Summarises the store's entries. By default this reads every entry, so stores which can
+    /// summarise more cheaply should.
+    async fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for key in self.keys().await {
+            if let Some(value) = self.get(&key).await {
+                stats.add(&value);
+            }
+        }
+        stats
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct CacheStats {
+    pub(crate) entries: u64,
+    /// The total size of the entries' response bodies.
+    pub(crate) approximate_bytes: u64,
+    pub(crate) oldest_age: Option<Duration>,
+    pub(crate) newest_age: Option<Duration>,
+}
+
+impl CacheStats {
+    fn add(&mut self, value: &CacheValue) {
+        let age = value.generated_at.elapsed();
+        self.entries += 1;
+        self.approximate_bytes += value.body.bytes.len() as u64;
+        self.oldest_age = Some(self.oldest_age.map_or(age, |oldest| oldest.max(age)));
+        self.newest_age = Some(self.newest_age.map_or(age, |newest| newest.min(age)));
+    }
+}
+
+/// Tags for grouping related entries, so that they can be purged together. Currently just the
+/// `owner/repo` (lowercased, as github treats it case-insensitively) of entries for a repository,
+/// or for each repository of aggregated entries.
+pub(crate) fn tags(key: &CacheKey) -> Vec<String> {
+    if key.path == "aggregate/issues" {
+        let mut query = key.query.clone();
+        let repos = take_aggregated_repos(&mut query).unwrap_or_default();
+        return repos.iter().map(|repo| repo.to_lowercase()).collect();
+    }
+    let mut segments = key.path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some("repos"), Some(owner), Some(repo)) if !owner.is_empty() && !repo.is_empty() => {
+            vec![format!("{}/{}", owner, repo).to_lowercase()]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Holds entries in memory, evicting the least useful entries once their total size exceeds a
+/// budget.
+pub(crate) struct MemoryStore {
+    entries: moka::sync::Cache<CacheKey, MemoryEntry>,
+    /// Which keys have each tag, kept in step with `entries` as they're inserted and evicted.
+    tagged: Arc<Mutex<HashMap<String, HashSet<CacheKey>>>>,
+    disk_cache: Option<DiskCache>,
+}
+
+#[derive(Clone)]
+struct MemoryEntry {
+    value: Arc<CacheValue>,
+    retention: Duration,
+    /// Roughly how many bytes the entry takes up.
+    weight: u32,
+}
+
+impl MemoryEntry {
+    fn new(key: &CacheKey, value: Arc<CacheValue>, retention: Duration) -> MemoryEntry {
+        let weight = u32::try_from(key.path.len() + value.body.bytes.len()).unwrap_or(u32::MAX);
+        MemoryEntry {
+            value,
+            retention,
+            weight,
+        }
+    }
+}
+
+struct RetentionExpiry;
+
+impl moka::Expiry<CacheKey, MemoryEntry> for RetentionExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &CacheKey,
+        entry: &MemoryEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(entry.retention)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &CacheKey,
+        entry: &MemoryEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(entry.retention)
+    }
+}
+
+impl MemoryStore {
+    /// Creates a store holding up to roughly `max_bytes` of entries, pre-populated from
+    /// `disk_cache` if one is given.
+    pub(crate) fn new(max_bytes: u64, disk_cache: Option<DiskCache>) -> MemoryStore {
+        let tagged: Arc<Mutex<HashMap<String, HashSet<CacheKey>>>> = Arc::default();
+        let entries = moka::sync::Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(|_key, entry: &MemoryEntry| entry.weight)
+            .expire_after(RetentionExpiry)
+            .eviction_listener({
+                let tagged = tagged.clone();
+                move |key: Arc<CacheKey>, _, cause| {
+                    // A replaced entry's key is still in the cache.
+                    if cause != moka::notification::RemovalCause::Replaced {
+                        untag(&tagged, &key);
+                    }
+                }
+            })
+            .build();
+        let store = MemoryStore {
+            entries,
+            tagged,
+            disk_cache,
+        };
+        if let Some(disk_cache) = &store.disk_cache {
+            for (key, value, retention) in disk_cache.load() {
+                store.insert_entry(key, Arc::new(value), retention);
+            }
+        }
+        store
+    }
+
+    fn insert_entry(&self, key: CacheKey, value: Arc<CacheValue>, retention: Duration) {
+        // Tag before inserting, in case the entry is evicted as soon as it's inserted.
+        {
+            let mut tagged = self.tagged.lock().unwrap();
+            for tag in tags(&key) {
+                tagged.entry(tag).or_default().insert(key.clone());
+            }
+        }
+        let entry = MemoryEntry::new(&key, value, retention);
+        self.entries.insert(key, entry);
+    }
+}
+
+fn untag(tagged: &Mutex<HashMap<String, HashSet<CacheKey>>>, key: &CacheKey) {
+    let mut tagged = tagged.lock().unwrap();
+    for tag in tags(key) {
+        if let Some(keys) = tagged.get_mut(&tag) {
+            keys.remove(key);
+            if keys.is_empty() {
+                tagged.remove(&tag);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &CacheKey) -> Option<Arc<CacheValue>> {
+        self.entries.get(key).map(|entry| entry.value)
+    }
+
+    async fn insert(&self, key: CacheKey, value: Arc<CacheValue>, retention: Duration) {
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.store(&key, &value, retention);
+        }
+        self.insert_entry(key, value, retention);
+    }
+
+    async fn remove(&self, key: &CacheKey) {
+        self.entries.invalidate(key);
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.remove(key);
+        }
+    }
+
+    async fn keys(&self) -> Vec<CacheKey> {
+        self.entries
+            .iter()
+            .map(|(key, _)| CacheKey::clone(&key))
+            .collect()
+    }
+
+    async fn keys_tagged(&self, tag: &str) -> Vec<CacheKey> {
+        let tagged = self.tagged.lock().unwrap();
+        tagged
+            .get(tag)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    async fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for (_, entry) in self.entries.iter() {
+            stats.add(&entry.value);
+        }
+        stats
+    }
+}
+
+/// A stable digest of `key`, for naming entries in stores outside of this process.
+pub(crate) fn key_digest(key: &CacheKey) -> String {
+    let key = serde_json::to_vec(key).expect("Serializing JSON values to a Vec can't fail");
+    format!("{:x}", Sha256::digest(key))
+}
+
+fn ok_status() -> u16 {
+    StatusCode::OK.as_u16()
+}
+
+/// The serialized form of a cache entry, for stores which live outside of this process.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct StoredEntry {
+    pub(crate) key: CacheKey,
+    body: String,
+    #[serde(default)]
+    truncated: bool,
+    #[serde(default)]
+    link: Option<Vec<u8>>,
+    #[serde(default = "ok_status")]
+    status: u16,
+    page_etags: Option<Vec<(String, Vec<u8>)>>,
+    generated_at: SystemTime,
+    expires_at: SystemTime,
+    max_duration: Duration,
+    request_headers: Vec<(String, Vec<u8>)>,
+    request: UpstreamRequest,
+    #[serde(default)]
+    stability: i8,
+}
+
+impl StoredEntry {
+    pub(crate) fn new(key: &CacheKey, value: &CacheValue, retention: Duration) -> StoredEntry {
+        let now = SystemTime::now();
+        StoredEntry {
+            key: key.clone(),
+            body: String::from_utf8(value.body.bytes.to_vec())
+                .expect("Serialized JSON is always valid UTF-8"),
+            truncated: value.body.truncated,
+            status: value.body.status.as_u16(),
+            link: value
+                .body
+                .link
+                .as_ref()
+                .map(|link| link.as_bytes().to_owned()),
+            page_etags: value.page_etags.as_ref().map(|page_etags| {
+                page_etags
+                    .iter()
+                    .map(|page| (page.url.clone(), page.etag.as_bytes().to_owned()))
+                    .collect()
+            }),
+            generated_at: now - Instant::now().duration_since(value.generated_at),
+            expires_at: now + retention,
+            max_duration: value.max_duration,
+            request_headers: value
+                .refresher
+                .without_credentials()
+                .request_headers
+                .iter()
+                .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_owned()))
+                .collect(),
+            request: value.refresher.request.clone(),
+            stability: value.stability,
+        }
+    }
+
+    /// Converts back to an in-memory entry, along with how much longer it should be retained, or
+    /// `None` if it has expired.
+    pub(crate) fn into_entry(self) -> Option<(CacheKey, CacheValue, Duration)> {
+        let now = SystemTime::now();
+        let retention = self.expires_at.duration_since(now).ok()?;
+        // Entries from before the machine booted can't be represented as an Instant.
+        let age = now.duration_since(self.generated_at).unwrap_or_default();
+        let generated_at = Instant::now().checked_sub(age)?;
+        let page_etags = self.page_etags.map(|page_etags| {
+            page_etags
+                .into_iter()
+                .filter_map(|(url, etag)| {
+                    Some(PageEtag {
+                        url,
+                        etag: HeaderValue::from_bytes(&etag).ok()?,
+                    })
+                })
+                .collect()
+        });
+        let request_headers: HeaderMap = self
+            .request_headers
+            .into_iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_bytes(&value).ok()?,
+                ))
+            })
+            .collect();
+        let value = CacheValue {
+            body: SerializedBody {
+                status: StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK),
+                link: self
+                    .link
+                    .and_then(|link| HeaderValue::from_bytes(&link).ok()),
+                ..SerializedBody::from_bytes(Bytes::from(self.body), self.truncated)
+            },
+            page_etags,
+            generated_at,
+            max_duration: self.max_duration,
+            // Entries written before credentials were left out shouldn't bring them back.
+            refresher: Refresher {
+                request_headers,
+                request: self.request,
+            }
+            .without_credentials(),
+            stability: self.stability,
+        };
+        Some((self.key, value, retention))
+    }
+}
+
+impl StoredEntry {
+    /// Serializes the entry, encrypting it if there's a `cipher`.
+    pub(crate) fn to_bytes(&self, cipher: Option<&EntryCipher>) -> Result<Vec<u8>, String> {
+        let bytes = serde_json::to_vec(self).map_err(|err| err.to_string())?;
+        match cipher {
+            Some(cipher) => cipher.seal(&bytes),
+            None => Ok(bytes),
+        }
+    }
+
+    pub(crate) fn from_bytes(
+        bytes: &[u8],
+        cipher: Option<&EntryCipher>,
+    ) -> Result<StoredEntry, String> {
+        let bytes = match cipher {
+            Some(cipher) => std::borrow::Cow::Owned(cipher.open(bytes)?),
+            None => std::borrow::Cow::Borrowed(bytes),
+        };
+        serde_json::from_slice(&bytes).map_err(|err| err.to_string())
+    }
+}
+
+/// Encrypts entries with AES-256-GCM before they're written outside of this process, as responses
+/// from private repositories are sensitive.
+#[derive(Clone)]
+pub(crate) struct EntryCipher {
+    cipher: Aes256Gcm,
+}
+
+impl EntryCipher {
+    const NONCE_LEN: usize = 12;
+
+    /// Takes a 256-bit key as 64 hex characters.
+    pub(crate) fn from_hex(key: &str) -> Result<EntryCipher, String> {
+        let key = hex::decode(key.trim())
+            .map_err(|err| format!("Failed to parse encryption key as hex: {}", err))?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| format!("Encryption keys must be 32 bytes, not {}", key.len()))?;
+        Ok(EntryCipher { cipher })
+    }
+
+    /// Encrypts `plaintext`, prefixing it with the random nonce it was encrypted with.
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut aes_gcm::aead::OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| "Failed to encrypt cache entry".to_owned())?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < Self::NONCE_LEN {
+            return Err("Encrypted cache entry is too short".to_owned());
+        }
+        let (nonce, ciphertext) = sealed.split_at(Self::NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Failed to decrypt cache entry (is it from a different key?)".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_entries_only_open_with_the_same_key() {
+        let cipher = EntryCipher::from_hex(&"ab".repeat(32)).unwrap();
+        let sealed = cipher.seal(b"secret issue").unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert_eq!(cipher.open(&sealed).unwrap(), b"secret issue");
+        let other = EntryCipher::from_hex(&"cd".repeat(32)).unwrap();
+        assert!(other.open(&sealed).is_err());
+        assert!(cipher.open(&sealed[..8]).is_err());
+        assert!(EntryCipher::from_hex("abcd").is_err());
+    }
+
+    #[test]
+    fn stored_entries_leave_out_credentials() {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert("authorization", "token ghp_secret".parse().unwrap());
+        request_headers.insert("cookie", "session=also_secret".parse().unwrap());
+        request_headers.insert("accept", "application/vnd.github+json".parse().unwrap());
+        let key = CacheKey {
+            authorization_hash: Some([1; 32]),
+            path: "repos/a/b/issues".to_owned(),
+            query: None,
+            body_hash: None,
+        };
+        let value = CacheValue {
+            body: SerializedBody::from_bytes(Bytes::from_static(b"[]"), false),
+            page_etags: None,
+            max_duration: Duration::from_secs(60),
+            refresher: Refresher {
+                request_headers,
+                request: UpstreamRequest::Rest {
+                    path: "repos/a/b/issues".to_owned(),
+                    query: None,
+                    limits: Default::default(),
+                    single_page: false,
+                    include_comments: false,
+                },
+            },
+            generated_at: Instant::now(),
+            stability: 0,
+        };
+        let bytes = StoredEntry::new(&key, &value, Duration::from_secs(60))
+            .to_bytes(None)
+            .unwrap();
+        for secret in [b"ghp_secret".as_slice(), b"also_secret"] {
+            assert!(!bytes.windows(secret.len()).any(|window| window == secret));
+        }
+        let (_, value, _) = StoredEntry::from_bytes(&bytes, None)
+            .unwrap()
+            .into_entry()
+            .unwrap();
+        let request_headers = &value.refresher.request_headers;
+        assert!(!request_headers.contains_key("authorization"));
+        assert_eq!(request_headers["accept"], "application/vnd.github+json");
+    }
+}
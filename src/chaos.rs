@@ -0,0 +1,200 @@
+//! Injects faults into proxied responses, only if the proxy is started with `--chaos`, so that its
+//! clients can test how they handle github (or the proxy) misbehaving: slow responses, 5XX errors,
+//! rate limits, and lists which stop short.
+//!
+//! Responses with an injected fault say which with `X-Proxy-Fault`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::{Bytes, Full};
+use axum::extract::State;
+use axum::http::header::{self, HeaderValue};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::config::duration;
+use crate::{text_response, AppState};
+
+const FAULT_HEADER: &str = "x-proxy-fault";
+
+/// How likely each fault is, from 0 (never) to 1 (always), for each request.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ChaosConfig {
+    /// Delays the response by `latency`.
+    pub(crate) latency_probability: f64,
+    /// e.g. `"5s"`. [default: 2s]
+    #[serde(deserialize_with = "duration")]
+    pub(crate) latency: Option<Duration>,
+    /// Answers with a 500, 502, 503 or 504 instead.
+    pub(crate) error_probability: f64,
+    /// Answers with a 429, as if github's rate limit had been used up.
+    pub(crate) rate_limit_probability: f64,
+    /// Drops the second half of JSON arrays, as if pagination had stopped early.
+    pub(crate) truncate_probability: f64,
+}
+
+impl ChaosConfig {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (name, probability) in [
+            ("latency_probability", self.latency_probability),
+            ("error_probability", self.error_probability),
+            ("rate_limit_probability", self.rate_limit_probability),
+            ("truncate_probability", self.truncate_probability),
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(format!(
+                    "chaos.{} should be from 0 to 1, not {}",
+                    name, probability
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Injects the configured faults into proxied responses, if the proxy was started with `--chaos`.
+pub(crate) async fn inject_faults<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.chaos {
+        return next.run(request).await;
+    }
+    let chaos = state.settings().chaos;
+    let happens = |probability: f64| rand::thread_rng().gen_bool(probability);
+    let mut faults = Vec::new();
+    if happens(chaos.latency_probability) {
+        tokio::time::sleep(chaos.latency.unwrap_or(Duration::from_secs(2))).await;
+        faults.push("latency");
+    }
+    let mut response = if happens(chaos.error_probability) {
+        faults.push("error");
+        let status_code = [
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::GATEWAY_TIMEOUT,
+        ][rand::thread_rng().gen_range(0..4)];
+        text_response(status_code, "Injected fault").into_response()
+    } else if happens(chaos.rate_limit_probability) {
+        faults.push("rate-limit");
+        rate_limited().into_response()
+    } else {
+        let response = next.run(request).await;
+        if happens(chaos.truncate_probability) {
+            match truncate(response).await {
+                (response, true) => {
+                    faults.push("truncated");
+                    response
+                }
+                (response, false) => response,
+            }
+        } else {
+            response
+        }
+    };
+    if !faults.is_empty() {
+        tracing::debug!(?faults, "Injected faults");
+        if let Ok(faults) = faults.join(", ").parse() {
+            response.headers_mut().insert(FAULT_HEADER, faults);
+        }
+    }
+    response
+}
+
+/// A rate limit like the one the proxy gives when github's is used up.
+fn rate_limited() -> (StatusCode, axum::http::HeaderMap, Bytes) {
+    let wait = Duration::from_secs(60);
+    let reset_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        + wait;
+    let (status_code, mut headers, body) = text_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        serde_json::json!({
+            "message": "Rate limited by github (injected fault)",
+            "retry_after_seconds": wait.as_secs(),
+            "reset_at": reset_at.as_secs(),
+        })
+        .to_string(),
+    );
+    headers.insert(header::RETRY_AFTER, wait.as_secs().into());
+    headers.insert("x-ratelimit-remaining", 0.into());
+    headers.insert("x-ratelimit-reset", reset_at.as_secs().into());
+    (status_code, headers, body)
+}
+
+/// Drops the second half of a successful JSON array response, saying whether it did. Responses
+/// streamed straight through (which have no Content-Length) are left alone.
+async fn truncate(response: Response) -> (Response, bool) {
+    if !response.status().is_success() || !response.headers().contains_key(header::CONTENT_LENGTH) {
+        return (response, false);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            return (
+                text_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to read response: {}", err),
+                )
+                .into_response(),
+                false,
+            )
+        }
+    };
+    let Some(truncated) = truncated_array(&body) else {
+        return (
+            Response::from_parts(parts, axum::body::boxed(Full::from(body))),
+            false,
+        );
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::ETAG);
+    parts.headers.insert(
+        header::WARNING,
+        HeaderValue::from_static("199 - \"Response truncated by pagination limits\""),
+    );
+    (
+        Response::from_parts(parts, axum::body::boxed(Full::from(truncated))),
+        true,
+    )
+}
+
+/// The first half of a JSON array with more than one item.
+fn truncated_array(body: &[u8]) -> Option<Vec<u8>> {
+    let items: Vec<&serde_json::value::RawValue> = serde_json::from_slice(body).ok()?;
+    if items.len() < 2 {
+        return None;
+    }
+    Some(serde_json::to_vec(&items[..items.len() / 2]).expect("Raw JSON can always be serialized"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrays_are_cut_in_half() {
+        assert_eq!(
+            truncated_array(br#"[{"a": 1}, 2, "three"]"#).unwrap(),
+            br#"[{"a": 1}]"#
+        );
+        assert_eq!(truncated_array(b"[1, 2, 3, 4]").unwrap(), b"[1,2]");
+        assert!(truncated_array(b"[1]").is_none());
+        assert!(truncated_array(br#"{"a": [1, 2]}"#).is_none());
+
+        let config = ChaosConfig {
+            error_probability: 1.5,
+            ..ChaosConfig::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(ChaosConfig::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+//! Stops sending requests to github while most of them are failing, so that an outage turns into
+//! quick 503s (or stale cached responses) rather than piles of slow failing requests, each of which
+//! would otherwise wait out its timeout and retries.
+//!
+//! Once the circuit has been open for a while, a single request is let through to probe whether
+//! github has recovered, closing the circuit if it succeeds.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+
+/// How far back requests count towards the error rate.
+const WINDOW: Duration = Duration::from_secs(60);
+
+pub(crate) struct CircuitBreaker {
+    state: Mutex<State>,
+    /// The percentage of requests which have to fail for the circuit to open.
+    error_percent: u64,
+    /// How many requests have to have been made within the window before it can open at all.
+    min_requests: usize,
+    /// How long the circuit stays open before a request is let through to probe github.
+    open_for: Duration,
+}
+
+enum State {
+    /// Requests go ahead, and when each finished and whether it failed is recorded.
+    Closed { outcomes: VecDeque<(Instant, bool)> },
+    /// Requests are turned away.
+    Open { until: Instant },
+    /// A single request is let through, and its outcome opens or closes the circuit.
+    HalfOpen { probing: bool },
+}
+
+/// Permission to send a request to github, whose outcome should be recorded.
+pub(crate) struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(
+        error_percent: u64,
+        min_requests: usize,
+        open_for: Duration,
+    ) -> CircuitBreaker {
+        CircuitBreaker {
+            state: Mutex::new(State::Closed {
+                outcomes: VecDeque::new(),
+            }),
+            error_percent,
+            min_requests,
+            open_for,
+        }
+    }
+
+    /// Lets a request go ahead, unless the circuit is open, or half-open and already being probed.
+    pub(crate) fn attempt(&self) -> Result<Attempt<'_>, (StatusCode, String)> {
+        let mut state = self.state.lock().unwrap();
+        let probe = match &*state {
+            State::Closed { .. } => false,
+            State::Open { until } => {
+                let now = Instant::now();
+                if now < *until {
+                    return Err((
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        format!(
+                            "Requests to github are failing, so none are being sent for another {}s",
+                            until.duration_since(now).as_secs() + 1
+                        ),
+                    ));
+                }
+                true
+            }
+            State::HalfOpen { probing: false } => true,
+            State::HalfOpen { probing: true } => {
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Requests to github are failing, so none are being sent until one gets through"
+                        .to_owned(),
+                ))
+            }
+        };
+        if probe {
+            *state = State::HalfOpen { probing: true };
+        }
+        Ok(Attempt {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    /// Whether requests are being turned away.
+    pub(crate) fn is_open(&self) -> bool {
+        match &*self.state.lock().unwrap() {
+            State::Closed { .. } => false,
+            State::Open { until } => Instant::now() < *until,
+            State::HalfOpen { probing } => *probing,
+        }
+    }
+}
+
+impl Attempt<'_> {
+    /// Records whether the request failed in a way which suggests github is having an outage, e.g.
+    /// with a network error or a 5XX response.
+    pub(crate) fn record(mut self, failed: bool) {
+        self.recorded = true;
+        let breaker = self.breaker;
+        let mut state = breaker.state.lock().unwrap();
+        let now = Instant::now();
+        match &mut *state {
+            State::HalfOpen { .. } if self.probe => {
+                if failed {
+                    *state = State::Open {
+                        until: now + breaker.open_for,
+                    };
+                } else {
+                    tracing::info!("github has recovered, so requests are being sent to it again");
+                    *state = State::Closed {
+                        outcomes: VecDeque::new(),
+                    };
+                }
+            }
+            State::Closed { outcomes } => {
+                outcomes.push_back((now, failed));
+                while outcomes
+                    .front()
+                    .is_some_and(|(at, _)| now.duration_since(*at) > WINDOW)
+                {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|(_, failed)| *failed).count();
+                if outcomes.len() >= breaker.min_requests
+                    && failures as u64 * 100 >= breaker.error_percent * outcomes.len() as u64
+                {
+                    tracing::warn!(
+                        failures,
+                        requests = outcomes.len(),
+                        open_for = ?breaker.open_for,
+                        "Requests to github are failing, so no more are being sent for a while"
+                    );
+                    *state = State::Open {
+                        until: now + breaker.open_for,
+                    };
+                }
+            }
+            // Requests which were sent before the circuit opened don't change anything.
+            State::Open { .. } | State::HalfOpen { .. } => {}
+        }
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        // A probe which was cancelled before it finished leaves the next request to probe instead.
+        if self.probe && !self.recorded {
+            let mut state = self.breaker.state.lock().unwrap();
+            if let State::HalfOpen { probing } = &mut *state {
+                *probing = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_when_github_fails_and_probes_to_close() {
+        let breaker = CircuitBreaker::new(50, 4, Duration::ZERO);
+        for failed in [false, true, false] {
+            breaker.attempt().unwrap().record(failed);
+        }
+        // Not enough requests have been made yet.
+        assert!(!breaker.is_open());
+        breaker.attempt().unwrap().record(true);
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+
+        // Only one request probes github at a time, and cancelling it lets another probe instead.
+        let probe = breaker.attempt().unwrap();
+        assert!(breaker.is_open());
+        let (status_code, _) = breaker.attempt().err().unwrap();
+        assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+        drop(probe);
+        breaker.attempt().unwrap().record(true);
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+
+        breaker.attempt().unwrap().record(false);
+        assert!(!breaker.is_open());
+        breaker.attempt().unwrap().record(true);
+        assert!(!breaker.is_open());
+    }
+}
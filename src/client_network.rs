@@ -0,0 +1,111 @@
+//! Works out which network a request came from, so that the proxy can be limited to trusted ones.
+
+use std::net::IpAddr;
+
+use axum::http::header::HeaderMap;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8`, or a single IP address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Network(IpNet);
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(network: String) -> Result<Network, String> {
+        match network.parse::<IpNet>() {
+            Ok(network) => Ok(Network(network)),
+            Err(_) => network
+                .parse::<IpAddr>()
+                .map(|ip| Network(IpNet::from(ip)))
+                .map_err(|err| format!("Failed to parse {:?} as a network: {}", network, err)),
+        }
+    }
+}
+
+impl Network {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+        self.0.contains(&ip)
+    }
+}
+
+/// The IP address of the client which made a request, `None` if it came over a Unix domain socket
+/// from a client which didn't say who it's forwarding for.
+///
+/// `X-Forwarded-For` is only believed when the request came from a `trusted_proxy` (or over a Unix
+/// domain socket, which only local processes can connect to), and only as far back as the last
+/// address which isn't a trusted proxy, as anything before that could have been made up by the
+/// client.
+pub(crate) fn client_ip(
+    remote_ip: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[Network],
+) -> Option<IpAddr> {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+    if remote_ip.is_some_and(|ip| !trusted(ip)) {
+        return remote_ip;
+    }
+    let forwarded_for: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    forwarded_for
+        .iter()
+        .rev()
+        .find(|ip| !trusted(**ip))
+        .or(forwarded_for.first())
+        .copied()
+        .or(remote_ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(network: &str) -> Network {
+        Network::try_from(network.to_owned()).unwrap()
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn networks_can_be_single_addresses() {
+        assert!(network("10.0.0.0/8").contains("10.1.2.3".parse().unwrap()));
+        assert!(!network("10.0.0.0/8").contains("11.0.0.1".parse().unwrap()));
+        assert!(network("192.0.2.1").contains("192.0.2.1".parse().unwrap()));
+        assert!(network("192.0.2.1").contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(Network::try_from("nonsense".to_owned()).is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let trusted = [network("10.0.0.0/8")];
+        let headers = forwarded_for("203.0.113.9, 198.51.100.7, 10.0.0.2");
+        let proxy = Some("10.0.0.1".parse().unwrap());
+        let stranger = Some("192.0.2.1".parse().unwrap());
+        assert_eq!(
+            client_ip(proxy, &headers, &trusted),
+            Some("198.51.100.7".parse().unwrap())
+        );
+        assert_eq!(client_ip(stranger, &headers, &trusted), stranger);
+        assert_eq!(
+            client_ip(None, &headers, &trusted),
+            Some("198.51.100.7".parse().unwrap())
+        );
+        assert_eq!(client_ip(proxy, &HeaderMap::new(), &trusted), proxy);
+    }
+}
@@ -0,0 +1,94 @@
+//! Limits how quickly each client may make requests, so that one misbehaving client can't spend the
+//! rate limit which every client shares.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// How many clients are tracked before idle ones are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClientRateLimit {
+    pub(crate) requests_per_minute: u32,
+    /// How many requests a client may make at once after being idle. [default: requests_per_minute]
+    pub(crate) burst: Option<u32>,
+}
+
+impl ClientRateLimit {
+    fn capacity(&self) -> f64 {
+        f64::from(self.burst.unwrap_or(self.requests_per_minute).max(1))
+    }
+
+    fn per_second(&self) -> f64 {
+        f64::from(self.requests_per_minute) / 60.0
+    }
+}
+
+/// A token bucket for each client, e.g. `ip:192.0.2.1` or `key:team-a`.
+#[derive(Default)]
+pub(crate) struct ClientRateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl ClientRateLimiter {
+    /// Takes a token from `client`'s bucket, or says how long until it will have one.
+    pub(crate) fn check(&self, client: &str, limit: ClientRateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(client) {
+            // Clients whose buckets have refilled would start afresh anyway.
+            buckets.retain(|_, bucket| bucket.refilled(now, limit) < limit.capacity());
+        }
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: limit.capacity(),
+            updated_at: now,
+        });
+        bucket.tokens = bucket.refilled(now, limit);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        if limit.per_second() <= 0.0 {
+            return Err(Duration::from_secs(60));
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / limit.per_second(),
+        ))
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, limit: ClientRateLimit) -> f64 {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        (self.tokens + elapsed * limit.per_second()).min(limit.capacity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_get_a_burst_then_wait() {
+        let limiter = ClientRateLimiter::default();
+        let limit = ClientRateLimit {
+            requests_per_minute: 60,
+            burst: Some(2),
+        };
+        assert!(limiter.check("ip:a", limit).is_ok());
+        assert!(limiter.check("ip:a", limit).is_ok());
+        let wait = limiter.check("ip:a", limit).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert!(limiter.check("ip:b", limit).is_ok());
+    }
+}
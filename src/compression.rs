@@ -0,0 +1,175 @@
+//! Compresses responses for clients which accept it. Cached responses can also be compressed once,
+//! as they're cached, rather than every time they're served.
+
+use std::io::Write;
+
+use axum::body::{Bytes, Full};
+use axum::extract::State;
+use axum::http::header::{self, HeaderMap, HeaderValue};
+use axum::http::{Extensions, Request, StatusCode, Version};
+use axum::middleware::Next;
+use axum::response::Response;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+use tower_http::compression::{CompressionLayer, CompressionLevel};
+
+use crate::{AppState, SerializedBody};
+
+/// How many bytes of compressed cached responses are kept.
+const PRECOMPRESSED_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Responses smaller than this aren't worth compressing ahead of time.
+const MIN_PRECOMPRESSED_LEN: usize = 1024;
+
+/// Compressed cached response bodies, keyed by the ETag of the uncompressed body.
+pub(crate) type Precompressed = moka::sync::Cache<HeaderValue, Bytes>;
+
+pub(crate) fn precompressed() -> Precompressed {
+    moka::sync::Cache::builder()
+        .max_capacity(PRECOMPRESSED_MAX_BYTES)
+        .weigher(|_: &HeaderValue, body: &Bytes| u32::try_from(body.len()).unwrap_or(u32::MAX))
+        .build()
+}
+
+/// Compresses responses with gzip or brotli, whichever the client prefers.
+pub(crate) fn layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        // The highest levels are far slower for little gain on multi-megabyte JSON.
+        .quality(CompressionLevel::Precise(4))
+        .compress_when(DefaultPredicate::new().and(worth_compressing))
+}
+
+/// Skips archives and downloads passed through from github, which are usually compressed already,
+/// and partial responses, whose ranges refer to the uncompressed body.
+fn worth_compressing(
+    _status: StatusCode,
+    _version: Version,
+    headers: &HeaderMap,
+    _extensions: &Extensions,
+) -> bool {
+    if headers.contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    ![
+        "application/octet-stream",
+        "application/gzip",
+        "application/x-gzip",
+        "application/zip",
+    ]
+    .iter()
+    .any(|compressed| content_type.starts_with(compressed))
+}
+
+/// Compresses `body` ahead of time, if we're keeping compressed cached responses, so that hits
+/// needn't compress it again.
+pub(crate) fn precompress(state: &AppState, body: &SerializedBody) {
+    let Some(precompressed) = state.precompressed.clone() else {
+        return;
+    };
+    if !body.status.is_success()
+        || body.bytes.len() < MIN_PRECOMPRESSED_LEN
+        || precompressed.contains_key(&body.etag)
+    {
+        return;
+    }
+    let etag = body.etag.clone();
+    let bytes = body.bytes.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(6));
+        encoder
+            .write_all(&bytes)
+            .expect("Writing to a Vec can't fail");
+        let compressed = encoder.finish().expect("Writing to a Vec can't fail");
+        precompressed.insert(etag, Bytes::from(compressed));
+    });
+}
+
+/// Serves the compressed copy of a cached response, for clients which accept gzip, if there is one
+/// and the response hasn't been transformed since it was cached (which would change its ETag).
+pub(crate) async fn serve_precompressed<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let accepts_gzip = accepts_gzip(request.headers());
+    let mut response = next.run(request).await;
+    if response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+    // Responses are compressed (or not) depending on Accept-Encoding, so shared caches in front of
+    // the proxy have to keep them apart.
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let compressed = match (&state.precompressed, response.headers().get(header::ETAG)) {
+        (Some(precompressed), Some(etag)) if accepts_gzip => precompressed.get(etag),
+        _ => None,
+    };
+    let Some(compressed) = compressed else {
+        return response;
+    };
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    Response::from_parts(parts, axum::body::boxed(Full::from(compressed)))
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let refused = parts.any(|parameter| {
+                parameter
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_gzip() {
+        let accepts = |accept_encoding: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static(accept_encoding),
+            );
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip, deflate, br"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("br"));
+        assert!(!accepts("gzip;q=0, br"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-gzip"),
+        );
+        assert!(!worth_compressing(
+            StatusCode::OK,
+            Version::HTTP_11,
+            &headers,
+            &Extensions::new()
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+//! Decides which web pages may read the proxy's responses, so that it can be limited to the
+//! dashboards which use it.
+
+use std::time::Duration;
+
+use axum::http::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, VARY,
+};
+use serde::Deserialize;
+
+use crate::config::parse_duration;
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CorsConfig {
+    /// Origins whose pages may read responses, e.g. `https://dash.example.com`, or with one
+    /// wildcard, e.g. `https://*.example.com`. `*` allows any origin.
+    allowed_origins: Vec<String>,
+    /// Request headers which pages may send, beyond those browsers always allow.
+    allowed_headers: Vec<String>,
+    allowed_methods: Vec<String>,
+    /// Whether pages may send cookies and HTTP authentication with their requests.
+    allow_credentials: bool,
+    /// How long browsers may remember the answer to a preflight request, e.g. `10m`.
+    max_age: String,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec!["*".to_owned()],
+            allowed_headers: ["Authorization", "Content-Type", "X-Proxy-Key"]
+                .map(str::to_owned)
+                .to_vec(),
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .map(str::to_owned)
+                .to_vec(),
+            allow_credentials: false,
+            max_age: "2h".to_owned(),
+        }
+    }
+}
+
+/// The `[cors]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "CorsConfig")]
+pub(crate) struct CorsPolicy {
+    allowed_origins: Vec<OriginPattern>,
+    allowed_headers: Option<HeaderValue>,
+    allowed_methods: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum OriginPattern {
+    Any,
+    Exact(String),
+    Wildcard { prefix: String, suffix: String },
+}
+
+impl Default for CorsPolicy {
+    fn default() -> CorsPolicy {
+        CorsPolicy::try_from(CorsConfig::default()).expect("Default CORS policy is valid")
+    }
+}
+
+impl TryFrom<CorsConfig> for CorsPolicy {
+    type Error = String;
+
+    fn try_from(config: CorsConfig) -> Result<CorsPolicy, String> {
+        let list = |items: Vec<String>| -> Result<Option<HeaderValue>, String> {
+            if items.is_empty() {
+                return Ok(None);
+            }
+            let joined = items.join(", ");
+            HeaderValue::try_from(&joined)
+                .map(Some)
+                .map_err(|err| format!("{:?} isn't a valid header value: {}", joined, err))
+        };
+        Ok(CorsPolicy {
+            allowed_origins: config
+                .allowed_origins
+                .iter()
+                .map(|origin| OriginPattern::parse(origin))
+                .collect::<Result<_, _>>()?,
+            allowed_headers: list(config.allowed_headers)?,
+            allowed_methods: list(config.allowed_methods)?,
+            allow_credentials: config.allow_credentials,
+            max_age: parse_duration(&config.max_age)?,
+        })
+    }
+}
+
+impl OriginPattern {
+    fn parse(origin: &str) -> Result<OriginPattern, String> {
+        let origin = origin.trim().trim_end_matches('/').to_ascii_lowercase();
+        if origin == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        match origin.split('*').collect::<Vec<_>>()[..] {
+            [exact] => Ok(OriginPattern::Exact(exact.to_owned())),
+            [prefix, suffix] => Ok(OriginPattern::Wildcard {
+                prefix: prefix.to_owned(),
+                suffix: suffix.to_owned(),
+            }),
+            _ => Err(format!(
+                "Allowed origin {:?} may only contain one wildcard",
+                origin
+            )),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => origin == exact,
+            OriginPattern::Wildcard { prefix, suffix } => origin
+                .strip_prefix(prefix.as_str())
+                .and_then(|rest| rest.strip_suffix(suffix.as_str()))
+                // The wildcard only stands for part of the host.
+                .is_some_and(|host| !host.is_empty() && !host.contains(['/', ':', '@'])),
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// Sets the CORS headers of a response to a request from `origin`, replacing any it had.
+    pub(crate) fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        headers.remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+        headers.remove(ACCESS_CONTROL_ALLOW_CREDENTIALS);
+        if self.allowed_origins.contains(&OriginPattern::Any) && !self.allow_credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            // The response depends on the origin, so caches mustn't serve it to other origins.
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+            let Some(origin) = origin.filter(|origin| self.allows(origin)) else {
+                return;
+            };
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            if self.allow_credentials {
+                headers.insert(
+                    ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+            }
+        }
+    }
+
+    /// The headers answering a preflight request, which browsers send before requests which
+    /// aren't "simple", e.g. because they have an Authorization header.
+    pub(crate) fn preflight(&self, origin: Option<&HeaderValue>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        self.apply(origin, &mut headers);
+        if !headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return headers;
+        }
+        if let Some(allowed_headers) = &self.allowed_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers.clone());
+        }
+        if let Some(allowed_methods) = &self.allowed_methods {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, allowed_methods.clone());
+        }
+        headers.insert(ACCESS_CONTROL_MAX_AGE, self.max_age.as_secs().into());
+        headers
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        let origin = origin.to_ascii_lowercase();
+        self.allowed_origins
+            .iter()
+            .any(|pattern| pattern.matches(&origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed_origins: &[&str], allow_credentials: bool) -> CorsPolicy {
+        CorsPolicy::try_from(CorsConfig {
+            allowed_origins: allowed_origins
+                .iter()
+                .map(|&origin| origin.to_owned())
+                .collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn allowed_origin(policy: &CorsPolicy, origin: &'static str) -> Option<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        policy.apply(Some(&HeaderValue::from_static(origin)), &mut headers);
+        headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).cloned()
+    }
+
+    #[test]
+    fn allows_exact_and_wildcard_origins() {
+        let policy = policy(
+            &["https://dash.example.com", "https://*.example.org"],
+            false,
+        );
+        for origin in [
+            "https://dash.example.com",
+            "https://DASH.example.com",
+            "https://a.b.example.org",
+        ] {
+            assert_eq!(allowed_origin(&policy, origin).unwrap(), origin);
+        }
+        for origin in [
+            "http://dash.example.com",
+            "https://example.org",
+            "https://evil.com/.example.org",
+            "https://dash.example.com.evil.com",
+        ] {
+            assert_eq!(allowed_origin(&policy, origin), None, "{}", origin);
+        }
+        assert!(OriginPattern::parse("https://*.*.example.org").is_err());
+    }
+
+    #[test]
+    fn credentials_need_the_origin_echoed() {
+        assert_eq!(
+            allowed_origin(&policy(&["*"], false), "https://a.com").unwrap(),
+            "*"
+        );
+
+        let mut headers = HeaderMap::new();
+        policy(&["*"], true).apply(
+            Some(&HeaderValue::from_static("https://a.com")),
+            &mut headers,
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://a.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[VARY], "Origin");
+    }
+
+    #[test]
+    fn preflights_are_only_answered_for_allowed_origins() {
+        let policy = policy(&["https://dash.example.com"], false);
+        let headers = policy.preflight(Some(&HeaderValue::from_static("https://dash.example.com")));
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "Authorization, Content-Type, X-Proxy-Key"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "7200");
+
+        let headers = policy.preflight(Some(&HeaderValue::from_static("https://evil.com")));
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+    }
+}
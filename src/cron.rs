@@ -0,0 +1,151 @@
+//! Cron-style schedules, e.g. `*/10 * * * *`, for jobs run by the proxy itself.
+
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+/// The minutes at which something happens, as the five fields of a cron expression (minute, hour,
+/// day of month, month and day of week) evaluated in UTC.
+///
+/// Each field is `*`, a number, or a range like `1-5`, any of which may have a step like `*/10`,
+/// or a comma-separated list of those. As in cron, if both day fields are restricted then a day
+/// matching either of them matches.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Schedule {
+    // Each field is a set of bits, one for each value it matches.
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(schedule: String) -> Result<Schedule, String> {
+        schedule.parse()
+    }
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(schedule: &str) -> Result<Schedule, String> {
+        let fields: Vec<_> = schedule.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(format!(
+                "{:?} should have five fields: minute, hour, day of month, month and day of week",
+                schedule
+            ));
+        };
+        // Sunday is both 0 and 7.
+        let mut days_of_week_bits = parse_field(days_of_week, 0, 7)?;
+        if days_of_week_bits & (1 << 7) != 0 {
+            days_of_week_bits |= 1;
+        }
+        Ok(Schedule {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days_of_month: parse_field(days_of_month, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            days_of_week: days_of_week_bits,
+            days_of_month_restricted: !days_of_month.starts_with('*'),
+            days_of_week_restricted: !days_of_week.starts_with('*'),
+        })
+    }
+}
+
+impl Schedule {
+    /// Whether the schedule fires during the minute of `at`.
+    pub(crate) fn matches(&self, at: OffsetDateTime) -> bool {
+        let bit = |bits: u64, value: u8| bits & (1 << value) != 0;
+        let day_of_month = bit(self.days_of_month, at.day());
+        let day_of_week = bit(self.days_of_week, at.weekday().number_days_from_sunday());
+        let day = if self.days_of_month_restricted && self.days_of_week_restricted {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        };
+        bit(self.minutes, at.minute())
+            && bit(self.hours, at.hour())
+            && bit(self.months, u8::from(at.month()))
+            && day
+    }
+}
+
+/// Parses one field of a cron expression, whose values run from `min` to `max`.
+fn parse_field(field: &str, min: u8, max: u8) -> Result<u64, String> {
+    let number = |value: &str| {
+        value
+            .parse::<u8>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(|| format!("{:?} isn't a number from {} to {}", value, min, max))
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u8>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("{:?} isn't a valid step", step)),
+            },
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // `5/10` means every 10 from 5.
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if start > end {
+            return Err(format!("{:?} is an empty range", range));
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1).into()) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::format_description::well_known::Rfc3339;
+
+    fn at(datetime: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(datetime, &Rfc3339).unwrap()
+    }
+
+    #[test]
+    fn schedules_match_like_cron() {
+        let every_ten_minutes: Schedule = "*/10 * * * *".parse().unwrap();
+        assert!(every_ten_minutes.matches(at("2024-03-05T14:20:59Z")));
+        assert!(!every_ten_minutes.matches(at("2024-03-05T14:21:00Z")));
+
+        let weekday_mornings: Schedule = "0,30 8-9 * * 1-5".parse().unwrap();
+        // 2024-03-05 was a Tuesday, and 2024-03-10 a Sunday.
+        assert!(weekday_mornings.matches(at("2024-03-05T09:30:00Z")));
+        assert!(!weekday_mornings.matches(at("2024-03-05T10:00:00Z")));
+        assert!(!weekday_mornings.matches(at("2024-03-10T09:30:00Z")));
+
+        // Either day field may match once both are restricted.
+        let firsts_and_sundays: Schedule = "0 0 1 * 7".parse().unwrap();
+        assert!(firsts_and_sundays.matches(at("2024-03-01T00:00:00Z")));
+        assert!(firsts_and_sundays.matches(at("2024-03-10T00:00:00Z")));
+        assert!(!firsts_and_sundays.matches(at("2024-03-05T00:00:00Z")));
+
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{:?}", invalid);
+        }
+    }
+}
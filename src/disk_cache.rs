@@ -0,0 +1,114 @@
+//! Persists cache entries to disk, so that restarting the proxy doesn't mean re-fetching everything
+//! from github at once.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::cache::{key_digest, EntryCipher, StoredEntry};
+use crate::{CacheKey, CacheValue};
+
+/// Stores each cache entry as a JSON file named after a hash of its key.
+///
+/// Entries which are evicted from memory to make space for others stay on disk until they expire,
+/// and are cleaned up the next time the cache is loaded.
+pub(crate) struct DiskCache {
+    dir: PathBuf,
+    cipher: Option<EntryCipher>,
+}
+
+impl DiskCache {
+    pub(crate) fn new(dir: PathBuf, cipher: Option<EntryCipher>) -> DiskCache {
+        if let Err(err) = std::fs::create_dir_all(&dir) {
+            panic!(
+                "Failed to create cache directory {}: {}",
+                dir.display(),
+                err
+            );
+        }
+        DiskCache { dir, cipher }
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!("{}.json", key_digest(key)))
+    }
+
+    /// Writes an entry to disk in the background.
+    pub(crate) fn store(&self, key: &CacheKey, value: &CacheValue, retention: Duration) {
+        let record = StoredEntry::new(key, value, retention);
+        let record = match record.to_bytes(self.cipher.as_ref()) {
+            Ok(record) => record,
+            Err(err) => {
+                tracing::error!(%err, "Failed to serialize cache entry");
+                return;
+            }
+        };
+        let path = self.path(key);
+        tokio::task::spawn_blocking(move || {
+            // Write then rename, so that a crash mid-write never leaves a truncated entry behind.
+            let temp_path = path.with_extension(format!("{:x}.tmp", rand::random::<u64>()));
+            let result = std::fs::write(&temp_path, record)
+                .and_then(|()| std::fs::rename(&temp_path, &path));
+            if let Err(err) = result {
+                tracing::error!(path = %path.display(), %err, "Failed to persist cache entry");
+            }
+        });
+    }
+
+    /// Deletes an entry from disk in the background.
+    pub(crate) fn remove(&self, key: &CacheKey) {
+        let path = self.path(key);
+        tokio::task::spawn_blocking(move || match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                tracing::error!(path = %path.display(), %err, "Failed to remove cache entry")
+            }
+        });
+    }
+
+    /// Reads every unexpired entry from disk, along with how much longer each should be retained.
+    ///
+    /// Expired entries (and entries too old to load), and anything left over from interrupted
+    /// writes, are deleted.
+    pub(crate) fn load(&self) -> Vec<(CacheKey, CacheValue, Duration)> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => panic!(
+                "Failed to read cache directory {}: {}",
+                self.dir.display(),
+                err
+            ),
+        };
+        let mut loaded = Vec::new();
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    tracing::warn!(%err, "Failed to read cache directory entry");
+                    continue;
+                }
+            };
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let record = match std::fs::read(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| StoredEntry::from_bytes(&bytes, self.cipher.as_ref()))
+            {
+                Ok(record) => record,
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), %err, "Skipping cache entry");
+                    continue;
+                }
+            };
+            match record.into_entry() {
+                Some(entry) => loaded.push(entry),
+                None => {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        loaded
+    }
+}
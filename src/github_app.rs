@@ -0,0 +1,182 @@
+//! Authenticates as a GitHub App installation, whose tokens have higher rate limits than personal
+//! access tokens and don't need rotating by hand.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::header::HeaderValue;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+/// How long before an installation token expires that it's replaced.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// How long to wait before trying again when minting a token fails.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+pub(crate) struct GitHubApp {
+    app_id: u64,
+    installation_id: u64,
+    key: EncodingKey,
+    client: reqwest::Client,
+    github_api_base_url: Url,
+    token: RwLock<Tokens>,
+    /// Held while minting, so that requests which all find their token has expired only mint one
+    /// replacement between them.
+    minting: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct Tokens {
+    /// The Authorization header for the current installation token, once one has been minted.
+    current: Option<HeaderValue>,
+    expires_at: Option<SystemTime>,
+    /// The token the current one replaced, which requests may still be using.
+    previous: Option<HeaderValue>,
+}
+
+#[derive(Serialize)]
+struct Claims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+#[derive(Deserialize)]
+struct InstallationToken {
+    token: String,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: time::OffsetDateTime,
+}
+
+impl GitHubApp {
+    /// `private_key` is the PEM-encoded key downloaded from the app's settings.
+    pub(crate) fn new(
+        app_id: u64,
+        installation_id: u64,
+        private_key: &[u8],
+        client: reqwest::Client,
+        github_api_base_url: Url,
+    ) -> Result<GitHubApp, String> {
+        let key = EncodingKey::from_rsa_pem(private_key)
+            .map_err(|err| format!("Failed to parse GitHub App private key: {}", err))?;
+        Ok(GitHubApp {
+            app_id,
+            installation_id,
+            key,
+            client,
+            github_api_base_url,
+            token: RwLock::default(),
+            minting: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// The Authorization header for the current installation token.
+    pub(crate) fn auth_header(&self) -> Option<HeaderValue> {
+        self.token.read().unwrap().current.clone()
+    }
+
+    /// Replaces the installation token shortly before it expires, forever.
+    pub(crate) async fn refresh_before_expiry(self: Arc<Self>) {
+        loop {
+            let expires_at = self.token.read().unwrap().expires_at;
+            let refresh_in = expires_at
+                .and_then(|expires_at| expires_at.duration_since(SystemTime::now()).ok())
+                .map_or(Duration::ZERO, |lifetime| {
+                    lifetime.saturating_sub(REFRESH_MARGIN)
+                });
+            tokio::time::sleep(refresh_in).await;
+            let result = {
+                let _minting = self.minting.lock().await;
+                if self.token.read().unwrap().expires_at != expires_at {
+                    // A request found the token had been revoked, and already replaced it.
+                    continue;
+                }
+                self.mint_installation_token().await
+            };
+            if let Err(err) = result {
+                tracing::error!(%err, "Failed to refresh GitHub App installation token");
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    /// Replaces `rejected`, if it's an installation token which github has stopped accepting (e.g.
+    /// because it was revoked), returning the token to retry with.
+    pub(crate) async fn reauthenticate(&self, rejected: &HeaderValue) -> Option<HeaderValue> {
+        let _minting = self.minting.lock().await;
+        {
+            let tokens = self.token.read().unwrap();
+            if tokens.previous.as_ref() == Some(rejected) {
+                // Another request already replaced it.
+                return tokens.current.clone();
+            }
+            if tokens.current.as_ref() != Some(rejected) {
+                // Not one of ours, so not ours to fix.
+                return None;
+            }
+        }
+        match self.mint_installation_token().await {
+            Ok(()) => self.auth_header(),
+            Err(err) => {
+                tracing::error!(%err, "Failed to replace rejected GitHub App installation token");
+                None
+            }
+        }
+    }
+
+    /// Exchanges a JWT signed by the app for a new installation token, and uses it from now on.
+    pub(crate) async fn mint_installation_token(&self) -> Result<(), String> {
+        let url = self
+            .github_api_base_url
+            .join(&format!(
+                "app/installations/{}/access_tokens",
+                self.installation_id
+            ))
+            .map_err(|err| format!("Failed to build installation token URL: {}", err))?;
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(self.jwt()?)
+            .send()
+            .await
+            .map_err(|err| format!("Failed to request installation token: {}", err))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "github refused to mint an installation token ({}): {}",
+                status, body
+            ));
+        }
+        let InstallationToken { token, expires_at } = response
+            .json()
+            .await
+            .map_err(|err| format!("Failed to parse installation token: {}", err))?;
+        let auth_header = HeaderValue::from_str(&format!("token {}", token))
+            .map_err(|err| format!("Installation token isn't a valid header: {}", err))?;
+        let expires_at = SystemTime::from(expires_at);
+        tracing::info!(?expires_at, "Minted GitHub App installation token");
+        let mut tokens = self.token.write().unwrap();
+        tokens.previous = tokens.current.replace(auth_header);
+        tokens.expires_at = Some(expires_at);
+        Ok(())
+    }
+
+    /// A JWT identifying the app, valid for the few minutes it takes to exchange it.
+    fn jwt(&self) -> Result<String, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let claims = Claims {
+            // Backdated, as github recommends, in case our clock is ahead of theirs.
+            iat: now - 60,
+            // github rejects JWTs which expire more than ten minutes from now.
+            exp: now + 9 * 60,
+            iss: self.app_id.to_string(),
+        };
+        jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(|err| format!("Failed to sign GitHub App JWT: {}", err))
+    }
+}
@@ -0,0 +1,162 @@
+//! Renders lists of milestones as iCalendar feeds, so that calendars can subscribe to their due
+//! dates.
+
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, UtcOffset};
+
+/// How long lines may be, in bytes, before they're folded onto the next.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Renders `items`, which were fetched from `path`, as a calendar with an all-day event on the due
+/// date of each milestone which has one.
+pub(crate) fn render_calendar(path: &str, items: &[Value]) -> Result<String, String> {
+    let mut calendar = String::new();
+    push_line(&mut calendar, "BEGIN:VCALENDAR");
+    push_line(&mut calendar, "VERSION:2.0");
+    push_line(&mut calendar, "PRODID:-//github-issue-proxy//EN");
+    push_line(&mut calendar, "CALSCALE:GREGORIAN");
+    push_line(&mut calendar, &format!("X-WR-CALNAME:{}", escape(path)));
+    for milestone in items {
+        write_event(&mut calendar, milestone)?;
+    }
+    push_line(&mut calendar, "END:VCALENDAR");
+    Ok(calendar)
+}
+
+fn write_event(calendar: &mut String, milestone: &Value) -> Result<(), String> {
+    let field = |name: &str| milestone.get(name).and_then(Value::as_str);
+    let required = |name: &str| {
+        field(name).ok_or_else(|| {
+            format!(
+                "format=ical only works for lists of milestones, but an item had no {:?}",
+                name
+            )
+        })
+    };
+    let url = required("html_url")?;
+    let title = required("title")?;
+    let updated_at = parse_timestamp(required("updated_at")?)?;
+    // Milestones always have a due_on field, but it's null until a due date is set.
+    let Some(due_on) = milestone.get("due_on") else {
+        return Err(
+            "format=ical only works for lists of milestones, but an item had no \"due_on\"".into(),
+        );
+    };
+    let Some(due_on) = due_on.as_str() else {
+        return Ok(());
+    };
+    // Due dates are set as dates, so ignore the time of day github stores with them.
+    let due_on = OffsetDateTime::parse(due_on, &Rfc3339)
+        .map_err(|err| format!("Invalid due date {:?}: {}", due_on, err))?
+        .date();
+
+    push_line(calendar, "BEGIN:VEVENT");
+    push_line(calendar, &format!("UID:{}", escape(url)));
+    push_line(calendar, &format!("DTSTAMP:{}", updated_at));
+    push_line(
+        calendar,
+        &format!(
+            "DTSTART;VALUE=DATE:{:04}{:02}{:02}",
+            due_on.year(),
+            u8::from(due_on.month()),
+            due_on.day()
+        ),
+    );
+    push_line(calendar, &format!("SUMMARY:{}", escape(title)));
+    if let Some(description) = field("description").filter(|description| !description.is_empty()) {
+        push_line(calendar, &format!("DESCRIPTION:{}", escape(description)));
+    }
+    push_line(calendar, &format!("URL:{}", url));
+    push_line(calendar, "TRANSP:TRANSPARENT");
+    push_line(calendar, "END:VEVENT");
+    Ok(())
+}
+
+/// Converts an RFC 3339 timestamp to iCalendar's UTC date-time format, e.g. `20240101T120000Z`.
+fn parse_timestamp(timestamp: &str) -> Result<String, String> {
+    let timestamp = OffsetDateTime::parse(timestamp, &Rfc3339)
+        .map_err(|err| format!("Invalid timestamp {:?}: {}", timestamp, err))?
+        .to_offset(UtcOffset::UTC);
+    Ok(format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        timestamp.year(),
+        u8::from(timestamp.month()),
+        timestamp.day(),
+        timestamp.hour(),
+        timestamp.minute(),
+        timestamp.second()
+    ))
+}
+
+/// Appends a content line, folding it so that no line is longer than calendars have to accept.
+fn push_line(calendar: &mut String, line: &str) {
+    let mut line_length = 0;
+    for c in line.chars() {
+        if line_length + c.len_utf8() > MAX_LINE_LENGTH {
+            calendar.push_str("\r\n ");
+            line_length = 1;
+        }
+        calendar.push(c);
+        line_length += c.len_utf8();
+    }
+    calendar.push_str("\r\n");
+}
+
+/// Escapes `text` for use in a text property value.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_due_milestones_as_events() {
+        let items = serde_json::json!([
+            {
+                "html_url": "https://github.com/o/r/milestone/1",
+                "title": "v1.0; the big one",
+                "description": "Line one\r\nLine two",
+                "updated_at": "2024-01-02T03:04:05Z",
+                "due_on": "2024-03-01T00:00:00-08:00",
+            },
+            {
+                "html_url": "https://github.com/o/r/milestone/2",
+                "title": "Someday",
+                "updated_at": "2024-01-02T03:04:05Z",
+                "due_on": null,
+            },
+        ]);
+        let calendar = render_calendar("repos/o/r/milestones", items.as_array().unwrap()).unwrap();
+        assert!(calendar.contains("\r\nDTSTART;VALUE=DATE:20240301\r\n"));
+        assert!(calendar.contains("\r\nDTSTAMP:20240102T030405Z\r\n"));
+        assert!(calendar.contains("\r\nSUMMARY:v1.0\\; the big one\r\n"));
+        assert!(calendar.contains("\r\nDESCRIPTION:Line one\\nLine two\r\n"));
+        assert_eq!(calendar.matches("BEGIN:VEVENT").count(), 1);
+
+        let long = "x".repeat(200);
+        let mut folded = String::new();
+        push_line(&mut folded, &long);
+        assert!(folded
+            .split("\r\n")
+            .all(|line| line.len() <= MAX_LINE_LENGTH));
+        assert_eq!(folded.replace("\r\n ", ""), format!("{}\r\n", long));
+
+        let issues = serde_json::json!([{"html_url": "u", "title": "t", "updated_at": "2024-01-02T03:04:05Z"}]);
+        assert!(render_calendar("repos/o/r/issues", issues.as_array().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+//! Accepts connections over TCP or a Unix domain socket, optionally terminating TLS with either a
+//! certificate from disk or certificates provisioned automatically over ACME.
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::extract::connect_info::Connected;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use rustls_acme::caches::DirCache;
+use rustls_acme::AcmeConfig;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_rustls::rustls;
+
+/// How many TLS handshakes may be in progress at once. Connections beyond this wait to be accepted.
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+/// How long a client has to complete a TLS handshake before it's disconnected.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// An accepted connection, along with the IP address of the client which made it.
+pub(crate) struct ClientConnection {
+    io: Box<dyn Connection>,
+    /// `None` for connections over a Unix domain socket.
+    remote_ip: Option<IpAddr>,
+}
+
+impl AsyncRead for ClientConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ClientConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+/// The IP address of the client which made a request, for handlers to extract with `ConnectInfo`.
+#[derive(Clone, Copy)]
+pub(crate) struct RemoteIp(pub(crate) Option<IpAddr>);
+
+impl Connected<&ClientConnection> for RemoteIp {
+    fn connect_info(connection: &ClientConnection) -> RemoteIp {
+        RemoteIp(connection.remote_ip)
+    }
+}
+
+pub(crate) struct Listener {
+    pub(crate) connections: BoxStream<'static, ClientConnection>,
+    /// Removed on shutdown, if we're listening on a Unix domain socket.
+    pub(crate) unix_socket_path: Option<PathBuf>,
+}
+
+impl Listener {
+    /// Listens on `bind_addr`, which is an address (or just an IP, to listen on `port`), or `unix:`
+    /// followed by the path of a Unix domain socket.
+    pub(crate) async fn bind(bind_addr: &str, port: u16) -> Listener {
+        if let Some(path) = bind_addr.strip_prefix("unix:") {
+            let path = PathBuf::from(path);
+            remove_unix_socket(&path);
+            let listener = tokio::net::UnixListener::bind(&path).unwrap_or_else(|err| {
+                panic!("Failed to bind to unix socket {}: {}", path.display(), err)
+            });
+            tracing::info!(path = %path.display(), "Listening on unix socket");
+            let connections = futures::stream::unfold(listener, |listener| async move {
+                let stream = retry_accept(|| listener.accept()).await.0;
+                let connection = ClientConnection {
+                    io: Box::new(stream),
+                    remote_ip: None,
+                };
+                Some((connection, listener))
+            });
+            return Listener {
+                connections: connections.boxed(),
+                unix_socket_path: Some(path),
+            };
+        }
+
+        let addr = bind_addr
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                bind_addr
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, port))
+            })
+            .unwrap_or_else(|err| {
+                panic!("Failed to parse $BIND_ADDR from {:?}: {}", bind_addr, err)
+            });
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|err| panic!("Failed to bind to {}: {}", addr, err));
+        tracing::info!(%addr, "Listening");
+        let connections = futures::stream::unfold(listener, |listener| async move {
+            let (stream, remote_addr) = retry_accept(|| listener.accept()).await;
+            let connection = ClientConnection {
+                io: Box::new(stream),
+                remote_ip: Some(remote_addr.ip()),
+            };
+            Some((connection, listener))
+        });
+        Listener {
+            connections: connections.boxed(),
+            unix_socket_path: None,
+        }
+    }
+
+    /// Terminates TLS on each connection. Connections which fail their handshake (or which were
+    /// only made to answer an ACME challenge) are dropped.
+    pub(crate) fn with_tls(self, tls: Tls) -> Listener {
+        let tls = Arc::new(tls);
+        let connections = self
+            .connections
+            .map(move |connection| {
+                let tls = tls.clone();
+                async move {
+                    let remote_ip = connection.remote_ip;
+                    let handshake = tls_handshake(connection.io, &tls);
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => stream.map(|io| ClientConnection { io, remote_ip }),
+                        Ok(Err(err)) => {
+                            tracing::debug!(%err, "TLS handshake failed");
+                            None
+                        }
+                        Err(_) => {
+                            tracing::debug!("TLS handshake timed out");
+                            None
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+            .filter_map(futures::future::ready);
+        Listener {
+            connections: connections.boxed(),
+            unix_socket_path: self.unix_socket_path,
+        }
+    }
+}
+
+pub(crate) enum Tls {
+    /// Serves a certificate loaded from disk.
+    Static(Arc<rustls::ServerConfig>),
+    /// Serves certificates provisioned over ACME, answering its tls-alpn-01 challenges.
+    Acme {
+        config: Arc<rustls::ServerConfig>,
+        challenge_config: Arc<rustls::ServerConfig>,
+    },
+}
+
+async fn tls_handshake(
+    connection: Box<dyn Connection>,
+    tls: &Tls,
+) -> std::io::Result<Option<Box<dyn Connection>>> {
+    let handshake =
+        tokio_rustls::LazyConfigAcceptor::new(rustls::server::Acceptor::default(), connection)
+            .await?;
+    let config = match tls {
+        Tls::Static(config) => config.clone(),
+        Tls::Acme {
+            config,
+            challenge_config,
+        } => {
+            if rustls_acme::is_tls_alpn_challenge(&handshake.client_hello()) {
+                let mut stream = handshake.into_stream(challenge_config.clone()).await?;
+                stream.shutdown().await?;
+                return Ok(None);
+            }
+            config.clone()
+        }
+    };
+    let stream = handshake.into_stream(config).await?;
+    Ok(Some(Box::new(stream)))
+}
+
+/// Retries errors accepting a connection (e.g. running out of file descriptors), which shouldn't
+/// stop the server.
+async fn retry_accept<T, F, Fut>(mut accept: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    loop {
+        match accept().await {
+            Ok(accepted) => return accepted,
+            Err(err) => {
+                tracing::warn!(%err, "Failed to accept connection");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Removes a Unix domain socket, e.g. one left behind by a previous run which would stop us binding
+/// to its path. Anything else at the path is left alone.
+pub(crate) fn remove_unix_socket(path: &Path) {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if let Err(err) = std::fs::remove_file(path) {
+                panic!("Failed to remove unix socket {}: {}", path.display(), err);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Loads a PEM certificate chain and private key to serve over TLS.
+pub(crate) fn load_tls_config(cert_path: &Path, key_path: &Path) -> Tls {
+    let read = |path: &Path| {
+        std::fs::read(path)
+            .unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err))
+    };
+    let certs = rustls_pemfile::certs(&mut read(cert_path).as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|err| panic!("Failed to parse {}: {}", cert_path.display(), err));
+    if certs.is_empty() {
+        panic!("No certificates found in {}", cert_path.display());
+    }
+    let key = rustls_pemfile::private_key(&mut read(key_path).as_slice())
+        .unwrap_or_else(|err| panic!("Failed to parse {}: {}", key_path.display(), err))
+        .unwrap_or_else(|| panic!("No private key found in {}", key_path.display()));
+    let mut config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap_or_else(|err| panic!("Failed to load TLS certificate: {}", err));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Tls::Static(Arc::new(config))
+}
+
+/// Provisions and renews certificates for `domains` in the background, over ACME with Let's Encrypt.
+///
+/// Challenges are answered over TLS (tls-alpn-01), so we must be reachable on port 443 of each
+/// domain. Certificates and the ACME account are cached in `cache_dir`, so that restarts don't
+/// run into Let's Encrypt's rate limits.
+pub(crate) fn start_acme(
+    domains: Vec<String>,
+    contact: Option<String>,
+    cache_dir: PathBuf,
+    production: bool,
+) -> Tls {
+    let mut state = AcmeConfig::new(domains)
+        .contact(contact)
+        .cache(DirCache::new(cache_dir))
+        .directory_lets_encrypt(production)
+        .state();
+    let mut config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(state.resolver());
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let challenge_config = state.challenge_rustls_config();
+    tokio::spawn(async move {
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(event) => tracing::info!(?event, "ACME certificate management"),
+                Err(err) => tracing::error!(%err, "ACME certificate management failed"),
+            }
+        }
+    });
+    Tls::Acme {
+        config: Arc::new(config),
+        challenge_config,
+    }
+}
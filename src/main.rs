@@ -1,297 +1,5796 @@
+mod atom;
+mod cache;
+mod chaos;
+mod circuit_breaker;
+mod cli;
+mod client_network;
+mod client_rate_limit;
+mod compression;
+mod config;
+mod cors;
+mod cron;
+mod disk_cache;
+mod github_app;
+mod ical;
+mod listener;
+mod metrics;
+mod openapi;
+mod redact;
+mod redis_cache;
+mod sqlite_cache;
+mod telemetry;
+mod token_pool;
+mod transform;
+mod upstream_limit;
+mod vcr;
+
+use std::collections::{HashMap, HashSet};
 use std::env::VarError;
-use std::num::NonZeroU16;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-
-use axum::extract::{Path, Query, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::{http::header::HeaderMap, routing::get, Router};
-use futures::future::{BoxFuture, FutureExt};
-use indexmap::IndexMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::{Bytes, StreamBody};
+use axum::extract::{ConnectInfo, Extension, MatchedPath, Path, Query, RawQuery, State};
+use axum::http::Request;
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{http::header::HeaderMap, Router};
+use clap::Parser;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::{Future, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use rand::Rng;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use ttl_cache::TtlCache;
+use sha2::{Digest, Sha256};
+use tracing::Instrument;
+
+use crate::cache::{CacheStore, EntryCipher, MemoryStore};
+use crate::chaos::ChaosConfig;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::cli::{CacheBackend, Cli, Command, RedirectPolicy, ServeArgs, SettingsArgs};
+use crate::client_network::Network;
+use crate::client_rate_limit::{ClientRateLimit, ClientRateLimiter};
+use crate::config::{ApiKey, Config, PathPattern, RouteRule, Ttl, WarmJob};
+use crate::cors::CorsPolicy;
+use crate::disk_cache::DiskCache;
+use crate::github_app::GitHubApp;
+use crate::listener::{Listener, RemoteIp};
+use crate::metrics::Metrics;
+use crate::redis_cache::RedisStore;
+use crate::sqlite_cache::SqliteStore;
+use crate::token_pool::TokenPool;
+use crate::transform::{JqResults, Transform};
+use crate::upstream_limit::{Priority, UpstreamLimit, UpstreamPermit};
+use crate::vcr::{RecordedRequest, Vcr};
 
 #[tokio::main]
 async fn main() {
-    let port = std::env::var_os("PORT").map_or_else(
-        || "3000".to_owned(),
-        |s| s.into_string().expect("Failed to parse $PORT"),
+    let cli = Cli::parse();
+    match cli.command {
+        None => serve(cli.serve).await,
+        Some(Command::Serve(args)) => serve(*args).await,
+        Some(command) => {
+            if !cli::run(command).await {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+async fn serve(args: ServeArgs) {
+    telemetry::init();
+
+    let config = match &args.config {
+        Some(path) => Config::load(path).unwrap_or_else(|err| panic!("{}", err)),
+        None => Config::default(),
+    };
+    let settings = Settings::new(&config, &args.settings).unwrap_or_else(|err| panic!("{}", err));
+
+    let port = args.port.or(config.port).unwrap_or(3000);
+    let bind_addr = args
+        .bind_addr
+        .clone()
+        .or_else(|| config.bind_addr.clone())
+        .unwrap_or_else(|| format!("0.0.0.0:{port}"));
+    // clap makes sure that these aren't mixed.
+    let domains: Vec<String> = args
+        .acme_domains
+        .iter()
+        .map(|domain| domain.trim().to_owned())
+        .filter(|domain| !domain.is_empty())
+        .collect();
+    let tls_config = match (&args.tls_cert_path, &args.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(listener::load_tls_config(cert_path, key_path)),
+        _ if !domains.is_empty() => Some(listener::start_acme(
+            domains,
+            args.acme_contact.clone(),
+            args.acme_cache_dir.clone(),
+            !args.acme_staging,
+        )),
+        _ => None,
+    };
+
+    let github_api_base_url = args.github_api_base_url.clone().map_or_else(
+        || Url::parse("https://api.github.com/").unwrap(),
+        // Without a trailing slash, joining paths onto the URL would replace its last segment.
+        |mut url| {
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+            url
+        },
     );
+    // GitHub Enterprise Server serves REST from /api/v3/ but GraphQL from /api/graphql.
+    let github_graphql_url = args.github_graphql_url.clone().unwrap_or_else(|| {
+        if github_api_base_url.path().ends_with("/api/v3/") {
+            github_api_base_url.join("../graphql").unwrap()
+        } else {
+            github_api_base_url.join("graphql").unwrap()
+        }
+    });
+
+    let background_refresh_lead_time = Duration::from_secs(args.background_refresh_lead_secs);
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout_secs);
 
-    let default_auth_header = match std::env::var("DEFAULT_AUTH_HEADER") {
-        Ok(value) => {
-            let result = value.parse();
-            match result {
-                Ok(value) => Some(value),
-                Err(err) => panic!("Failed to parse default auth header as header: {:?}", err),
+    let mut passthrough = get(handler);
+    if args.allow_writes {
+        passthrough = passthrough
+            .post(write_handler)
+            .put(write_handler)
+            .patch(write_handler)
+            .delete(write_handler);
+    }
+
+    let cipher = match (&args.cache_encryption_key, &args.cache_encryption_key_file) {
+        (Some(key), _) => Some(EntryCipher::from_hex(key)),
+        (None, Some(path)) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|err| format!("Failed to read {}: {}", path.display(), err))
+                .and_then(|key| EntryCipher::from_hex(&key)),
+        ),
+        (None, None) => None,
+    }
+    .transpose()
+    .unwrap_or_else(|err| panic!("Invalid cache encryption key: {}", err));
+    let disk_cache = args
+        .cache_dir
+        .clone()
+        .map(|dir| DiskCache::new(dir, cipher.clone()));
+    if disk_cache.is_some() && args.cache_backend != CacheBackend::Memory {
+        panic!("--cache-dir is only supported by the memory cache backend");
+    }
+    let cache: Arc<dyn CacheStore> = match args.cache_backend {
+        CacheBackend::Memory => {
+            let max_bytes = args
+                .cache_max_bytes
+                .or(config.cache.max_bytes)
+                .unwrap_or(256 * 1024 * 1024);
+            Arc::new(MemoryStore::new(max_bytes, disk_cache))
+        }
+        CacheBackend::Redis => match RedisStore::connect(&args.redis_url, cipher).await {
+            Ok(store) => Arc::new(store),
+            Err(err) => panic!("Failed to connect to redis at {}: {}", args.redis_url, err),
+        },
+        CacheBackend::Sqlite => match SqliteStore::open(&args.sqlite_path, cipher) {
+            Ok(store) => Arc::new(store),
+            Err(err) => panic!(
+                "Failed to open sqlite database at {}: {}",
+                args.sqlite_path.display(),
+                err
+            ),
+        },
+    };
+
+    let cache_key_salt: Arc<[u8]> = match &args.cache_key_salt {
+        Some(salt) => salt.as_bytes().into(),
+        None => {
+            if args.cache_backend != CacheBackend::Memory || args.cache_dir.is_some() {
+                tracing::warn!(
+                    "Without --cache-key-salt, authenticated cache entries won't be shared \
+                     between restarts or replicas"
+                );
             }
+            rand::thread_rng().gen::<[u8; 32]>().into()
         }
-        Err(VarError::NotPresent) => None,
-        Err(VarError::NotUnicode(_)) => panic!("Failed to parse default auth header as unicode"),
     };
 
-    let app = Router::new()
-        .route("/*path", get(handler))
-        .route("/cached/:minutes/*path", get(cached_handler))
-        .with_state(AppState {
-            client: reqwest::Client::new(),
-            cache: Arc::new(Mutex::new(TtlCache::new(10000))),
-            default_auth_header,
+    let token_pool = Arc::new(TokenPool::default());
+    token_pool.set_tokens(settings.default_auth_headers.clone());
+
+    let upstream_timeout = args
+        .upstream_timeout
+        .or(config.timeouts.upstream)
+        .unwrap_or(Duration::from_secs(30));
+    let request_timeout = args
+        .request_timeout
+        .or(config.timeouts.request)
+        .unwrap_or(Duration::from_secs(120));
+
+    let client = upstream_client(&args, &config);
+
+    let github_app = match (
+        args.github_app_id,
+        args.github_app_installation_id,
+        &args.github_app_private_key_path,
+    ) {
+        (Some(app_id), Some(installation_id), Some(private_key_path)) => {
+            let private_key = std::fs::read(private_key_path).unwrap_or_else(|err| {
+                panic!(
+                    "Failed to read GitHub App private key from {}: {}",
+                    private_key_path.display(),
+                    err
+                )
+            });
+            let app = GitHubApp::new(
+                app_id,
+                installation_id,
+                &private_key,
+                client.clone(),
+                github_api_base_url.clone(),
+            )
+            .unwrap_or_else(|err| panic!("{}", err));
+            if let Err(err) = app.mint_installation_token().await {
+                panic!("Failed to authenticate as GitHub App: {}", err);
+            }
+            let app = Arc::new(app);
+            tokio::spawn(app.clone().refresh_before_expiry());
+            Some(app)
+        }
+        _ => None,
+    };
+
+    let vcr = match (&args.record_upstream, &args.replay_upstream) {
+        (Some(dir), _) => Some(Arc::new(Vcr::record(dir.clone()).unwrap_or_else(|err| {
+            panic!("Failed to create {} to record into: {}", dir.display(), err)
+        }))),
+        (None, Some(dir)) => Some(Arc::new(Vcr::replay(dir.clone()))),
+        (None, None) => None,
+    };
+
+    let metrics = Arc::new(Metrics::new());
+    let state = AppState {
+        upstream: Upstream {
+            client,
+            rate_limit_wait_budget: Duration::from_secs(args.rate_limit_wait_budget_secs),
+            anonymous_fallback: args.anonymous_fallback,
+            retry_attempts: args.upstream_retry_attempts,
+            retry_base_delay: Duration::from_millis(args.upstream_retry_base_delay_ms),
+            hedge_after: args.upstream_hedge_after_ms.map(Duration::from_millis),
+            redirects: args.upstream_redirects,
+            timeout: upstream_timeout,
+            page_fetch_concurrency: args.page_fetch_concurrency as usize,
+            metrics: metrics.clone(),
+            token_pool: token_pool.clone(),
+            github_app,
+            limit: args.max_upstream_concurrency.map(|max_concurrency| {
+                Arc::new(UpstreamLimit::new(
+                    max_concurrency as usize,
+                    args.max_upstream_queue as usize,
+                ))
+            }),
+            circuit_breaker: args.circuit_breaker_error_percent.map(|error_percent| {
+                Arc::new(CircuitBreaker::new(
+                    error_percent,
+                    args.circuit_breaker_min_requests as usize,
+                    args.circuit_breaker_open_for
+                        .unwrap_or(Duration::from_secs(30)),
+                ))
+            }),
+            vcr,
+            priority: Priority::Interactive,
+        },
+        cache,
+        hits: args
+            .background_refresh_min_hits
+            .map(|_| Arc::new(Mutex::new(HashMap::new()))),
+        in_flight: Arc::new(Mutex::new(HashMap::new())),
+        metrics: metrics.clone(),
+        settings: Arc::new(RwLock::new(Arc::new(settings))),
+        invalidate_cache_on_write: args.invalidate_cache_on_write,
+        tls: tls_config.is_some(),
+        readyz_check_upstream: args.readyz_check_upstream,
+        github_api_base_url,
+        github_graphql_url,
+        cache_key_salt,
+        client_rate_limiter: Arc::default(),
+        jq_results: args.allow_jq_transforms.then(transform::jq_results),
+        request_timeout: (!request_timeout.is_zero()).then_some(request_timeout),
+        precompressed: args
+            .precompress_cached_responses
+            .then(compression::precompressed),
+        prefetch_next_pages: args.prefetch_next_pages,
+        chaos: args.chaos,
+    };
+    if args.chaos {
+        tracing::warn!("Injecting faults into responses, as configured in the chaos section");
+    }
+
+    // Replayed responses don't depend on credentials.
+    if !args.skip_credential_check && args.replay_upstream.is_none() {
+        check_default_auth_headers(&state).await;
+    }
+
+    if let Some(path) = args.config.clone() {
+        let settings = state.settings.clone();
+        let settings_args = args.settings.clone();
+        let token_pool = state.upstream.token_pool.clone();
+        let startup_only = config.startup_only();
+        config::watch(path, move |config| {
+            let new_settings = Settings::new(&config, &settings_args)?;
+            token_pool.set_tokens(new_settings.default_auth_headers.clone());
+            *settings.write().unwrap() = Arc::new(new_settings);
+            if config.startup_only() != startup_only {
+                tracing::warn!(
+                    "Changes to port, bind_addr, cache.max_bytes, timeouts and upstream take effect on restart"
+                );
+            }
+            Ok(())
         });
+    }
 
-    axum::Server::bind(
-        &format!("0.0.0.0:{port}")
-            .parse()
-            .expect("Failed to parse SocketAddr"),
-    )
-    .serve(app.into_make_service())
-    .await
-    .unwrap();
+    if let Some(min_hits) = args.background_refresh_min_hits {
+        tokio::spawn(refresh_hot_entries(
+            state.in_background(),
+            min_hits,
+            background_refresh_lead_time,
+        ));
+    }
+
+    tokio::spawn(run_warm_jobs(state.in_background()));
+
+    let proxied = Router::new()
+        .route("/*path", passthrough)
+        .route(
+            "/cached/:max_age/*path",
+            get(cached_handler).delete(purge_handler),
+        )
+        .route("/swr/:max_age/*path", get(stale_while_revalidate_handler))
+        .route("/stream/*path", get(streaming_handler))
+        .route("/aggregate/issues", get(aggregate_issues_handler))
+        .route(
+            "/cached/:max_age/aggregate/issues",
+            get(cached_aggregate_issues_handler),
+        )
+        .route("/batch", post(batch_handler))
+        .route("/graphql", post(graphql_handler))
+        .route("/cached/:max_age/graphql", post(cached_graphql_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            chaos::inject_faults,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            pass_through_raw_media_types,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            limit_client_rate,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            authenticate_api_key,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            check_allowed_path,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            check_client_network,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            enforce_request_timeout,
+        ));
+    let app = Router::new()
+        .route("/webhook", post(webhook_handler))
+        .merge(proxied)
+        .route("/admin", get(admin_dashboard_handler))
+        .route("/admin/cache/stats", get(cache_stats_handler))
+        .route(
+            "/admin/cache/entries",
+            get(cache_entries_handler).delete(purge_entries_handler),
+        )
+        .route("/admin/cache/refresh", post(refresh_entries_handler))
+        .route("/admin/warm", post(warm_handler))
+        .route("/admin/purge", post(purge_repo_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/openapi.json", get(openapi_handler))
+        .route("/readyz", get(readyz_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            record_request_metrics,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            apply_cors,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            compression::serve_precompressed,
+        ))
+        .with_state(state)
+        .layer(axum::middleware::from_fn(not_modified))
+        .layer(compression::layer())
+        .layer(axum::middleware::from_fn(trace_request));
+
+    let shutdown = shutdown_signal().boxed().shared();
+    let mut listener = Listener::bind(&bind_addr, port).await;
+    if let Some(tls_config) = tls_config {
+        listener = listener.with_tls(tls_config);
+    }
+    let server = axum::Server::builder(hyper::server::accept::from_stream(
+        listener.connections.map(Ok::<_, std::io::Error>),
+    ))
+    .serve(app.into_make_service_with_connect_info::<RemoteIp>())
+    .with_graceful_shutdown(shutdown.clone());
+    let drain_deadline = async {
+        shutdown.await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    tokio::select! {
+        result = server => result.unwrap(),
+        () = drain_deadline => {
+            tracing::warn!(?shutdown_timeout, "Timed out waiting for in-flight requests to complete");
+        }
+    }
+    if let Some(path) = &listener.unix_socket_path {
+        listener::remove_unix_socket(path);
+    }
+    telemetry::shutdown();
+}
+
+/// Resolves on SIGINT or SIGTERM, after which no new connections are accepted.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("Failed to listen for SIGINT"),
+        _ = terminate.recv() => {}
+    }
+    tracing::info!("Shutting down once in-flight requests complete");
+}
+
+fn env_parse<T>(name: &str) -> Option<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => Some(value),
+            Err(err) => panic!("Failed to parse ${name} from {:?}: {}", value, err),
+        },
+        Err(VarError::NotPresent) => None,
+        Err(VarError::NotUnicode(_)) => panic!("Failed to parse ${name} as unicode"),
+    }
 }
 
+#[tracing::instrument(skip_all, fields(path = %path))]
 async fn cached_handler(
     State(state): State<AppState>,
-    Path((minutes, path)): Path<(NonZeroU16, String)>,
-    Query(query): Query<IndexMap<String, String>>,
-    mut headers: HeaderMap,
+    Path((max_age, path)): Path<(MaxAge, String)>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    if !headers.contains_key(axum::http::header::AUTHORIZATION) {
-        if let Some(default_auth_header) = state.default_auth_header {
-            headers.append(axum::http::header::AUTHORIZATION, default_auth_header);
-        }
+    let policy = CachePolicy {
+        max_duration: max_age.for_client(api_key),
+        stale_while_revalidate: false,
+    };
+    let route_prefix = format!("/cached/{}/", max_age.segment);
+    cached_rest_response(state, policy, &route_prefix, path, query, headers).await
+}
+
+/// Like `cached_handler`, but stale entries are served immediately while they're refreshed in the
+/// background.
+#[tracing::instrument(skip_all, fields(path = %path))]
+async fn stale_while_revalidate_handler(
+    State(state): State<AppState>,
+    Path((max_age, path)): Path<(MaxAge, String)>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let policy = CachePolicy {
+        max_duration: max_age.for_client(api_key),
+        stale_while_revalidate: true,
+    };
+    let route_prefix = format!("/swr/{}/", max_age.segment);
+    cached_rest_response(state, policy, &route_prefix, path, query, headers).await
+}
+
+/// `route_prefix` is the part of the request's path before `path`, for rewriting links back to the
+/// same route.
+async fn cached_rest_response(
+    state: AppState,
+    mut policy: CachePolicy,
+    route_prefix: &str,
+    path: String,
+    mut query: Option<String>,
+    headers: HeaderMap,
+) -> (StatusCode, HeaderMap, Bytes) {
+    if is_immutable(&path) {
+        policy.max_duration = IMMUTABLE_MAX_DURATION;
+    }
+    let format = OutputFormat::take_from_query(&mut query);
+    let slice = match PageSlice::take_from_query(&mut query) {
+        Ok(slice) => slice,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let transform = match Transform::take_from_query(&mut query, state.jq_results.as_ref()) {
+        Ok(transform) => transform,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let proxy_url = proxy_url(&state, &headers, route_prefix);
+    let (key, refresher) = match rest_cache_request(&state, &path, query, headers) {
+        Ok(request) => request,
+        Err((status_code, err)) => return text_response(status_code, err),
     };
+    let never_cache = state.settings().never_cache(&key.path);
+    let prefetch_headers = match refresher.request {
+        UpstreamRequest::Rest {
+            single_page: true, ..
+        } if state.prefetch_next_pages && !never_cache => Some(refresher.request_headers.clone()),
+        _ => None,
+    };
+    let mut response = if never_cache {
+        state.record_cache_lookup("bypass");
+        let mut response = match refresher
+            .request
+            .fetch(&state, refresher.request_headers)
+            .await
+        {
+            Ok(response) => serialize_for_response(&response),
+            Err(err) => err.to_response(),
+        };
+        insert_x_cache_headers(&mut response.1, "BYPASS", None);
+        response
+    } else {
+        fetch_with_cache(&state, key, policy, refresher).await
+    };
+    if let Some(prefetch_headers) = prefetch_headers {
+        prefetch_next_page(&state, policy, &response.1, prefetch_headers);
+    }
+    rewrite_link_header(&mut response.1, &state.github_api_base_url, &proxy_url);
+    response = transform.apply(response).await;
+    if let Some(slice) = slice {
+        response = slice.apply(response);
+    }
+    format.render(&path, response)
+}
+
+/// Works out the cache key and upstream request for a REST response, once the parameters which
+/// only change how it's rendered have been taken off `query`.
+fn rest_cache_request(
+    state: &AppState,
+    path: &str,
+    mut query: Option<String>,
+    mut headers: HeaderMap,
+) -> Result<(CacheKey, Refresher), (StatusCode, String)> {
+    apply_default_auth_header(state, path, &mut headers);
+    // The limits and pagination mode stay in the key's query, as they change what's cached.
     let key = CacheKey {
-        authorization_header: headers
-            .get(axum::http::header::AUTHORIZATION)
-            .map(|h| h.as_bytes().to_owned()),
-        path: path.clone(),
+        authorization_hash: state.authorization_hash(&headers),
+        path: path.to_owned(),
+        query: query.clone(),
+        body_hash: None,
     };
-    let max_duration = Duration::from_secs(u64::from(u16::from(minutes) * 60));
-    {
-        let cache = state.cache.lock().unwrap();
-        if let Some(value) = cache.get(&key) {
-            if Instant::now().duration_since(value.generated_at) <= max_duration {
-                return serialize_for_response(&value.values);
-            }
-        }
+    let limits = state
+        .settings()
+        .pagination_limits(path)
+        .take_from_query(&mut query)?;
+    let single_page = take_single_page(&mut query)?;
+    let include_comments = take_include_comments(path, &mut query)?;
+    let refresher = Refresher {
+        request_headers: headers,
+        request: UpstreamRequest::Rest {
+            path: path.to_owned(),
+            query,
+            limits,
+            single_page,
+            include_comments,
+        },
+    };
+    Ok((key, refresher))
+}
+
+/// Fetches the page after an unmerged one into the cache in the background, unless it's cached
+/// already, so that a client paging through a response gets hits on every page after the first.
+///
+/// Only the next page is fetched, rather than following its links in turn, so that a client which
+/// stops after the first page costs at most one extra request.
+fn prefetch_next_page(
+    state: &AppState,
+    policy: CachePolicy,
+    response_headers: &HeaderMap,
+    request_headers: HeaderMap,
+) {
+    let Some((path, query)) = next_page(response_headers, &state.github_api_base_url) else {
+        return;
+    };
+    // The key matches the one the client's request for the rewritten link will look up.
+    let Ok((key, refresher)) = rest_cache_request(state, &path, Some(query), request_headers)
+    else {
+        return;
+    };
+    if state.settings().never_cache(&key.path) {
+        return;
     }
-    match fetch_from_github(
-        state.client,
-        RequestableUrl::GitHubApi { path, query },
-        headers,
-    )
-    .await
-    {
-        Ok(github_response) => {
-            let response = serialize_for_response(&github_response);
-            if response.0.is_success() {
-                let mut cache = state.cache.lock().unwrap();
-                cache.insert(
-                    key,
-                    CacheValue {
-                        generated_at: Instant::now(),
-                        values: github_response,
-                    },
-                    max_duration,
-                );
+    let state = state.in_background();
+    tokio::spawn(async move {
+        let page_etags = match state.cache.get(&key).await {
+            Some(value)
+                if Instant::now().duration_since(value.generated_at)
+                    <= state.settings().fresh_for(&value, policy.max_duration) =>
+            {
+                return;
             }
-            response
-        }
-        Err((status_code, err)) => (status_code, cors_allow_all(), err),
-    }
+            Some(value) => value.page_etags.clone(),
+            None => None,
+        };
+        start_refresh(&state, key, policy.max_duration, page_etags, refresher).await;
+    });
 }
 
-async fn handler(
+/// The path and query of the page after this one, as the client will request it through the
+/// proxy's rewritten `Link` header, if there is one on github.
+fn next_page(response_headers: &HeaderMap, github_api_base_url: &Url) -> Option<(String, String)> {
+    let next = page_links(response_headers).ok()?.next?;
+    let next = rewrite_link_url(&next, github_api_base_url, "")?;
+    let (path, query) = next.split_once('?')?;
+    Some((path.to_owned(), query.to_owned()))
+}
+
+/// Merges the issues of several repositories, e.g.
+/// `/aggregate/issues?repos=org/a,org/b&state=open`, so that dashboards tracking many repositories
+/// don't have to fetch each themselves. Any other parameters are passed on for every repository.
+///
+/// Responses are cached according to the route rules for `aggregate/issues`.
+#[tracing::instrument(skip_all)]
+async fn aggregate_issues_handler(
     State(state): State<AppState>,
-    Path(path): Path<String>,
-    Query(query): Query<IndexMap<String, String>>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    match fetch_from_github(
-        state.client,
-        RequestableUrl::GitHubApi { path, query },
-        headers,
-    )
-    .await
+    let policy = match state.settings().ttl("aggregate/issues") {
+        Ttl::For(max_duration) => Some(CachePolicy {
+            max_duration,
+            stale_while_revalidate: false,
+        }),
+        Ttl::Never => None,
+    };
+    aggregate_issues_response(state, policy, query, headers).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn cached_aggregate_issues_handler(
+    State(state): State<AppState>,
+    Path(max_age): Path<MaxAge>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let policy = CachePolicy {
+        max_duration: max_age.for_client(api_key),
+        stale_while_revalidate: false,
+    };
+    aggregate_issues_response(state, Some(policy), query, headers).await
+}
+
+async fn aggregate_issues_response(
+    state: AppState,
+    policy: Option<CachePolicy>,
+    mut query: Option<String>,
+    mut headers: HeaderMap,
+) -> (StatusCode, HeaderMap, Bytes) {
+    let format = OutputFormat::take_from_query(&mut query);
+    let transform = match Transform::take_from_query(&mut query, state.jq_results.as_ref()) {
+        Ok(transform) => transform,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    // The repositories, limits and pagination mode stay in the key's query, as they change what's
+    // cached.
+    let key_query = query.clone();
+    let repos = match take_aggregated_repos(&mut query) {
+        Ok(repos) => repos,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let limits = match state
+        .settings()
+        .pagination_limits("aggregate/issues")
+        .take_from_query(&mut query)
     {
-        Ok(response) => serialize_for_response(&response),
-        Err((status_code, err)) => (status_code, cors_allow_all(), err),
-    }
+        Ok(limits) => limits,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    apply_default_auth_header(&state, "aggregate/issues", &mut headers);
+    let key = CacheKey {
+        authorization_hash: state.authorization_hash(&headers),
+        path: "aggregate/issues".to_owned(),
+        query: key_query,
+        body_hash: None,
+    };
+    let refresher = Refresher {
+        request_headers: headers,
+        request: UpstreamRequest::AggregateIssues {
+            repos,
+            query,
+            limits,
+        },
+    };
+    let response = match policy {
+        Some(policy) => fetch_with_cache(&state, key, policy, refresher).await,
+        None => match refresher
+            .request
+            .fetch(&state, refresher.request_headers)
+            .await
+        {
+            Ok(response) => serialize_for_response(&response),
+            Err(err) => err.to_response(),
+        },
+    };
+    format.render("aggregate/issues", transform.apply(response).await)
 }
 
-fn serialize_for_response(response: &OpaqueJsonArray) -> (StatusCode, HeaderMap, String) {
-    match serde_json::to_string(response) {
-        Ok(response) => (StatusCode::OK, cors_allow_all(), response),
-        Err(err) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            cors_allow_all(),
-            format!("Failed to serialize response: {}", err),
-        ),
+/// How many repositories one aggregated request may fetch from.
+const MAX_AGGREGATED_REPOS: usize = 100;
+
+/// Removes the `repos` parameter from `query`, returning the `owner/name` of each repository it
+/// lists.
+fn take_aggregated_repos(query: &mut Option<String>) -> Result<Vec<String>, (StatusCode, String)> {
+    let Some(repos) = take_query_param(query, "repos") else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "repos must list the repositories to aggregate, e.g. repos=org/a,org/b".to_owned(),
+        ));
+    };
+    // Browsers percent-encode the commas and slashes.
+    let repos = url::form_urlencoded::parse(format!("repos={}", repos).as_bytes())
+        .map(|(_, repos)| repos.into_owned())
+        .next()
+        .unwrap_or_default();
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && name != "."
+            && name != ".."
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    };
+    let repos: Vec<String> = repos
+        .split(',')
+        .map(str::trim)
+        .filter(|repo| !repo.is_empty())
+        .map(|repo| match repo.split_once('/') {
+            Some((owner, name)) if is_name(owner) && is_name(name) => Ok(repo.to_owned()),
+            _ => Err((
+                StatusCode::BAD_REQUEST,
+                format!("{:?} isn't a repository, e.g. org/name", repo),
+            )),
+        })
+        .collect::<Result<_, _>>()?;
+    if repos.is_empty() || repos.len() > MAX_AGGREGATED_REPOS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "repos must list between 1 and {} repositories",
+                MAX_AGGREGATED_REPOS
+            ),
+        ));
     }
+    Ok(repos)
 }
 
-fn fetch_from_github(
-    client: reqwest::Client,
-    url: RequestableUrl,
-    request_headers: HeaderMap,
-) -> BoxFuture<'static, Result<OpaqueJsonArray, (StatusCode, String)>> {
-    async move {
-        let url = url.into_string();
-        let mut builder = client.get(&url);
-        for (key, value) in request_headers.iter() {
-            match key.as_str() {
-                "host" => match Url::parse(&url) {
-                    Ok(url) => {
-                        if let Some(host) = url.host_str() {
-                            builder = builder.header(key.clone(), host);
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!(
-                            "Skipping setting host header - Failed to parse URL from \"{}\": {}",
-                            url, err
-                        );
-                    }
-                },
-                "accept-encoding" => {
-                    // We don't handle decompression, so drop any requests for compression.
-                }
-                key => {
-                    builder = builder.header(key, value.clone());
-                }
-            }
-        }
-        let response = builder.send().await.map_err(|err| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to make request to github: {:?}", err),
+async fn cached_graphql_handler(
+    State(state): State<AppState>,
+    Path(max_age): Path<MaxAge>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    mut headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    apply_default_auth_header(&state, "graphql", &mut headers);
+    let request: GraphQlRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                format!("Failed to parse GraphQL request: {}", err),
             )
-        })?;
-        if !response.status().is_success() {
-            return Err((
-                StatusCode::from_u16(response.status().as_u16())
-                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    };
+    let key = CacheKey {
+        authorization_hash: state.authorization_hash(&headers),
+        path: "graphql".to_owned(),
+        query: None,
+        body_hash: Some(request.cache_hash()),
+    };
+    let policy = CachePolicy {
+        max_duration: max_age.for_client(api_key),
+        stale_while_revalidate: false,
+    };
+    let refresher = Refresher {
+        request_headers: headers,
+        request: UpstreamRequest::GraphQl(request),
+    };
+    fetch_with_cache(&state, key, policy, refresher).await
+}
+
+async fn fetch_with_cache(
+    state: &AppState,
+    key: CacheKey,
+    policy: CachePolicy,
+    refresher: Refresher,
+) -> (StatusCode, HeaderMap, Bytes) {
+    let refresh_requested = refresh_requested(&refresher.request_headers);
+    let deadline = DEADLINE.try_with(|deadline| *deadline).ok();
+    let (page_etags, stale_response, fallback) = match state.cache.get(&key).await {
+        Some(value) => {
+            if let Some(hits) = &state.hits {
+                *hits.lock().unwrap().entry(key.clone()).or_default() += 1;
+            }
+            // Negative entries are only fresh for the negative TTL, whichever route reads them.
+            let max_duration = if value.body.status.is_success() {
+                state.settings().fresh_for(&value, policy.max_duration)
+            } else {
+                policy.max_duration.min(value.max_duration)
+            };
+            if !refresh_requested
+                && Instant::now().duration_since(value.generated_at) <= max_duration
+            {
+                state.record_cache_lookup("hit");
+                let mut response = value.to_response(max_duration);
+                insert_x_cache_headers(&mut response.1, "HIT", Some(value.generated_at));
+                return response;
+            }
+            let stale = || {
+                let mut response = value.to_response(max_duration);
+                insert_x_cache_headers(&mut response.1, "STALE", Some(value.generated_at));
                 response
-                    .text()
-                    .await
-                    .unwrap_or_else(|err| format!("Failed to read response body: {}", err)),
+            };
+            // While github is failing, anything cached is better than a quick 503.
+            let circuit_open = state
+                .upstream
+                .circuit_breaker
+                .as_ref()
+                .is_some_and(|circuit_breaker| circuit_breaker.is_open());
+            let stale_response =
+                ((policy.stale_while_revalidate && !refresh_requested) || circuit_open).then(stale);
+            // Served instead if the refresh outlasts the client's deadline.
+            let fallback = deadline.and(stale_response.is_none().then(stale));
+            let result = if stale_response.is_some() {
+                "stale"
+            } else if refresh_requested {
+                "refresh"
+            } else {
+                "miss"
+            };
+            state.record_cache_lookup(result);
+            (value.page_etags.clone(), stale_response, fallback)
+        }
+        None => {
+            state.record_cache_lookup("miss");
+            (None, None, None)
+        }
+    };
+    match stale_response {
+        Some(stale_response) => {
+            tokio::spawn(start_refresh(
+                &state.in_background(),
+                key,
+                policy.max_duration,
+                page_etags,
+                refresher,
             ));
+            stale_response
         }
-        let mut response_headers = response.headers().clone();
-        let response_body = response.text().await.map_err(|err| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to read response: {}", err),
-            )
-        })?;
-        let mut values: OpaqueJsonArray = serde_json::from_str(&response_body).map_err(|err| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to read response \"{}\": {}", response_body, err),
-            )
-        })?;
-        if let Some(link) = response_headers.remove("link") {
-            let link_map = match link.to_str() {
-                Ok(link) => match parse_link_header::parse(link) {
-                    Ok(link_map) => link_map,
-                    Err(err) => {
-                        return Err((
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Failed to parse link map \"{}\": {}", link, err),
-                        ))
+        None => {
+            let refresh = start_refresh(state, key, policy.max_duration, page_etags, refresher);
+            let mut response = match (deadline, fallback) {
+                (Some(deadline), Some(fallback)) => {
+                    match tokio::time::timeout_at(deadline.into(), refresh).await {
+                        Ok(response) => response,
+                        Err(_) => return fallback,
                     }
-                },
-                Err(err) => {
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to parse link header \"{:?}\": {}", link, err),
-                    ));
                 }
+                _ => refresh.await,
             };
-            if let Some(link) = link_map.get(&Some("next".to_owned())) {
-                let rest = fetch_from_github(
-                    client,
-                    RequestableUrl::Absolute(link.uri.to_string()),
-                    request_headers,
-                )
-                .await
-                .map_err(|err| {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to make follow-up request to github: {:?}", err),
-                    )
-                })?;
-                values.values.extend(rest.values);
+            // Unless the refresh failed and an expired entry was served instead.
+            if !response.1.contains_key("x-cache") {
+                insert_x_cache_headers(&mut response.1, "MISS", None);
             }
+            response
         }
-        Ok(values)
     }
-    .boxed()
 }
 
-enum RequestableUrl {
-    GitHubApi {
-        path: String,
-        query: IndexMap<String, String>,
-    },
-    Absolute(String),
+/// The expired entry for `key`, if refreshing it failed with `status_code` in a way which might
+/// not last (a 5XX, a rate limit, or a network error) and it went stale no longer ago than we serve
+/// entries after errors like that.
+async fn stale_if_error(
+    state: &AppState,
+    key: &CacheKey,
+    max_duration: Duration,
+    status_code: StatusCode,
+) -> Option<(StatusCode, HeaderMap, Bytes)> {
+    let settings = state.settings();
+    let stale_if_error = settings.stale_if_error?;
+    if !(status_code.is_server_error() || status_code == StatusCode::TOO_MANY_REQUESTS) {
+        return None;
+    }
+    let value = state.cache.get(key).await?;
+    if !value.body.status.is_success() {
+        return None;
+    }
+    let fresh_for = settings.fresh_for(&value, max_duration);
+    if Instant::now().duration_since(value.generated_at) > fresh_for + stale_if_error {
+        return None;
+    }
+    tracing::warn!(
+        path = key.path,
+        %status_code,
+        "Failed to refresh cached response, serving it stale"
+    );
+    let mut response = value.to_response(fresh_for);
+    insert_x_cache_headers(&mut response.1, "STALE", Some(value.generated_at));
+    response.1.append(
+        axum::http::header::WARNING,
+        axum::http::HeaderValue::from_static("111 - \"Revalidation Failed\""),
+    );
+    Some(response)
 }
 
-impl RequestableUrl {
-    fn into_string(self) -> String {
-        match self {
-            RequestableUrl::GitHubApi { path, query } => {
-                let mut url = url::Url::parse("https://api.github.com/")
-                    .unwrap()
-                    .join(&path)
-                    // TODO: Justify this unwrap.
-                    .unwrap();
-                url.set_query(Some(
-                    &query
-                        .iter()
-                        .map(|(key, value)| format!("{key}={value}"))
-                        .collect::<Vec<_>>()
-                        .join("&"),
-                ));
-                url.to_string()
-            }
-            RequestableUrl::Absolute(url) => url,
+/// Tells the client whether its response came from the cache, and if so how old it is, for
+/// debugging stale data.
+fn insert_x_cache_headers(
+    headers: &mut HeaderMap,
+    result: &'static str,
+    generated_at: Option<Instant>,
+) {
+    headers.insert("x-cache", axum::http::HeaderValue::from_static(result));
+    if let Some(generated_at) = generated_at {
+        let age = Instant::now().duration_since(generated_at);
+        headers.insert("x-cache-age", age.as_secs().into());
+    }
+}
+
+/// Whether the client asked for a fresh response with `Cache-Control: no-cache` or
+/// `X-Refresh: true`, e.g. because it has just changed something. Cached entries are still
+/// revalidated with their ETags rather than fetched from scratch, as that's cheaper.
+fn refresh_requested(request_headers: &HeaderMap) -> bool {
+    let no_cache = request_headers
+        .get_all(axum::http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+    let x_refresh = request_headers
+        .get("x-refresh")
+        .is_some_and(|value| value == "true" || value == "1");
+    no_cache || x_refresh
+}
+
+/// Starts refreshing a cache entry, unless it's already being refreshed.
+///
+/// Concurrent misses for the same key all wait on whichever request got there first, rather than
+/// each making their own identical requests to github.
+fn start_refresh(
+    state: &AppState,
+    key: CacheKey,
+    max_duration: Duration,
+    page_etags: Option<Vec<PageEtag>>,
+    refresher: Refresher,
+) -> Refresh {
+    let mut in_flight = state.in_flight.lock().unwrap();
+    let shared = in_flight
+        .entry(key.clone())
+        .or_insert_with(|| {
+            refresh_cache(state.clone(), key, max_duration, page_etags, refresher)
+                .boxed()
+                .shared()
+        })
+        .clone();
+    Refresh(Some(shared))
+}
+
+/// A refresh of a cache entry, which carries on in the background if whoever is waiting for it
+/// goes away (e.g. because the client disconnected or its deadline passed), as its response will
+/// be cached for the next request. Otherwise it would sit half-done until the key was next
+/// requested.
+///
+/// Uncached responses are fetched by the handler itself, so are abandoned along with it.
+struct Refresh(Option<SharedResponse>);
+
+impl Future for Refresh {
+    type Output = (StatusCode, HeaderMap, Bytes);
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let shared = self.0.as_mut().expect("Refresh polled after completing");
+        let response = std::task::ready!(shared.poll_unpin(cx));
+        self.0 = None;
+        std::task::Poll::Ready(response)
+    }
+}
+
+impl Drop for Refresh {
+    fn drop(&mut self) {
+        let Some(shared) = self.0.take() else {
+            return;
+        };
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(shared);
         }
     }
 }
 
-fn cors_allow_all() -> HeaderMap {
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-        "*".parse().unwrap(),
-    );
-    headers
+async fn refresh_cache(
+    state: AppState,
+    key: CacheKey,
+    max_duration: Duration,
+    page_etags: Option<Vec<PageEtag>>,
+    refresher: Refresher,
+) -> (StatusCode, HeaderMap, Bytes) {
+    let response = async {
+        if let Some(page_etags) = page_etags {
+            if revalidate_with_github(&state.upstream, &refresher.request_headers, &page_etags)
+                .await
+            {
+                // Re-insert rather than updating in place so that the entry's retention is extended.
+                if let Some(value) = state.cache.get(&key).await {
+                    let value = CacheValue {
+                        generated_at: Instant::now(),
+                        max_duration,
+                        stability: value.refreshed_stability(false),
+                        ..(*value).clone()
+                    };
+                    let response =
+                        value.to_response(state.settings().fresh_for(&value, max_duration));
+                    insert_into_cache(&state, &key, value).await;
+                    return response;
+                }
+            }
+        }
+        let fetch = refresher
+            .request
+            .fetch(&state, refresher.request_headers.clone());
+        match fetch.await {
+            Ok(github_response) => {
+                let body = match SerializedBody::new(&github_response) {
+                    Ok(body) => body,
+                    Err((status_code, err)) => return text_response(status_code, err),
+                };
+                let mut response = if refresher.request.cacheable(&github_response.values) {
+                    let stability = match state.cache.get(&key).await {
+                        Some(previous) => {
+                            previous.refreshed_stability(previous.body.etag != body.etag)
+                        }
+                        None => 0,
+                    };
+                    let value = CacheValue {
+                        generated_at: Instant::now(),
+                        max_duration,
+                        body: body.clone(),
+                        page_etags: github_response.page_etags,
+                        refresher: refresher.without_credentials(),
+                        stability,
+                    };
+                    let response =
+                        value.to_response(state.settings().fresh_for(&value, max_duration));
+                    insert_into_cache(&state, &key, value).await;
+                    response
+                } else {
+                    body.to_response()
+                };
+                insert_rate_limit_headers(&mut response.1, &github_response.rate_limit);
+                response
+            }
+            Err(err) => {
+                let status_code = err.status_code;
+                if let Some(response) =
+                    stale_if_error(&state, &key, max_duration, status_code).await
+                {
+                    return response;
+                }
+                let negative_ttl = match state.settings().negative_ttl {
+                    Ttl::For(negative_ttl) => Some(negative_ttl),
+                    Ttl::Never => None,
+                };
+                match negative_ttl {
+                    Some(negative_ttl)
+                        if matches!(status_code, StatusCode::NOT_FOUND | StatusCode::GONE)
+                            && matches!(refresher.request, UpstreamRequest::Rest { .. }) =>
+                    {
+                        let body = SerializedBody::error(status_code, err.message);
+                        let value = CacheValue {
+                            generated_at: Instant::now(),
+                            max_duration: max_duration.min(negative_ttl),
+                            body: body.clone(),
+                            page_etags: None,
+                            refresher: refresher.without_credentials(),
+                            stability: 0,
+                        };
+                        let response = value.to_response(value.max_duration);
+                        insert_into_cache(&state, &key, value).await;
+                        response
+                    }
+                    _ => err.to_response(),
+                }
+            }
+        }
+    }
+    .await;
+    state.in_flight.lock().unwrap().remove(&key);
+    response
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(transparent)]
-struct OpaqueJsonArray {
-    #[serde(flatten)]
-    values: Vec<serde_json::Value>,
+/// Stores `value`, keeping it for a while after it goes stale so that it can be revalidated.
+async fn insert_into_cache(state: &AppState, key: &CacheKey, value: CacheValue) {
+    let settings = state.settings();
+    let retention = settings.fresh_for(&value, value.max_duration)
+        + settings
+            .stale_retention
+            .max(settings.stale_if_error.unwrap_or_default());
+    compression::precompress(state, &value.body);
+    state
+        .cache
+        .insert(key.clone(), Arc::new(value), retention)
+        .await;
+    state.reset_hits(key);
 }
 
-#[derive(Clone)]
-struct AppState {
-    client: reqwest::Client,
-    cache: Arc<Mutex<TtlCache<CacheKey, CacheValue>>>,
-    default_auth_header: Option<axum::http::header::HeaderValue>,
+/// Periodically refreshes entries which have been read at least `min_hits` times since they were
+/// fetched, shortly before they go stale, so that popular entries never miss.
+async fn refresh_hot_entries(state: AppState, min_hits: u64, lead_time: Duration) {
+    let Some(hits) = state.hits.clone() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(lead_time / 2);
+    loop {
+        interval.tick().await;
+        // Forget about entries which have been evicted, so that hits don't grow forever.
+        let keys: HashSet<_> = state.cache.keys().await.into_iter().collect();
+        let hot: Vec<_> = {
+            let mut hits = hits.lock().unwrap();
+            hits.retain(|key, _| keys.contains(key));
+            hits.iter()
+                .filter(|(_, hits)| **hits >= min_hits)
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        for key in hot {
+            let Some(value) = state.cache.get(&key).await else {
+                continue;
+            };
+            let age = Instant::now().duration_since(value.generated_at);
+            let fresh_for = state.settings().fresh_for(&value, value.max_duration);
+            if age + lead_time >= fresh_for && age <= fresh_for {
+                let Some(refresher) = value.refresher.with_default_credentials(&state, &key) else {
+                    continue;
+                };
+                tokio::spawn(start_refresh(
+                    &state,
+                    key,
+                    value.max_duration,
+                    value.page_etags.clone(),
+                    refresher,
+                ));
+            }
+        }
+    }
 }
 
-#[derive(Hash, PartialEq, Eq)]
-struct CacheKey {
-    authorization_header: Option<Vec<u8>>,
-    path: String,
+/// Asks GitHub whether every page of a cached response is unchanged.
+///
+/// Conditional requests answered with a 304 don't count against the rate limit, so this is much
+/// cheaper than re-fetching, even for responses made up of many pages.
+async fn revalidate_with_github(
+    upstream: &Upstream,
+    request_headers: &HeaderMap,
+    page_etags: &[PageEtag],
+) -> bool {
+    for page in page_etags {
+        let Ok(_permit) = upstream.acquire().await else {
+            return false;
+        };
+        let Ok(request) = forward_request_headers(upstream.client.get(&page.url), request_headers)
+            .header(axum::http::header::IF_NONE_MATCH, page.etag.clone())
+            .build()
+        else {
+            return false;
+        };
+        let started = Instant::now();
+        let response = execute(upstream, request).await;
+        upstream
+            .metrics
+            .observe_upstream(started, response.as_ref().ok());
+        match response {
+            Ok(response) if response.status() == reqwest::StatusCode::NOT_MODIFIED => {}
+            _ => return false,
+        }
+    }
+    true
 }
 
-struct CacheValue {
-    values: OpaqueJsonArray,
+fn apply_default_auth_header(state: &AppState, path: &str, headers: &mut HeaderMap) {
+    if !headers.contains_key(axum::http::header::AUTHORIZATION) {
+        let settings = state.settings();
+        let default_auth_header = match (
+            settings.route_setting(path, |rule| rule.default_auth_header.clone()),
+            &state.upstream.github_app,
+        ) {
+            (Some(route_auth_header), _) => Some(route_auth_header),
+            (None, Some(github_app)) => github_app.auth_header(),
+            // Requests are rotated between the rest of the default auth headers as they're sent.
+            (None, None) => settings.default_auth_headers.first().cloned(),
+        };
+        if let Some(default_auth_header) = default_auth_header {
+            headers.append(axum::http::header::AUTHORIZATION, default_auth_header);
+        }
+    };
+}
+
+#[tracing::instrument(skip_all, fields(path = %path))]
+async fn handler(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    rest_response(state, path, query, headers).await
+}
+
+async fn rest_response(
+    state: AppState,
+    path: String,
+    mut query: Option<String>,
+    mut headers: HeaderMap,
+) -> (StatusCode, HeaderMap, Bytes) {
+    if let Ttl::For(max_duration) = state.settings().ttl(&path) {
+        let policy = CachePolicy {
+            max_duration,
+            stale_while_revalidate: false,
+        };
+        return cached_rest_response(state, policy, "/", path, query, headers).await;
+    }
+    let format = OutputFormat::take_from_query(&mut query);
+    let transform = match Transform::take_from_query(&mut query, state.jq_results.as_ref()) {
+        Ok(transform) => transform,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let limits = match state
+        .settings()
+        .pagination_limits(&path)
+        .take_from_query(&mut query)
+    {
+        Ok(limits) => limits,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let single_page = match take_single_page(&mut query) {
+        Ok(single_page) => single_page,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let include_comments = match take_include_comments(&path, &mut query) {
+        Ok(include_comments) => include_comments,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let proxy_url = proxy_url(&state, &headers, "/");
+    apply_default_auth_header(&state, &path, &mut headers);
+    let request = UpstreamRequest::Rest {
+        path: path.clone(),
+        query,
+        limits,
+        single_page,
+        include_comments,
+    };
+    let mut response = match request.fetch(&state, headers).await {
+        Ok(response) => serialize_for_response(&response),
+        Err(err) => err.to_response(),
+    };
+    rewrite_link_header(&mut response.1, &state.github_api_base_url, &proxy_url);
+    format.render(&path, transform.apply(response).await)
+}
+
+/// How many paths one batch request may fetch.
+const MAX_BATCH_PATHS: usize = 100;
+
+/// Fetches each of a JSON array of paths (with any query strings) as if it had been requested on
+/// its own from `/{path}`, so that pages which need many small responses can get them in one round
+/// trip. Responds with an object mapping each path to its status and body.
+async fn batch_handler(
+    State(state): State<AppState>,
+    api_key: Option<Extension<Arc<ApiKey>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let requested: Vec<String> = match serde_json::from_slice(&body) {
+        Ok(requested) => requested,
+        Err(err) => {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                format!("Failed to parse batch request as a list of paths: {}", err),
+            )
+        }
+    };
+    if requested.len() > MAX_BATCH_PATHS {
+        return text_response(
+            StatusCode::BAD_REQUEST,
+            format!("Batches may fetch at most {} paths", MAX_BATCH_PATHS),
+        );
+    }
+    let settings = state.settings();
+    let concurrency = state.upstream.page_fetch_concurrency;
+    let results: serde_json::Map<_, _> = futures::stream::iter(requested)
+        .map(|requested| {
+            let state = state.clone();
+            let settings = settings.clone();
+            let api_key = api_key.clone();
+            let headers = headers.clone();
+            async move {
+                let (path, query) = match requested.split_once('?') {
+                    Some((path, query)) => (path, Some(query.to_owned())),
+                    None => (requested.as_str(), None),
+                };
+                let path = path.trim_start_matches('/').to_owned();
+                let (status_code, response_headers, body) = if !settings.serves(&path) {
+                    text_response(
+                        StatusCode::FORBIDDEN,
+                        format!("This proxy doesn't serve {}", path),
+                    )
+                } else if api_key
+                    .as_ref()
+                    .is_some_and(|Extension(api_key)| !api_key.allows(&path))
+                {
+                    text_response(
+                        StatusCode::FORBIDDEN,
+                        format!("This X-Proxy-Key may not request {}", path),
+                    )
+                } else {
+                    rest_response(state, path, query, headers).await
+                };
+                let body = serde_json::from_slice(&body).unwrap_or_else(|_| {
+                    serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())
+                });
+                let result = serde_json::json!({
+                    "status": status_code.as_u16(),
+                    "cache": response_headers
+                        .get("x-cache")
+                        .and_then(|value| value.to_str().ok()),
+                    "body": body,
+                });
+                (requested, result)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static("application/json"),
+    );
+    (
+        StatusCode::OK,
+        response_headers,
+        Bytes::from(serde_json::Value::Object(results).to_string()),
+    )
+}
+
+async fn graphql_handler(
+    State(state): State<AppState>,
+    mut headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, HeaderMap, Bytes) {
+    apply_default_auth_header(&state, "graphql", &mut headers);
+    match fetch_graphql(state.upstream, &state.github_graphql_url, headers, body).await {
+        Ok(response) => serialize_for_response(&response),
+        Err(err) => err.to_response(),
+    }
+}
+
+/// Removes any `merge_pages=` parameter from `query`, returning whether only a single page should be
+/// fetched, with its `Link` header passed back so that the client can page through responses
+/// itself.
+fn take_single_page(query: &mut Option<String>) -> Result<bool, (StatusCode, String)> {
+    match take_query_param(query, "merge_pages").as_deref() {
+        None | Some("true") => Ok(false),
+        Some("false") => Ok(true),
+        Some(value) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Failed to parse merge_pages from {:?}", value),
+        )),
+    }
+}
+
+/// Removes the `include` parameter from `query`, returning whether it asked for each issue's
+/// comments to be embedded in a list of issues at `path`.
+fn take_include_comments(
+    path: &str,
+    query: &mut Option<String>,
+) -> Result<bool, (StatusCode, String)> {
+    match take_query_param(query, "include").as_deref() {
+        None => Ok(false),
+        Some("comments") if path.trim_end_matches('/').ends_with("issues") => Ok(true),
+        Some("comments") => Err((
+            StatusCode::BAD_REQUEST,
+            "include=comments is only supported on lists of issues".to_owned(),
+        )),
+        Some(value) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Failed to parse include from {:?}", value),
+        )),
+    }
+}
+
+/// The URL of `route_prefix` on this proxy, as the client addressed it, or just `route_prefix` if
+/// the client didn't say which host it was talking to.
+fn proxy_url(state: &AppState, request_headers: &HeaderMap, route_prefix: &str) -> String {
+    let Some(host) = request_headers
+        .get(axum::http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+    else {
+        return route_prefix.to_owned();
+    };
+    let scheme = request_headers
+        .get("x-forwarded-proto")
+        .and_then(|scheme| scheme.to_str().ok())
+        .unwrap_or(if state.tls { "https" } else { "http" });
+    format!("{scheme}://{host}{route_prefix}")
+}
+
+/// Points the github URLs in a `Link` header at `proxy_url` instead, keeping pages unmerged, so
+/// that clients which understand github's pagination can page through the proxy.
+fn rewrite_link_header(
+    response_headers: &mut HeaderMap,
+    github_api_base_url: &Url,
+    proxy_url: &str,
+) {
+    let Some(link) = response_headers
+        .get(axum::http::header::LINK)
+        .and_then(|link| link.to_str().ok())
+    else {
+        return;
+    };
+    // URLs may contain commas, so find them by their angle brackets rather than splitting links.
+    let mut parts = link.split('<');
+    let mut rewritten = parts.next().unwrap_or_default().to_owned();
+    for part in parts {
+        rewritten.push('<');
+        match part.split_once('>') {
+            Some((url, rest)) => {
+                match rewrite_link_url(url, github_api_base_url, proxy_url) {
+                    Some(url) => rewritten.push_str(&url),
+                    None => rewritten.push_str(url),
+                }
+                rewritten.push('>');
+                rewritten.push_str(rest);
+            }
+            None => rewritten.push_str(part),
+        }
+    }
+    if let Ok(rewritten) = rewritten.parse() {
+        response_headers.insert(axum::http::header::LINK, rewritten);
+    }
+}
+
+/// Points one of github's page URLs at `proxy_url`, keeping pages unmerged, or returns `None` if
+/// it isn't under `github_api_base_url`.
+fn rewrite_link_url(url: &str, github_api_base_url: &Url, proxy_url: &str) -> Option<String> {
+    // Parsing normalises the URL (e.g. the case of its host, and default ports) before comparing.
+    let url = Url::parse(url).ok()?;
+    if url.origin() != github_api_base_url.origin() {
+        return None;
+    }
+    let path = url.path().strip_prefix(github_api_base_url.path())?;
+    let mut query: Vec<&str> = url
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty() && param.split('=').next() != Some("merge_pages"))
+        .collect();
+    query.push("merge_pages=false");
+    Some(format!("{proxy_url}{path}?{}", query.join("&")))
+}
+
+/// Like `handler`, but streams array responses to the client page by page as they arrive from
+/// github, rather than waiting for every page before responding.
+///
+/// Once streaming has started the status code can't be changed, so if a later page fails the
+/// response is cut off: the client sees invalid JSON rather than a silently truncated array.
+/// Similarly, there's no warning header if pagination limits end the response early.
+async fn streaming_handler(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    RawQuery(mut query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let format = OutputFormat::take_from_query(&mut query);
+    if matches!(format, OutputFormat::Atom | OutputFormat::ICalendar) {
+        // Feeds are small, and an Atom feed's last-updated time comes before its entries, so they
+        // need every page first.
+        return text_response(
+            StatusCode::BAD_REQUEST,
+            format!("Feeds can't be streamed; request /{} instead", path),
+        )
+        .into_response();
+    }
+    let limits = match state
+        .settings()
+        .pagination_limits(&path)
+        .take_from_query(&mut query)
+    {
+        Ok(limits) => limits,
+        Err((status_code, err)) => return text_response(status_code, err).into_response(),
+    };
+    let url = RequestableUrl::GitHubApi {
+        base_url: state.github_api_base_url.clone(),
+        path: path.clone(),
+        query,
+    }
+    .into_string();
+    let builder = forward_request_headers(state.upstream.client.get(&url), &headers);
+    let (response_headers, values) = match send_to_github(&state.upstream, builder).await {
+        Ok(response) => response,
+        Err(err) => return err.to_response().into_response(),
+    };
+    let rate_limit = rate_limit_headers(&response_headers);
+    let OpaqueJson::Array(mut first_page) = values else {
+        let response = GitHubResponse {
+            values,
+            page_etags: None,
+            truncated: false,
+            link: None,
+            rate_limit,
+        };
+        return format
+            .render(&path, serialize_for_response(&response))
+            .into_response();
+    };
+    let next_url = match page_links(&response_headers) {
+        Ok(links) => links.next,
+        Err((status_code, err)) => return text_response(status_code, err).into_response(),
+    };
+    let (_, limits) = limits.apply_to_page(&mut first_page);
+
+    let mut first_chunk = match format {
+        OutputFormat::Json => b"[".to_vec(),
+        OutputFormat::Ndjson | OutputFormat::Atom | OutputFormat::ICalendar => Vec::new(),
+    };
+    write_array_items(&mut first_chunk, &first_page, true, format);
+    let upstream = state.upstream;
+    let rest = futures::stream::try_unfold(
+        (next_url, limits, first_page.is_empty()),
+        move |(next_url, limits, empty_so_far)| {
+            let upstream = upstream.clone();
+            let headers = headers.clone();
+            async move {
+                let (Some(url), Some(limits)) = (next_url, limits) else {
+                    return Ok(None);
+                };
+                let page = fetch_follow_up_page(&upstream, &url, &headers)
+                    .await
+                    .and_then(|(response_headers, page)| {
+                        Ok((page, page_links(&response_headers)?.next))
+                    });
+                let (mut page, next_url) = page.map_err(|(_, err)| {
+                    tracing::error!(%url, %err, "Failed to stream page");
+                    err
+                })?;
+                let (_, limits) = limits.apply_to_page(&mut page);
+                let mut chunk = Vec::new();
+                write_array_items(&mut chunk, &page, empty_so_far, format);
+                Ok(Some((
+                    Bytes::from(chunk),
+                    (next_url, limits, empty_so_far && page.is_empty()),
+                )))
+            }
+        },
+    );
+    let body = futures::stream::once(futures::future::ready(Ok::<_, String>(Bytes::from(
+        first_chunk,
+    ))))
+    .chain(rest)
+    .chain(futures::stream::once(futures::future::ready(Ok(
+        match format {
+            OutputFormat::Json => Bytes::from_static(b"]"),
+            OutputFormat::Ndjson | OutputFormat::Atom | OutputFormat::ICalendar => Bytes::new(),
+        },
+    ))));
+    let mut response_headers = format.headers();
+    insert_rate_limit_headers(&mut response_headers, &rate_limit);
+    (response_headers, StreamBody::new(body)).into_response()
+}
+
+/// Writes `items` as a fragment of the response body: for JSON, part of an array, preceded by a
+/// comma unless they're the first items.
+fn write_array_items(
+    buffer: &mut Vec<u8>,
+    items: &[serde_json::Value],
+    first: bool,
+    format: OutputFormat,
+) {
+    for (index, item) in items.iter().enumerate() {
+        if format == OutputFormat::Json && (index > 0 || !first) {
+            buffer.push(b',');
+        }
+        serde_json::to_writer(&mut *buffer, item).expect("Serializing JSON values can't fail");
+        if format == OutputFormat::Ndjson {
+            buffer.push(b'\n');
+        }
+    }
+}
+
+/// How JSON responses are rendered to clients, chosen with a `format=` query parameter.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Json,
+    /// One JSON value per line, with arrays split into their elements.
+    Ndjson,
+    /// An Atom feed with an entry per issue.
+    Atom,
+    /// An iCalendar feed with an event on the due date of each milestone.
+    ICalendar,
+}
+
+impl OutputFormat {
+    /// Removes any `format=json`, `format=ndjson`, `format=atom` or `format=ical` parameter from
+    /// `query`, so that it isn't forwarded to github or made part of the cache key.
+    fn take_from_query(query: &mut Option<String>) -> OutputFormat {
+        let Some(query_string) = query.as_deref() else {
+            return OutputFormat::Json;
+        };
+        let mut format = OutputFormat::Json;
+        let remaining: Vec<_> = query_string
+            .split('&')
+            .filter(|param| match *param {
+                "format=json" => {
+                    format = OutputFormat::Json;
+                    false
+                }
+                "format=ndjson" => {
+                    format = OutputFormat::Ndjson;
+                    false
+                }
+                "format=atom" => {
+                    format = OutputFormat::Atom;
+                    false
+                }
+                "format=ical" => {
+                    format = OutputFormat::ICalendar;
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        *query = (!remaining.is_empty()).then(|| remaining.join("&"));
+        format
+    }
+
+    fn headers(self) -> HeaderMap {
+        let mut headers = text_headers();
+        let content_type = match self {
+            OutputFormat::Json => return headers,
+            OutputFormat::Ndjson => "application/x-ndjson",
+            OutputFormat::Atom => "application/atom+xml; charset=utf-8",
+            OutputFormat::ICalendar => "text/calendar; charset=utf-8",
+        };
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            axum::http::HeaderValue::from_static(content_type),
+        );
+        headers
+    }
+
+    /// Converts a successful JSON response for `path` to this format. Error responses are left as
+    /// they are.
+    fn render(
+        self,
+        path: &str,
+        (status_code, mut headers, body): (StatusCode, HeaderMap, Bytes),
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        if !status_code.is_success() || status_code == StatusCode::NO_CONTENT {
+            return (status_code, headers, body);
+        }
+        let rendered = match self {
+            OutputFormat::Json => return (status_code, headers, body),
+            OutputFormat::Ndjson => Ok(render_ndjson(&body)),
+            OutputFormat::Atom => render_document(&body, |items| atom::render_feed(path, items)),
+            OutputFormat::ICalendar => {
+                render_document(&body, |items| ical::render_calendar(path, items))
+            }
+        };
+        let rendered = match rendered {
+            Ok(rendered) => rendered,
+            Err((status_code, err)) => return text_response(status_code, err),
+        };
+        let body = SerializedBody::from_bytes(rendered, false);
+        headers.extend(self.headers());
+        headers.insert(axum::http::header::ETAG, body.etag);
+        (status_code, headers, body.bytes)
+    }
+}
+
+fn render_ndjson(body: &Bytes) -> Bytes {
+    // Avoid parsing each element fully, as they're only being copied out.
+    match serde_json::from_slice::<Vec<&serde_json::value::RawValue>>(body) {
+        Ok(items) => {
+            let mut ndjson = Vec::with_capacity(body.len());
+            for item in items {
+                ndjson.extend_from_slice(item.get().as_bytes());
+                ndjson.push(b'\n');
+            }
+            Bytes::from(ndjson)
+        }
+        Err(_) => {
+            let mut ndjson = body.to_vec();
+            ndjson.push(b'\n');
+            Bytes::from(ndjson)
+        }
+    }
+}
+
+/// Renders the items of a JSON array as a document of another type, e.g. a feed. A single JSON
+/// value is treated as an array of one item.
+fn render_document(
+    body: &Bytes,
+    render: impl FnOnce(&[serde_json::Value]) -> Result<String, String>,
+) -> Result<Bytes, (StatusCode, String)> {
+    let items = match serde_json::from_slice(body) {
+        Ok(serde_json::Value::Array(items)) => items,
+        Ok(item) => vec![item],
+        Err(err) => {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to parse response from github: {}", err),
+            ))
+        }
+    };
+    render(&items)
+        .map(Bytes::from)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))
+}
+
+/// One page of a merged array response, for clients which don't want all of it at once.
+#[derive(Clone, Copy)]
+struct PageSlice {
+    /// 1-based, like github's.
+    page: usize,
+    per_page: usize,
+}
+
+impl PageSlice {
+    /// Removes any `proxy_page=` and `proxy_per_page=` parameters from `query`, returning the slice
+    /// they ask for if there were any. These are distinct from `page=` and `per_page=`, which are
+    /// passed on to github.
+    fn take_from_query(
+        query: &mut Option<String>,
+    ) -> Result<Option<PageSlice>, (StatusCode, String)> {
+        let page = take_query_param(query, "proxy_page");
+        let per_page = take_query_param(query, "proxy_per_page");
+        if page.is_none() && per_page.is_none() {
+            return Ok(None);
+        }
+        let parse = |name: &str, value: Option<String>, default: usize| match value {
+            None => Ok(default),
+            Some(value) => match value.parse::<usize>() {
+                Ok(0) => Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("{} must be at least 1", name),
+                )),
+                Ok(value) => Ok(value),
+                Err(err) => Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to parse {} from {:?}: {}", name, value, err),
+                )),
+            },
+        };
+        Ok(Some(PageSlice {
+            page: parse("proxy_page", page, 1)?,
+            // github's default.
+            per_page: parse("proxy_per_page", per_page, 30)?,
+        }))
+    }
+
+    /// Cuts this page out of a successful array response, adding headers saying how much there is
+    /// in total. Other responses are left as they are.
+    fn apply(
+        self,
+        (status_code, mut headers, body): (StatusCode, HeaderMap, Bytes),
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        if !status_code.is_success() {
+            return (status_code, headers, body);
+        }
+        let Ok(items) = serde_json::from_slice::<Vec<&serde_json::value::RawValue>>(&body) else {
+            return (status_code, headers, body);
+        };
+        let total_pages = items.len().div_ceil(self.per_page);
+        let start = (self.page - 1).saturating_mul(self.per_page);
+        let slice = items
+            .get(start..)
+            .unwrap_or_default()
+            .iter()
+            .take(self.per_page);
+        let mut sliced = b"[".to_vec();
+        for (index, item) in slice.enumerate() {
+            if index > 0 {
+                sliced.push(b',');
+            }
+            sliced.extend_from_slice(item.get().as_bytes());
+        }
+        sliced.push(b']');
+        let sliced = SerializedBody::from_bytes(Bytes::from(sliced), false);
+        headers.insert(axum::http::header::ETAG, sliced.etag);
+        headers.insert("x-total-count", items.len().into());
+        headers.insert("x-total-pages", total_pages.into());
+        headers.insert(
+            axum::http::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            "x-total-count, x-total-pages".parse().unwrap(),
+        );
+        (status_code, headers, sliced.bytes)
+    }
+}
+
+async fn write_handler(
+    State(state): State<AppState>,
+    method: Method,
+    Path(path): Path<String>,
+    RawQuery(query): RawQuery,
+    mut headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, HeaderMap, Bytes) {
+    apply_default_auth_header(&state, &path, &mut headers);
+    let url = RequestableUrl::GitHubApi {
+        base_url: state.github_api_base_url.clone(),
+        path: path.clone(),
+        query,
+    }
+    .into_string();
+    let builder = forward_request_headers(state.upstream.client.request(method, &url), &headers);
+    let _permit = match state.upstream.acquire().await {
+        Ok(permit) => permit,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let request = match builder.body(body).build() {
+        Ok(request) => request,
+        Err(err) => {
+            return text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to build request to github: {:?}", err),
+            )
+        }
+    };
+    let started = Instant::now();
+    let response = execute(&state.upstream, request).await;
+    state
+        .upstream
+        .metrics
+        .observe_upstream(started, response.as_ref().ok());
+    let response = match response {
+        Ok(response) => response,
+        Err((status_code, err)) => return text_response(status_code, err),
+    };
+    let status = StatusCode::from_u16(response.status().as_u16())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    if status.is_success() && state.invalidate_cache_on_write {
+        invalidate_related_paths(&state, &path).await;
+    }
+    let response_headers = end_to_end_headers(response.headers());
+    match response.bytes().await {
+        Ok(body) => (status, response_headers, body),
+        Err(err) => text_response(
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to read response body: {}", err),
+        ),
+    }
+}
+
+/// The headers of github's response which are meant for the client, rather than hop-by-hop.
+fn end_to_end_headers(response_headers: &reqwest::header::HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in response_headers {
+        match name.as_str() {
+            "connection" | "keep-alive" | "transfer-encoding" | "upgrade" | "trailer" => {
+                // These describe github's connection to us, not the response.
+            }
+            "content-length" | "content-encoding" => {
+                // reqwest has decompressed the body, if github compressed it.
+            }
+            _ => {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+    headers
+}
+
+/// Evicts cached entries for `path`, for any collection containing it, and for anything nested
+/// under it, e.g. a write to `repos/a/b/issues/1` evicts `repos/a/b/issues` and
+/// `repos/a/b/issues/1/comments`.
+async fn invalidate_related_paths(state: &AppState, path: &str) {
+    let path = path.trim_end_matches('/');
+    let related = |other: &str| {
+        let other = other.trim_end_matches('/');
+        let (shorter, longer) = if other.len() <= path.len() {
+            (other, path)
+        } else {
+            (path, other)
+        };
+        longer
+            .strip_prefix(shorter)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    for key in state.cache.keys().await {
+        if related(&key.path) {
+            state.cache.remove(&key).await;
+        }
+    }
+}
+
+/// Evicts cached entries for `path` (every one, or just the one for the request's query string),
+/// so that the next request fetches them afresh.
+///
+/// Entries are shared between TTLs, so the max age in the route is ignored.
+async fn purge_handler(
+    State(state): State<AppState>,
+    Path((_max_age, path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    let path = path.trim_start_matches('/');
+    let mut purged = 0;
+    for key in state.cache.keys().await {
+        if key.path.trim_start_matches('/') == path && (query.is_none() || key.query == query) {
+            state.cache.remove(&key).await;
+            state.reset_hits(&key);
+            purged += 1;
+        }
+    }
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({ "purged": purged }).to_string(),
+    )
+}
+
+#[derive(Deserialize)]
+struct PurgeRepoParams {
+    repo: String,
+}
+
+/// Evicts every cached entry for a repository, e.g. after a burst of changes to it.
+async fn purge_repo_handler(
+    State(state): State<AppState>,
+    Query(params): Query<PurgeRepoParams>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    let purged = purge_repo(&state, &params.repo).await;
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({ "purged": purged }).to_string(),
+    )
+}
+
+/// Evicts every cached entry for `repo` (`owner/repo`), returning how many there were.
+async fn purge_repo(state: &AppState, repo: &str) -> usize {
+    let keys = state
+        .cache
+        .keys_tagged(&repo.trim_matches('/').to_lowercase())
+        .await;
+    for key in &keys {
+        state.cache.remove(key).await;
+        state.reset_hits(key);
+    }
+    keys.len()
+}
+
+#[derive(Deserialize)]
+struct WebhookPayload {
+    repository: Option<WebhookRepository>,
+}
+
+#[derive(Deserialize)]
+struct WebhookRepository {
+    full_name: String,
+}
+
+/// Receives github webhook deliveries, purging cached entries for whichever repository each event
+/// is about, so that changes show up immediately however long entries are cached for.
+async fn webhook_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let settings = state.settings();
+    let Some(secret) = &settings.webhook_secret else {
+        return (
+            StatusCode::NOT_FOUND,
+            HeaderMap::new(),
+            "Webhooks are not configured".to_owned(),
+        );
+    };
+    let signature = headers
+        .get("x-hub-signature-256")
+        .and_then(|signature| signature.to_str().ok())
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .and_then(|signature| hex::decode(signature).ok());
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(&body);
+    let verified = match signature {
+        Some(signature) => mac.verify_slice(&signature).is_ok(),
+        None => false,
+    };
+    if !verified {
+        return (
+            StatusCode::UNAUTHORIZED,
+            HeaderMap::new(),
+            "Missing or incorrect webhook signature".to_owned(),
+        );
+    }
+    if headers
+        .get("x-github-event")
+        .is_some_and(|event| event == "ping")
+    {
+        return (StatusCode::OK, HeaderMap::new(), "pong".to_owned());
+    }
+    let payload: WebhookPayload = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                HeaderMap::new(),
+                format!("Failed to parse webhook payload: {}", err),
+            )
+        }
+    };
+    let purged = match payload.repository {
+        Some(repository) => purge_repo(&state, &repository.full_name).await,
+        None => 0,
+    };
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({ "purged": purged }).to_string(),
+    )
+}
+
+async fn metrics_handler(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        prometheus::TEXT_FORMAT.parse().unwrap(),
+    );
+    (StatusCode::OK, headers, state.metrics.render())
+}
+
+/// Sets CORS headers on every response, according to the configured policy, and answers preflight
+/// requests before they reach any handler (or the authentication they'd fail, having no
+/// credentials).
+async fn apply_cors<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let origin = request.headers().get(axum::http::header::ORIGIN).cloned();
+    if request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(axum::http::header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let headers = state.settings().cors.preflight(origin.as_ref());
+        return (StatusCode::NO_CONTENT, headers).into_response();
+    }
+    let mut response = next.run(request).await;
+    state
+        .settings()
+        .cors
+        .apply(origin.as_ref(), response.headers_mut());
+    response
+}
+
+/// Runs each request in a span, continuing the trace from any incoming `traceparent` header, and
+/// logs how it was handled.
+async fn trace_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    let span = telemetry::request_span(&request);
+    let started = Instant::now();
+    let correlation_id = correlation_id(request.headers());
+    let (mut response, outcome) =
+        telemetry::collect_request_outcome(correlation_id.clone(), next.run(request))
+            .instrument(span.clone())
+            .await;
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            cache = outcome.cache,
+            upstream_pages = outcome.upstream_pages,
+            duration_ms = started.elapsed().as_millis() as u64,
+            correlation_id,
+            "Handled request"
+        )
+    });
+    if let Ok(correlation_id) = correlation_id.parse() {
+        let headers = response.headers_mut();
+        headers.insert(CORRELATION_ID_HEADER, correlation_id);
+        headers.append(
+            axum::http::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            axum::http::HeaderValue::from_static(CORRELATION_ID_HEADER),
+        );
+    }
+    response
+}
+
+/// Identifies each request in logs and error responses, and is passed back to the client.
+const CORRELATION_ID_HEADER: &str = "x-request-id";
+
+/// The client's `X-Request-Id`, so that its requests can be followed through the proxy's logs, or
+/// else a new random one.
+fn correlation_id(request_headers: &HeaderMap) -> String {
+    let provided = request_headers
+        .get(CORRELATION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= 128
+                && value
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:".contains(&byte))
+        });
+    match provided {
+        Some(provided) => provided.to_owned(),
+        None => format!("{:032x}", rand::thread_rng().gen::<u128>()),
+    }
+}
+
+/// Counts requests by the route they matched and the status they were answered with.
+async fn record_request_metrics<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| route.as_str().to_owned())
+        .unwrap_or_default();
+    let mut disconnect = RecordDisconnect {
+        metrics: state.metrics.clone(),
+        route: route.clone(),
+        answered: false,
+    };
+    let response = next.run(request).await;
+    disconnect.answered = true;
+    state
+        .metrics
+        .requests
+        .with_label_values(&[&route, response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Counts a request as a 499 (as nginx does) if it's dropped before it's answered, which happens
+/// when the client disconnects. Any fetches from github on its behalf which wouldn't be cached are
+/// dropped along with it.
+struct RecordDisconnect {
+    metrics: Arc<Metrics>,
+    route: String,
+    answered: bool,
+}
+
+impl Drop for RecordDisconnect {
+    fn drop(&mut self) {
+        if self.answered {
+            return;
+        }
+        tracing::debug!(
+            route = self.route,
+            "Client disconnected before it was answered"
+        );
+        self.metrics
+            .requests
+            .with_label_values(&[&self.route, "499"])
+            .inc();
+    }
+}
+
+/// Once API keys are configured, only lets through requests with a known `X-Proxy-Key` for paths it
+/// allows, authenticating them to github with the key's credentials.
+async fn authenticate_api_key<B>(
+    State(state): State<AppState>,
+    params: Option<Path<HashMap<String, String>>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let settings = state.settings();
+    if settings.api_keys.is_empty() {
+        return next.run(request).await;
+    }
+    let presented = request
+        .headers_mut()
+        .remove("x-proxy-key")
+        .map(|key| Sha256::digest(key.as_bytes()));
+    let Some(api_key) = presented.and_then(|presented| {
+        settings
+            .api_keys
+            .iter()
+            .find(|api_key| Sha256::digest(api_key.key.as_bytes()) == presented)
+    }) else {
+        return text_response(StatusCode::UNAUTHORIZED, "Missing or unknown X-Proxy-Key")
+            .into_response();
+    };
+    if let Some(path) = requested_paths(&params, &request)
+        .into_iter()
+        .find(|path| !api_key.allows(path))
+    {
+        return text_response(
+            StatusCode::FORBIDDEN,
+            format!("This X-Proxy-Key may not request {}", path),
+        )
+        .into_response();
+    }
+    if let Some(auth_header) = &api_key.auth_header {
+        request
+            .headers_mut()
+            .insert(axum::http::header::AUTHORIZATION, auth_header.clone());
+    }
+    request.extensions_mut().insert(api_key.clone());
+    next.run(request).await
+}
+
+/// The paths of the github API a proxied route was asked for: its `path` parameter, each
+/// repository's issues for aggregated routes, or else `graphql`. Batches' paths are in their
+/// bodies, so they're checked one by one as they're fetched instead.
+fn requested_paths<B>(
+    params: &Option<Path<HashMap<String, String>>>,
+    request: &Request<B>,
+) -> Vec<String> {
+    if let Some(path) = params.as_ref().and_then(|Path(params)| params.get("path")) {
+        return vec![path.clone()];
+    }
+    if request.uri().path() == "/batch" {
+        return Vec::new();
+    }
+    if request.uri().path().ends_with("/aggregate/issues") {
+        let mut query = request.uri().query().map(str::to_owned);
+        // Invalid lists of repositories are rejected by the handler.
+        let repos = take_aggregated_repos(&mut query).unwrap_or_default();
+        return repos
+            .iter()
+            .map(|repo| format!("repos/{}/issues", repo))
+            .collect();
+    }
+    vec!["graphql".to_owned()]
+}
+
+/// Rejects requests for paths outside of the allowed paths, if any are configured.
+async fn check_allowed_path<B>(
+    State(state): State<AppState>,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let settings = state.settings();
+    let disallowed = requested_paths(&params, &request)
+        .into_iter()
+        .find(|path| !settings.serves(path));
+    if let Some(path) = disallowed {
+        return text_response(
+            StatusCode::FORBIDDEN,
+            format!("This proxy doesn't serve {}", path),
+        )
+        .into_response();
+    }
+    next.run(request).await
+}
+
+/// Streams responses in github's media types which aren't JSON, e.g. diffs, files' raw contents or
+/// release assets, straight through to the client, as they can't be merged or cached like JSON
+/// responses. Range requests are passed on, so that large downloads can be resumed.
+///
+/// Repository archives are streamed the same way whatever the client accepts, as they're only ever
+/// archives, and can be too large to hold in memory.
+async fn pass_through_raw_media_types<B>(
+    State(state): State<AppState>,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = params.as_ref().and_then(|Path(params)| params.get("path"));
+    let (Some(path), &Method::GET) = (path, request.method()) else {
+        return next.run(request).await;
+    };
+    if !is_archive(path) && !wants_raw_media_type(request.headers()) {
+        return next.run(request).await;
+    }
+    let mut headers = request.headers().clone();
+    apply_default_auth_header(&state, path, &mut headers);
+    let url = RequestableUrl::GitHubApi {
+        base_url: state.github_api_base_url.clone(),
+        path: path.clone(),
+        query: request.uri().query().map(str::to_owned),
+    }
+    .into_string();
+    let builder = forward_request_headers(state.upstream.client.get(&url), &headers);
+    let (response, permit) = match send_with_retries(&state.upstream, builder).await {
+        Ok(response) => response,
+        Err((status_code, err)) => return text_response(status_code, err).into_response(),
+    };
+    let mut response_headers = HeaderMap::new();
+    for name in [
+        axum::http::header::CONTENT_TYPE,
+        axum::http::header::CONTENT_LENGTH,
+        axum::http::header::CONTENT_DISPOSITION,
+        axum::http::header::CONTENT_RANGE,
+        axum::http::header::ACCEPT_RANGES,
+        axum::http::header::ETAG,
+        axum::http::header::LAST_MODIFIED,
+        axum::http::header::LOCATION,
+    ] {
+        if let Some(value) = response.headers().get(&name) {
+            response_headers.insert(name, value.clone());
+        }
+    }
+    insert_rate_limit_headers(
+        &mut response_headers,
+        &rate_limit_headers(response.headers()),
+    );
+    let status_code =
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let timeout = state.upstream.timeout;
+    let body = futures::stream::try_unfold(
+        (response, permit),
+        move |(mut response, permit)| async move {
+            let chunk = tokio::time::timeout(timeout, response.chunk())
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("github sent nothing more of the response for {:?}", timeout),
+                    )
+                })?
+                .map_err(std::io::Error::other)?;
+            Ok::<_, std::io::Error>(chunk.map(|chunk| (chunk, (response, permit))))
+        },
+    );
+    (status_code, response_headers, StreamBody::new(body)).into_response()
+}
+
+/// Whether `path` is a repository's tarball or zipball, e.g. `repos/owner/repo/tarball/main`.
+fn is_archive(path: &str) -> bool {
+    let segments: Vec<_> = path.trim_start_matches('/').split('/').collect();
+    matches!(segments[..], ["repos", _, _, "tarball" | "zipball", ..])
+}
+
+/// Whether the client asked for one of github's media types which aren't JSON, e.g.
+/// `application/vnd.github.raw` or `application/vnd.github.v3.diff`, or for the binary contents of
+/// a release asset with `application/octet-stream`.
+fn wants_raw_media_type(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(axum::http::header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .any(|media_type| {
+            let media_type = media_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            if media_type == "application/octet-stream" {
+                return true;
+            }
+            let Some(rest) = media_type.strip_prefix("application/vnd.github") else {
+                return false;
+            };
+            // e.g. `.raw+json` wraps the raw contents in JSON.
+            !rest.ends_with("json")
+                && matches!(
+                    rest.rsplit('.').next(),
+                    Some("raw" | "diff" | "patch" | "sha" | "html")
+                )
+        })
+}
+
+/// The IP address of the client which made a request, having followed any trusted proxies'
+/// `X-Forwarded-For` headers back to it.
+#[derive(Clone, Copy)]
+struct ClientIp(Option<std::net::IpAddr>);
+
+/// Works out which IP address a request came from, and rejects it unless that's in one of the
+/// allowed networks, if any are configured.
+async fn check_client_network<B>(
+    State(state): State<AppState>,
+    ConnectInfo(RemoteIp(remote_ip)): ConnectInfo<RemoteIp>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let settings = state.settings();
+    let client_ip =
+        client_network::client_ip(remote_ip, request.headers(), &settings.trusted_proxies);
+    if !settings.allowed_networks.is_empty()
+        && !client_ip.is_some_and(|ip| {
+            settings
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(ip))
+        })
+    {
+        tracing::info!(
+            ?client_ip,
+            "Rejected request from outside the allowed networks"
+        );
+        return text_response(
+            StatusCode::FORBIDDEN,
+            "Requests aren't allowed from your network",
+        )
+        .into_response();
+    }
+    request.extensions_mut().insert(ClientIp(client_ip));
+    next.run(request).await
+}
+
+/// Limits how quickly each client (identified by its API key, or else its IP address) may make
+/// requests, if configured.
+async fn limit_client_rate<B>(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let settings = state.settings();
+    let (client, limit) = match request.extensions().get::<Arc<ApiKey>>() {
+        Some(api_key) => (
+            format!("key:{}", api_key.key),
+            api_key.rate_limit.or(settings.client_rate_limit),
+        ),
+        None => match client_ip {
+            Some(remote_ip) => (format!("ip:{}", remote_ip), settings.client_rate_limit),
+            // Clients of a Unix domain socket can't be told apart.
+            None => return next.run(request).await,
+        },
+    };
+    let Some(limit) = limit else {
+        return next.run(request).await;
+    };
+    if let Err(wait) = state.client_rate_limiter.check(&client, limit) {
+        let retry_after = (wait.as_secs_f64().ceil() as u64).max(1);
+        let (status_code, mut headers, body) = text_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("Too many requests, retry in {} seconds", retry_after),
+        );
+        headers.insert(axum::http::header::RETRY_AFTER, retry_after.into());
+        return (status_code, headers, body).into_response();
+    }
+    next.run(request).await
+}
+
+async fn openapi_handler() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(
+            axum::http::header::CONTENT_TYPE,
+            axum::http::HeaderValue::from_static("application/json"),
+        )],
+        openapi::document().to_string(),
+    )
+}
+
+async fn healthz_handler() -> impl IntoResponse {
+    text_response(StatusCode::OK, "ok")
+}
+
+async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    if !state.readyz_check_upstream {
+        return text_response(StatusCode::OK, "ok");
+    }
+    // Checking the rate limit doesn't count against it.
+    let url = state.github_api_base_url.join("rate_limit").unwrap();
+    let mut headers = HeaderMap::new();
+    apply_default_auth_header(&state, "rate_limit", &mut headers);
+    let started = Instant::now();
+    let response = forward_request_headers(state.upstream.client.get(url.clone()), &headers)
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await;
+    state
+        .upstream
+        .metrics
+        .observe_upstream(started, response.as_ref().ok());
+    match response {
+        Ok(response) if response.status().is_success() => text_response(StatusCode::OK, "ok"),
+        Ok(response) if response.status() == reqwest::StatusCode::UNAUTHORIZED => text_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "github rejected the default auth header",
+        ),
+        Ok(response) => text_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("github responded with {}", response.status()),
+        ),
+        Err(err) => text_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Failed to reach github: {}", err),
+        ),
+    }
+}
+
+/// Checks that github accepts each default auth header before serving anything, so that a revoked
+/// or mistyped token fails at startup rather than with a 401 on every request, logging whose each
+/// is, its scopes and how much of its rate limit is left.
+///
+/// If github can't be reached, that's only logged, as it may well be reachable by the time the
+/// first request arrives.
+async fn check_default_auth_headers(state: &AppState) {
+    let settings = state.settings();
+    let mut auth_headers: Vec<&axum::http::HeaderValue> = Vec::new();
+    for auth_header in settings.default_auth_headers.iter().chain(
+        settings
+            .routes
+            .iter()
+            .filter_map(|rule| rule.default_auth_header.as_ref()),
+    ) {
+        if !auth_headers.contains(&auth_header) {
+            auth_headers.push(auth_header);
+        }
+    }
+    for (index, auth_header) in auth_headers.into_iter().enumerate() {
+        // Tokens are never logged, so they're told apart by the order they're configured in.
+        let number = index + 1;
+        let mut headers = HeaderMap::new();
+        headers.insert(axum::http::header::AUTHORIZATION, auth_header.clone());
+        let get = |path: &str| {
+            let url = state.github_api_base_url.join(path).unwrap();
+            forward_request_headers(state.upstream.client.get(url), &headers)
+                .timeout(Duration::from_secs(10))
+                .send()
+        };
+        // Checking the rate limit doesn't count against it.
+        let response = match get("rate_limit").await {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to reach github to check default auth header {}: {}",
+                    number,
+                    err
+                );
+                continue;
+            }
+        };
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            let scheme = auth_header
+                .to_str()
+                .unwrap_or_default()
+                .split(' ')
+                .next()
+                .unwrap_or_default()
+                .to_lowercase();
+            let hint = if ["token", "bearer", "basic"].contains(&scheme.as_str()) {
+                "it may have been revoked or have expired"
+            } else {
+                "it should look like `token <token>` or `Bearer <token>`"
+            };
+            panic!("github rejected default auth header {}: {}", number, hint);
+        }
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let scopes = header("x-oauth-scopes");
+        let remaining = header("x-ratelimit-remaining");
+        let limit = header("x-ratelimit-limit");
+        // Installation tokens can't read /user, so may well have no login.
+        let login = match get("user").await {
+            Ok(response) if response.status().is_success() => response
+                .json::<serde_json::Value>()
+                .await
+                .ok()
+                .and_then(|user| user["login"].as_str().map(str::to_owned)),
+            _ => None,
+        };
+        tracing::info!(
+            login,
+            scopes,
+            remaining,
+            limit,
+            "Checked default auth header {}",
+            number
+        );
+    }
+}
+
+async fn cache_stats_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    let stats = state.cache.stats().await;
+    let cache_lookups = |result: &str| {
+        state
+            .metrics
+            .cache_lookups
+            .with_label_values(&[result])
+            .get()
+    };
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({
+            "entries": stats.entries,
+            "approximate_bytes": stats.approximate_bytes,
+            "oldest_entry_age_seconds": stats.oldest_age.map(|age| age.as_secs()),
+            "newest_entry_age_seconds": stats.newest_age.map(|age| age.as_secs()),
+            "hits": cache_lookups("hit"),
+            "stale_hits": cache_lookups("stale"),
+            "misses": cache_lookups("miss"),
+            "rate_limit_remaining": state.metrics.rate_limit_remaining(),
+        })
+        .to_string(),
+    )
+}
+
+/// How many entries `/admin/cache/entries` lists, as it reads each of them.
+const MAX_LISTED_ENTRIES: usize = 1000;
+
+/// Picks out cache entries for the admin endpoints, by path and optionally query string.
+#[derive(Deserialize)]
+struct EntryParams {
+    path: Option<String>,
+    /// An empty query only matches entries without one. If there's no query, entries with any
+    /// query match.
+    query: Option<String>,
+}
+
+impl EntryParams {
+    fn matches(&self, key: &CacheKey) -> bool {
+        let path_matches = self
+            .path
+            .as_deref()
+            .is_none_or(|path| key.path.trim_start_matches('/') == path.trim_start_matches('/'));
+        let query_matches = match self.query.as_deref() {
+            None => true,
+            Some("") => key.query.is_none(),
+            Some(query) => key.query.as_deref() == Some(query),
+        };
+        path_matches && query_matches
+    }
+}
+
+/// Lists what's cached, most recently fetched first.
+async fn cache_entries_handler(
+    State(state): State<AppState>,
+    Query(params): Query<EntryParams>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    let keys: Vec<_> = state
+        .cache
+        .keys()
+        .await
+        .into_iter()
+        .filter(|key| params.matches(key))
+        .collect();
+    let truncated = keys.len() > MAX_LISTED_ENTRIES;
+    let hits = state.hits.as_ref().map(|hits| hits.lock().unwrap().clone());
+    let mut entries = Vec::new();
+    for key in keys.into_iter().take(MAX_LISTED_ENTRIES) {
+        let Some(value) = state.cache.get(&key).await else {
+            continue;
+        };
+        let age = value.generated_at.elapsed();
+        entries.push((
+            age,
+            serde_json::json!({
+                "path": key.path,
+                "query": key.query,
+                "authenticated": key.authorization_hash.is_some(),
+                "status": value.body.status.as_u16(),
+                "bytes": value.body.bytes.len(),
+                "age_seconds": age.as_secs(),
+                "max_age_seconds": state.settings().fresh_for(&value, value.max_duration).as_secs(),
+                "hits": hits.as_ref().map(|hits| hits.get(&key).copied().unwrap_or(0)),
+            }),
+        ));
+    }
+    entries.sort_by_key(|(age, _)| *age);
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({
+            "entries": entries.into_iter().map(|(_, entry)| entry).collect::<Vec<_>>(),
+            "truncated": truncated,
+        })
+        .to_string(),
+    )
+}
+
+/// Evicts the matching cache entries.
+async fn purge_entries_handler(
+    State(state): State<AppState>,
+    Query(params): Query<EntryParams>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    if params.path.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            HeaderMap::new(),
+            "A path= parameter is required".to_owned(),
+        );
+    }
+    let mut purged = 0;
+    for key in state.cache.keys().await {
+        if params.matches(&key) {
+            state.cache.remove(&key).await;
+            state.reset_hits(&key);
+            purged += 1;
+        }
+    }
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({ "purged": purged }).to_string(),
+    )
+}
+
+/// Fetches the matching cache entries afresh (or revalidates them, if github says they're
+/// unchanged), so that they're fresh for their whole TTL. Entries fetched with a client's own token
+/// are left for its next request, as the token isn't cached.
+async fn refresh_entries_handler(
+    State(state): State<AppState>,
+    Query(params): Query<EntryParams>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    if params.path.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            HeaderMap::new(),
+            "A path= parameter is required".to_owned(),
+        );
+    }
+    let mut refreshes = Vec::new();
+    for key in state.cache.keys().await {
+        if !params.matches(&key) {
+            continue;
+        }
+        let Some(value) = state.cache.get(&key).await else {
+            continue;
+        };
+        let Some(refresher) = value.refresher.with_default_credentials(&state, &key) else {
+            continue;
+        };
+        refreshes.push(start_refresh(
+            &state,
+            key,
+            value.max_duration,
+            value.page_etags.clone(),
+            refresher,
+        ));
+    }
+    let refreshed = futures::future::join_all(refreshes)
+        .await
+        .iter()
+        .filter(|(status_code, _, _)| status_code.is_success())
+        .count();
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({ "refreshed": refreshed }).to_string(),
+    )
+}
+
+/// How many paths one `/admin/warm` request may fetch.
+const MAX_WARMED_PATHS: usize = 1000;
+
+/// A response for `/admin/warm` to fetch into the cache.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WarmTarget {
+    /// A GitHub API path, with any query string.
+    path: String,
+    /// How long the response stays fresh for, if not the route rules' TTL for the path.
+    ttl: Option<Ttl>,
+}
+
+/// Fetches each of a JSON array of `{"path": ..., "ttl": ...}` objects into the cache, so that
+/// operators can populate it ahead of time, e.g. right after a deploy. Responds with how many were
+/// warmed, and each path's status.
+///
+/// Paths are fetched a few at a time, as background requests, so warming never crowds out clients'
+/// requests to github.
+async fn warm_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    if let Err((status_code, err)) = check_admin_token(&state, &headers) {
+        return (status_code, HeaderMap::new(), err);
+    }
+    let targets: Vec<WarmTarget> = match serde_json::from_slice(&body) {
+        Ok(targets) => targets,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                HeaderMap::new(),
+                format!("Failed to parse the paths to warm: {}", err),
+            )
+        }
+    };
+    if targets.len() > MAX_WARMED_PATHS {
+        return (
+            StatusCode::BAD_REQUEST,
+            HeaderMap::new(),
+            format!("At most {} paths may be warmed at once", MAX_WARMED_PATHS),
+        );
+    }
+    let state = state.in_background();
+    let concurrency = state.upstream.page_fetch_concurrency;
+    let results: serde_json::Map<_, _> = futures::stream::iter(targets)
+        .map(|target| {
+            let state = state.clone();
+            async move {
+                let result = match warm_cache(&state, &target.path, target.ttl).await {
+                    Ok(status_code) => serde_json::json!({ "status": status_code.as_u16() }),
+                    Err((status_code, err)) => serde_json::json!({
+                        "status": status_code.as_u16(),
+                        "error": err,
+                    }),
+                };
+                (target.path, result)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let warmed = results
+        .values()
+        .filter(|result| result.get("error").is_none())
+        .count();
+    (
+        StatusCode::OK,
+        HeaderMap::new(),
+        serde_json::json!({ "warmed": warmed, "results": results }).to_string(),
+    )
+}
+
+/// Fetches `requested` (a path with any query string) into the cache, fresh for `ttl` or else the
+/// route rules' TTL for its path, where requests for it without an Authorization header will find
+/// it. Entries which are already cached are revalidated rather than fetched from scratch.
+async fn warm_cache(
+    state: &AppState,
+    requested: &str,
+    ttl: Option<Ttl>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let (path, query) = match requested.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (requested, None),
+    };
+    let path = path.trim_start_matches('/');
+    let settings = state.settings();
+    if !settings.serves(path) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("This proxy doesn't serve {}", path),
+        ));
+    }
+    let max_duration = match ttl.unwrap_or_else(|| settings.ttl(path)) {
+        Ttl::For(max_duration) if !settings.never_cache(path) => max_duration,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{} isn't cached, so can't be warmed", path),
+            ))
+        }
+    };
+    let (key, refresher) = rest_cache_request(state, path, query, HeaderMap::new())?;
+    let page_etags = match state.cache.get(&key).await {
+        Some(value) => value.page_etags.clone(),
+        None => None,
+    };
+    let (status_code, _, body) =
+        start_refresh(state, key, max_duration, page_etags, refresher).await;
+    if status_code.is_success() {
+        Ok(status_code)
+    } else {
+        Err((status_code, String::from_utf8_lossy(&body).into_owned()))
+    }
+}
+
+/// Runs the config file's `warm` jobs as they fall due, checking at the start of each minute (in
+/// the latest version of the config file) which are.
+async fn run_warm_jobs(state: AppState) {
+    loop {
+        let now = time::OffsetDateTime::now_utc();
+        let into_minute = Duration::new(now.second().into(), now.nanosecond());
+        let minute = now + (Duration::from_secs(60) - into_minute);
+        tokio::time::sleep(Duration::from_secs(60) - into_minute).await;
+        let settings = state.settings();
+        for job in settings
+            .warm_jobs
+            .iter()
+            .filter(|job| job.schedule.matches(minute))
+        {
+            let state = state.clone();
+            let job = job.clone();
+            tokio::spawn(async move {
+                if let Err((status_code, err)) = warm_cache(&state, &job.path, job.ttl).await {
+                    tracing::warn!(path = %job.path, %status_code, "Failed to warm cache: {}", err);
+                }
+            });
+        }
+    }
+}
+
+/// A page for operators to see what's cached and how the rate limit is holding up, and to purge or
+/// refresh entries. It reads everything from the admin endpoints, asking for the admin token if
+/// they need one.
+async fn admin_dashboard_handler() -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    headers.insert(
+        axum::http::header::CONTENT_SECURITY_POLICY,
+        axum::http::HeaderValue::from_static(
+            "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'",
+        ),
+    );
+    (StatusCode::OK, headers, include_str!("admin.html"))
+}
+
+/// Rejects requests to admin endpoints which don't carry `$ADMIN_TOKEN` as a bearer token, if one
+/// is configured.
+fn check_admin_token(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let settings = state.settings();
+    let Some(admin_token) = &settings.admin_token else {
+        return Ok(());
+    };
+    let provided = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+    // Compare digests, so that how long the comparison takes doesn't leak how much of the token
+    // matched.
+    if provided.is_some_and(|provided| Sha256::digest(provided) == Sha256::digest(admin_token)) {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::UNAUTHORIZED,
+            "Missing or incorrect admin token".to_owned(),
+        ))
+    }
+}
+
+fn serialize_for_response(response: &GitHubResponse) -> (StatusCode, HeaderMap, Bytes) {
+    match SerializedBody::new(response) {
+        Ok(body) => {
+            let mut response_parts = body.to_response();
+            insert_rate_limit_headers(&mut response_parts.1, &response.rate_limit);
+            response_parts
+        }
+        Err((status_code, err)) => text_response(status_code, err),
+    }
+}
+
+/// A response body which has already been serialized, so that it can be served repeatedly (e.g.
+/// from the cache) without re-serializing it each time.
+#[derive(Clone)]
+struct SerializedBody {
+    /// Successful (possibly with no content), unless this is a cached 404 or 410.
+    status: StatusCode,
+    bytes: Bytes,
+    etag: axum::http::header::HeaderValue,
+    /// Whether pagination limits stopped some of the response from being fetched.
+    truncated: bool,
+    /// github's `Link` header, for responses whose pages weren't merged.
+    link: Option<axum::http::header::HeaderValue>,
+}
+
+impl SerializedBody {
+    fn new(response: &GitHubResponse) -> Result<SerializedBody, (StatusCode, String)> {
+        if let OpaqueJson::Empty = response.values {
+            return Ok(SerializedBody {
+                status: StatusCode::NO_CONTENT,
+                ..SerializedBody::from_bytes(Bytes::new(), false)
+            });
+        }
+        match serde_json::to_vec(&response.values) {
+            Ok(bytes) => Ok(SerializedBody {
+                link: response.link.clone(),
+                ..SerializedBody::from_bytes(Bytes::from(bytes), response.truncated)
+            }),
+            Err(err) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to serialize response: {}", err),
+            )),
+        }
+    }
+
+    fn from_bytes(bytes: Bytes, truncated: bool) -> SerializedBody {
+        let etag = format!("\"{:x}\"", Sha256::digest(&bytes)).parse().unwrap();
+        SerializedBody {
+            status: StatusCode::OK,
+            bytes,
+            etag,
+            truncated,
+            link: None,
+        }
+    }
+
+    /// An error response from github, for negative caching.
+    fn error(status: StatusCode, message: String) -> SerializedBody {
+        SerializedBody {
+            status,
+            ..SerializedBody::from_bytes(Bytes::from(message), false)
+        }
+    }
+
+    fn to_response(&self) -> (StatusCode, HeaderMap, Bytes) {
+        let (status_code, mut headers, body) = text_response(self.status, self.bytes.clone());
+        if self.status.is_success() {
+            headers.insert(axum::http::header::ETAG, self.etag.clone());
+        }
+        if let Some(link) = &self.link {
+            headers.insert(axum::http::header::LINK, link.clone());
+        }
+        if self.truncated {
+            headers.insert(
+                axum::http::header::WARNING,
+                "199 - \"Response truncated by pagination limits\""
+                    .parse()
+                    .unwrap(),
+            );
+        }
+        (status_code, headers, body)
+    }
+}
+
+/// A response with the same headers axum would give a `String` body, for bodies which are `Bytes`.
+fn text_response(
+    status_code: StatusCode,
+    body: impl Into<Bytes>,
+) -> (StatusCode, HeaderMap, Bytes) {
+    let body = body.into();
+    if status_code.is_client_error() || status_code.is_server_error() {
+        return problem_response(status_code, &String::from_utf8_lossy(&body));
+    }
+    (status_code, text_headers(), body)
+}
+
+/// An `application/problem+json` (RFC 9457) response for an error, e.g.
+///
+/// ```json
+/// {
+///   "type": "not-found",
+///   "title": "Not Found",
+///   "status": 404,
+///   "detail": "Not Found",
+///   "documentation_url": "https://docs.github.com/rest",
+///   "upstream_status": 404,
+///   "upstream_request_id": "C0DE:1234:5678",
+///   "correlation_id": "3f2a..."
+/// }
+/// ```
+///
+/// If `detail` is a JSON object (such as github's own error responses), its `message` is the
+/// detail, and its other members are kept alongside.
+fn problem_response(status_code: StatusCode, detail: &str) -> (StatusCode, HeaderMap, Bytes) {
+    // Errors may quote github's responses, or URLs it redirected us to, which can carry secrets.
+    let detail = redact::redact(detail);
+    let mut problem = serde_json::Map::new();
+    problem.insert("type".to_owned(), error_type(status_code).into());
+    problem.insert(
+        "title".to_owned(),
+        status_code.canonical_reason().unwrap_or_default().into(),
+    );
+    problem.insert("status".to_owned(), status_code.as_u16().into());
+    match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&detail) {
+        Ok(mut members) => {
+            if let Some(message) = members.remove("message") {
+                problem.insert("detail".to_owned(), message);
+            }
+            for (name, value) in members {
+                problem.entry(name).or_insert(value);
+            }
+        }
+        Err(_) => {
+            problem.insert("detail".to_owned(), detail.into());
+        }
+    }
+    if let Some(context) = telemetry::error_context() {
+        if let Some(upstream_status) = context.upstream_status {
+            problem.insert("upstream_status".to_owned(), upstream_status.into());
+        }
+        if let Some(upstream_request_id) = context.upstream_request_id {
+            problem.insert("upstream_request_id".to_owned(), upstream_request_id.into());
+        }
+        problem.insert("correlation_id".to_owned(), context.correlation_id.into());
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static("application/problem+json"),
+    );
+    (
+        status_code,
+        headers,
+        Bytes::from(serde_json::Value::Object(problem).to_string()),
+    )
+}
+
+/// What kind of error a status means, for problem responses' `type`.
+fn error_type(status_code: StatusCode) -> &'static str {
+    match status_code {
+        StatusCode::BAD_REQUEST => "bad-request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not-found",
+        StatusCode::GONE => "gone",
+        StatusCode::UNPROCESSABLE_ENTITY => "unprocessable",
+        StatusCode::TOO_MANY_REQUESTS => "rate-limited",
+        StatusCode::BAD_GATEWAY => "bad-gateway",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        StatusCode::GATEWAY_TIMEOUT => "timeout",
+        status_code if status_code.is_client_error() => "client-error",
+        _ => "server-error",
+    }
+}
+
+fn text_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        "text/plain; charset=utf-8".parse().unwrap(),
+    );
+    headers
+}
+
+fn fetch_from_github(
+    upstream: Upstream,
+    url: RequestableUrl,
+    request_headers: HeaderMap,
+    limits: PaginationLimits,
+    single_page: bool,
+) -> BoxFuture<'static, FetchResult> {
+    let url = url.into_string();
+    let span = tracing::info_span!("fetch_from_github", %url, pages = tracing::field::Empty);
+    async move {
+        let builder = forward_request_headers(upstream.client.get(&url), &request_headers);
+        let (response_headers, mut values) = send_to_github(&upstream, builder)
+            .instrument(tracing::info_span!("fetch_page", %url))
+            .await?;
+        let mut pages_fetched = 1;
+        let mut page_etags = page_etag(&url, &response_headers).map(|etag| vec![etag]);
+        let mut rate_limit = rate_limit_headers(&response_headers);
+        let OpaqueJson::Array(array) = &mut values else {
+            return Ok(GitHubResponse {
+                values,
+                page_etags,
+                truncated: false,
+                link: None,
+                rate_limit,
+            });
+        };
+        let per_page = array.len();
+        let (mut truncated, mut limits) = limits.apply_to_page(array);
+        if single_page {
+            return Ok(GitHubResponse {
+                values,
+                page_etags,
+                truncated,
+                link: response_headers.get(axum::http::header::LINK).cloned(),
+                rate_limit,
+            });
+        }
+        let links = page_links(&response_headers)?;
+        let mut has_next_page = links.next.is_some();
+
+        // When the remaining pages are numbered, we know them all up front and can fetch them
+        // concurrently. Otherwise, each page's `next` link has to be followed in turn.
+        let numbered_pages = match (&links.next, &links.last, limits) {
+            (Some(next), Some(last), Some(limits)) => {
+                numbered_page_urls(next, last).map(|mut urls| {
+                    if let Some(max_pages) = limits.max_pages_needed(per_page) {
+                        urls.truncate(max_pages);
+                    }
+                    urls
+                })
+            }
+            _ => None,
+        };
+        let mut pages = match numbered_pages {
+            Some(urls) => futures::stream::iter(urls)
+                .map(|url| {
+                    let upstream = &upstream;
+                    let request_headers = &request_headers;
+                    async move {
+                        let (response_headers, page) =
+                            fetch_follow_up_page(upstream, &url, request_headers).await?;
+                        Ok::<_, (StatusCode, String)>((url, response_headers, page))
+                    }
+                })
+                .buffered(upstream.page_fetch_concurrency)
+                .boxed(),
+            None => futures::stream::try_unfold(links.next, |next_url| {
+                let upstream = &upstream;
+                let request_headers = &request_headers;
+                async move {
+                    let Some(url) = next_url else {
+                        return Ok(None);
+                    };
+                    let (response_headers, page) =
+                        fetch_follow_up_page(upstream, &url, request_headers).await?;
+                    let next_url = page_links(&response_headers)?.next;
+                    Ok(Some(((url, response_headers, page), next_url)))
+                }
+            })
+            .boxed(),
+        };
+        while let Some(page_limits) = limits {
+            let Some((url, response_headers, mut page)) = pages.try_next().await? else {
+                break;
+            };
+            has_next_page = page_links(&response_headers)?.next.is_some();
+            rate_limit = rate_limit_headers(&response_headers);
+            page_etags = page_etags.zip(page_etag(&url, &response_headers)).map(
+                |(mut page_etags, page_etag)| {
+                    page_etags.push(page_etag);
+                    page_etags
+                },
+            );
+            let (dropped_items, next_limits) = page_limits.apply_to_page(&mut page);
+            truncated |= dropped_items;
+            limits = next_limits;
+            array.extend(page);
+            pages_fetched += 1;
+        }
+        tracing::Span::current().record("pages", pages_fetched);
+        telemetry::record_upstream_pages(pages_fetched);
+        if limits.is_none() && has_next_page {
+            truncated = true;
+        }
+        Ok(GitHubResponse {
+            values,
+            page_etags,
+            truncated,
+            link: None,
+            rate_limit,
+        })
+    }
+    .instrument(span)
+    .boxed()
+}
+
+/// Fetches the issues of each of `repos` concurrently, merged and sorted as github would sort any
+/// one repository's. `limits` apply to each repository, and then to the merged issues.
+fn fetch_aggregate_issues(
+    upstream: Upstream,
+    base_url: Url,
+    repos: Vec<String>,
+    query: Option<String>,
+    request_headers: HeaderMap,
+    limits: PaginationLimits,
+) -> BoxFuture<'static, FetchResult> {
+    async move {
+        let responses: Vec<GitHubResponse> = futures::stream::iter(repos)
+            .map(|repo| {
+                let url = RequestableUrl::GitHubApi {
+                    base_url: base_url.clone(),
+                    path: format!("repos/{}/issues", repo),
+                    query: query.clone(),
+                };
+                fetch_from_github(
+                    upstream.clone(),
+                    url,
+                    request_headers.clone(),
+                    limits,
+                    false,
+                )
+                .map(move |result| {
+                    // A redirect of one repository can't be passed back for all of them.
+                    result.map_err(|err| {
+                        FetchError::from((
+                            err.status_code,
+                            format!("Failed to fetch issues for {}: {}", repo, err.message),
+                        ))
+                    })
+                })
+            })
+            .buffered(upstream.page_fetch_concurrency)
+            .try_collect()
+            .await?;
+        let mut issues = Vec::new();
+        let mut page_etags = Some(Vec::new());
+        let mut truncated = false;
+        let mut rate_limit = HeaderMap::new();
+        for response in responses {
+            let OpaqueJson::Array(values) = response.values else {
+                return Err(FetchError::from((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "github returned a non-array response for a repository's issues".to_owned(),
+                )));
+            };
+            issues.extend(values);
+            // Every repository's pages can be revalidated together.
+            page_etags =
+                page_etags
+                    .zip(response.page_etags)
+                    .map(|(mut page_etags, repo_page_etags)| {
+                        page_etags.extend(repo_page_etags);
+                        page_etags
+                    });
+            truncated |= response.truncated;
+            rate_limit = response.rate_limit;
+        }
+        // Sorting by any other field was taken out of the query, to be done once it's cached.
+        let mut github_query = query.clone();
+        match transform::Sort::take_from_query(&mut github_query) {
+            Ok(Some(sort)) => sort.apply(&mut issues),
+            _ => transform::NEWEST_FIRST.apply(&mut issues),
+        }
+        let (dropped_items, _) = limits.apply_to_page(&mut issues);
+        Ok(GitHubResponse {
+            values: OpaqueJson::Array(issues),
+            page_etags,
+            truncated: truncated || dropped_items,
+            link: None,
+            rate_limit,
+        })
+    }
+    .boxed()
+}
+
+/// Fetches the comments of each issue in `response`, embedding them in its `comments_data` field.
+async fn embed_comments(
+    upstream: Upstream,
+    base_url: Url,
+    request_headers: HeaderMap,
+    mut response: GitHubResponse,
+) -> FetchResult {
+    let OpaqueJson::Array(issues) = &mut response.values else {
+        return Ok(response);
+    };
+    // Issues without comments don't need a request to find that out.
+    let comments_paths: Vec<Option<String>> = issues
+        .iter()
+        .map(|issue| {
+            let path = issue["comments_url"]
+                .as_str()?
+                .strip_prefix(base_url.as_str())?;
+            (issue["comments"].as_u64() != Some(0)).then(|| path.to_owned())
+        })
+        .collect();
+    let comments: Vec<Option<GitHubResponse>> = futures::stream::iter(comments_paths)
+        .map(|path| {
+            let upstream = upstream.clone();
+            let base_url = base_url.clone();
+            let request_headers = request_headers.clone();
+            async move {
+                let Some(path) = path else {
+                    return Ok(None);
+                };
+                let url = RequestableUrl::GitHubApi {
+                    base_url,
+                    path,
+                    query: Some("per_page=100".to_owned()),
+                };
+                fetch_from_github(
+                    upstream,
+                    url,
+                    request_headers,
+                    PaginationLimits::default(),
+                    false,
+                )
+                .await
+                .map(Some)
+            }
+        })
+        .buffered(upstream.page_fetch_concurrency)
+        .try_collect()
+        .await?;
+    for (issue, comments) in issues.iter_mut().zip(comments) {
+        let Some(issue) = issue.as_object_mut() else {
+            continue;
+        };
+        let comments_data = match comments {
+            Some(comments) => {
+                // The entry is only still fresh while every issue's comments are.
+                response.page_etags = response.page_etags.zip(comments.page_etags).map(
+                    |(mut page_etags, comment_page_etags)| {
+                        page_etags.extend(comment_page_etags);
+                        page_etags
+                    },
+                );
+                response.truncated |= comments.truncated;
+                response.rate_limit = comments.rate_limit;
+                serde_json::to_value(comments.values).expect("Serializing JSON values can't fail")
+            }
+            None if issue.contains_key("comments_url") => serde_json::Value::Array(Vec::new()),
+            None => continue,
+        };
+        issue.insert("comments_data".to_owned(), comments_data);
+    }
+    Ok(response)
+}
+
+/// Fetches a page after the first of an array response.
+#[tracing::instrument(name = "fetch_page", skip(upstream, request_headers))]
+async fn fetch_follow_up_page(
+    upstream: &Upstream,
+    url: &str,
+    request_headers: &HeaderMap,
+) -> Result<(HeaderMap, Vec<serde_json::Value>), (StatusCode, String)> {
+    let builder = forward_request_headers(upstream.client.get(url), request_headers);
+    match send_to_github(upstream, builder).await {
+        Ok((response_headers, OpaqueJson::Array(page))) => Ok((response_headers, page)),
+        Ok((response_headers, OpaqueJson::Empty)) => Ok((response_headers, Vec::new())),
+        Ok((_, OpaqueJson::Value(_))) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!(
+                "Follow-up request to github for {} returned a non-array response",
+                url
+            ),
+        )),
+        Err(err) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!(
+                "Failed to make follow-up request to github: {} {}",
+                err.status_code, err.message
+            ),
+        )),
+    }
+}
+
+/// github's rate limit headers from a response, for the client to see how much of its quota is
+/// left.
+fn rate_limit_headers(response_headers: &HeaderMap) -> HeaderMap {
+    RATE_LIMIT_HEADERS
+        .iter()
+        .filter_map(|&name| {
+            let value = response_headers.get(name)?;
+            Some((axum::http::HeaderName::from_static(name), value.clone()))
+        })
+        .collect()
+}
+
+const RATE_LIMIT_HEADERS: [&str; 5] = [
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+    "x-ratelimit-used",
+    DEGRADED_HEADER,
+];
+
+/// Set on responses which github had to be asked for in a worse way than usual, e.g. `anonymous`
+/// when the default auth header's quota was used up.
+const DEGRADED_HEADER: &str = "x-proxy-degraded";
+
+/// Passes github's rate limit headers on to the client, including to pages on other origins.
+fn insert_rate_limit_headers(headers: &mut HeaderMap, rate_limit: &HeaderMap) {
+    if rate_limit.is_empty() {
+        return;
+    }
+    headers.extend(rate_limit.clone());
+    headers.append(
+        axum::http::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        axum::http::HeaderValue::from_static(
+            "x-ratelimit-limit, x-ratelimit-remaining, x-ratelimit-reset, x-ratelimit-used, x-proxy-degraded",
+        ),
+    );
+}
+
+fn page_etag(url: &str, response_headers: &HeaderMap) -> Option<PageEtag> {
+    response_headers
+        .get(axum::http::header::ETAG)
+        .map(|etag| PageEtag {
+            url: url.to_owned(),
+            etag: etag.clone(),
+        })
+}
+
+struct PageLinks {
+    next: Option<String>,
+    last: Option<String>,
+}
+
+/// Parses the URLs of the next and last pages of results from github's `Link` header.
+fn page_links(response_headers: &HeaderMap) -> Result<PageLinks, (StatusCode, String)> {
+    let Some(link) = response_headers.get("link") else {
+        return Ok(PageLinks {
+            next: None,
+            last: None,
+        });
+    };
+    let link_map = match link.to_str() {
+        Ok(link) => match parse_link_header::parse(link) {
+            Ok(link_map) => link_map,
+            Err(err) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to parse link map \"{}\": {}", link, err),
+                ))
+            }
+        },
+        Err(err) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to parse link header \"{:?}\": {}", link, err),
+            ));
+        }
+    };
+    let url = |rel: &str| {
+        link_map
+            .get(&Some(rel.to_owned()))
+            .map(|link| link.uri.to_string())
+    };
+    Ok(PageLinks {
+        next: url("next"),
+        last: url("last"),
+    })
+}
+
+/// If the `next` and `last` links differ only in their `page` parameter, lists the URL of every
+/// page from `next` to `last`.
+fn numbered_page_urls(next: &str, last: &str) -> Option<Vec<String>> {
+    let next = Url::parse(next).ok()?;
+    let last = Url::parse(last).ok()?;
+    let page_number = |url: &Url| {
+        url.query_pairs()
+            .find(|(name, _)| name == "page")
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+    };
+    let with_page = |page: usize| {
+        let pairs: Vec<(String, String)> = next
+            .query_pairs()
+            .map(|(name, value)| {
+                let value = if name == "page" {
+                    page.to_string()
+                } else {
+                    value.into_owned()
+                };
+                (name.into_owned(), value)
+            })
+            .collect();
+        let mut url = next.clone();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+        url
+    };
+    let last_page = page_number(&last)?;
+    if with_page(last_page) != last {
+        return None;
+    }
+    Some(
+        (page_number(&next)?..=last_page)
+            .map(|page| with_page(page).into())
+            .collect(),
+    )
+}
+
+/// The client for every request to github, with its connections configured by `args`, or else
+/// `config`.
+fn upstream_client(args: &ServeArgs, config: &Config) -> reqwest::Client {
+    let connect_timeout = args
+        .upstream_connect_timeout
+        .or(config.timeouts.upstream_connect)
+        .unwrap_or(Duration::from_secs(10));
+    // github rejects requests without a User-Agent, so send one for clients which don't.
+    let mut builder = reqwest::Client::builder()
+        .user_agent(args.default_user_agent.clone())
+        .connect_timeout(connect_timeout)
+        .default_headers(default_upstream_headers(args))
+        // Redirects are followed by send_with_retries, which decides where credentials may go.
+        .redirect(reqwest::redirect::Policy::none())
+        .pool_idle_timeout(
+            args.upstream_pool_idle_timeout
+                .or(config.upstream.pool_idle_timeout)
+                .unwrap_or(Duration::from_secs(90)),
+        )
+        .tcp_keepalive(
+            args.upstream_tcp_keepalive
+                .or(config.upstream.tcp_keepalive),
+        );
+    if let Some(max_idle) = args
+        .upstream_pool_max_idle_per_host
+        .or(config.upstream.pool_max_idle_per_host)
+    {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if !args
+        .upstream_http2
+        .or(config.upstream.http2)
+        .unwrap_or(true)
+    {
+        builder = builder.http1_only();
+    }
+    let proxy = args.upstream_proxy.clone().or_else(|| {
+        config.upstream.proxy.as_ref().map(|proxy| {
+            proxy
+                .parse()
+                .unwrap_or_else(|err| panic!("Failed to parse upstream.proxy: {}", err))
+        })
+    });
+    if let Some(proxy) = proxy {
+        let no_proxy = args
+            .upstream_no_proxy
+            .as_ref()
+            .or(config.upstream.no_proxy.as_ref())
+            .and_then(|no_proxy| reqwest::NoProxy::from_string(no_proxy));
+        let proxy = reqwest::Proxy::all(proxy.clone())
+            .unwrap_or_else(|err| panic!("Failed to use {} as a proxy: {}", proxy, err))
+            .no_proxy(no_proxy);
+        builder = builder.proxy(proxy);
+    }
+    builder.build().expect("Failed to build HTTP client")
+}
+
+/// Headers sent to github for requests which don't have their own, so that responses don't change
+/// when github changes its defaults.
+fn default_upstream_headers(args: &ServeArgs) -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(reqwest::header::ACCEPT, args.default_accept.clone());
+    if let Some(api_version) = &args.github_api_version {
+        headers.insert("x-github-api-version", api_version.clone());
+    }
+    headers
+}
+
+fn forward_request_headers(
+    mut builder: reqwest::RequestBuilder,
+    request_headers: &HeaderMap,
+) -> reqwest::RequestBuilder {
+    for (key, value) in request_headers.iter() {
+        match key.as_str() {
+            "host" => {
+                // reqwest sets the Host of each URL it requests, which may differ from the first
+                // if it follows a redirect, e.g. to where a release asset is stored.
+            }
+            "accept-encoding" => {
+                // reqwest asks github for gzip or brotli, and decompresses the response before we
+                // read it, but only if the request doesn't already say which encodings it accepts.
+            }
+            "content-length" | "transfer-encoding" | "connection" => {
+                // These describe the connection to us, not the request we make to github.
+            }
+            "if-none-match" | "if-modified-since" => {
+                // Conditional requests are answered by not_modified, against our own ETags.
+            }
+            key => {
+                builder = builder.header(key, value.clone());
+            }
+        }
+    }
+    builder
+}
+
+fn fetch_graphql(
+    upstream: Upstream,
+    url: &Url,
+    request_headers: HeaderMap,
+    body: Bytes,
+) -> impl Future<Output = FetchResult> {
+    let url = url.as_str();
+    let builder = forward_request_headers(upstream.client.post(url), &request_headers).body(body);
+    async move {
+        let (response_headers, values) = send_to_github(&upstream, builder).await?;
+        Ok(GitHubResponse {
+            values,
+            page_etags: None,
+            truncated: false,
+            link: None,
+            rate_limit: rate_limit_headers(&response_headers),
+        })
+    }
+}
+
+async fn send_to_github(
+    upstream: &Upstream,
+    builder: reqwest::RequestBuilder,
+) -> Result<(reqwest::header::HeaderMap, OpaqueJson), FetchError> {
+    let (response, _permit) = send_with_retries(upstream, builder).await?;
+    if response.status() == reqwest::StatusCode::ACCEPTED {
+        // Passed on rather than cached, so that the client tries again later.
+        return Err(FetchError::from((
+            StatusCode::ACCEPTED,
+            "github is still computing this response; try again shortly".to_owned(),
+        )));
+    }
+    if is_followable_redirect(response.status().as_u16()) {
+        // Only left unfollowed with --upstream-redirects=pass.
+        if let Some(location) = redirect_location(&response) {
+            return Err(FetchError {
+                status_code: StatusCode::from_u16(response.status().as_u16())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+                location: axum::http::HeaderValue::from_str(location.as_str()).ok(),
+                message: location.into(),
+            });
+        }
+    }
+    if !response.status().is_success() {
+        let status_code = StatusCode::from_u16(response.status().as_u16())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let err = match read_body(upstream, response).await {
+            Ok(body) => (status_code, body),
+            Err(err) => err,
+        };
+        return Err(err.into());
+    }
+    let response_headers = response.headers().clone();
+    let response_body = read_body(upstream, response).await?;
+    if response_body.trim().is_empty() {
+        return Ok((response_headers, OpaqueJson::Empty));
+    }
+    let values = serde_json::from_str(&response_body).map_err(|err| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read response \"{}\": {}", response_body, err),
+        )
+    })?;
+    Ok((response_headers, values))
+}
+
+/// Reads the body of a response from github, giving up if it takes longer than the upstream
+/// timeout.
+async fn read_body(
+    upstream: &Upstream,
+    response: reqwest::Response,
+) -> Result<String, (StatusCode, String)> {
+    let url = response.url().clone();
+    match tokio::time::timeout(upstream.timeout, response.text()).await {
+        Ok(Ok(body)) => Ok(body),
+        Ok(Err(err)) if err.is_timeout() => Err(upstream_timed_out(upstream, &url)),
+        Ok(Err(err)) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read response: {}", err),
+        )),
+        Err(_) => Err(upstream_timed_out(upstream, &url)),
+    }
+}
+
+fn upstream_timed_out(upstream: &Upstream, url: impl std::fmt::Display) -> (StatusCode, String) {
+    (
+        StatusCode::GATEWAY_TIMEOUT,
+        format!(
+            "Timed out after {:?} waiting for github to respond to {}",
+            upstream.timeout, url
+        ),
+    )
+}
+
+/// Sends a request to github once, within the upstream timeout, recording or replaying it if
+/// we're doing either.
+async fn execute(
+    upstream: &Upstream,
+    request: reqwest::Request,
+) -> Result<reqwest::Response, (StatusCode, String)> {
+    let url = request.url().clone();
+    let recorded = upstream
+        .vcr
+        .as_ref()
+        .map(|vcr| (vcr, RecordedRequest::new(&request)));
+    if let Some((vcr, recorded)) = &recorded {
+        if vcr.replaying() {
+            return vcr.play(recorded).await;
+        }
+    }
+    let response =
+        match tokio::time::timeout(upstream.timeout, upstream.client.execute(request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(err)) if err.is_timeout() => return Err(upstream_timed_out(upstream, url)),
+            Ok(Err(err)) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to make request to github: {:?}", err),
+                ))
+            }
+            Err(_) => return Err(upstream_timed_out(upstream, url)),
+        };
+    let Some((vcr, recorded)) = recorded else {
+        return Ok(response);
+    };
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = match tokio::time::timeout(upstream.timeout, response.bytes()).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(err)) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read response: {}", err),
+            ))
+        }
+        Err(_) => return Err(upstream_timed_out(upstream, url)),
+    };
+    Ok(vcr.save(&recorded, status, &headers, body.to_vec()).await)
+}
+
+/// Sends a request to github, and if it's safe to repeat and github hasn't started responding
+/// within the hedging delay, a second copy of it too, returning whichever response comes first.
+/// If one copy fails outright, the other's result is used instead.
+async fn execute_hedged(
+    upstream: &Upstream,
+    request: reqwest::Request,
+    repeatable: bool,
+) -> Result<reqwest::Response, (StatusCode, String)> {
+    let Some(hedge_after) = upstream.hedge_after.filter(|_| repeatable) else {
+        return execute(upstream, request).await;
+    };
+    let hedged = request
+        .try_clone()
+        .expect("Request bodies are always buffered, so can be cloned");
+    let first = execute(upstream, request);
+    futures::pin_mut!(first);
+    if let Ok(result) = tokio::time::timeout(hedge_after, &mut first).await {
+        return result;
+    }
+    tracing::debug!(url = %hedged.url(), ?hedge_after, "github is slow to respond, hedging");
+    upstream.metrics.hedged_requests.inc();
+    let second = async {
+        // The second copy takes its own turn, if requests to github are limited.
+        let _permit = upstream.acquire().await?;
+        execute(upstream, hedged).await
+    };
+    futures::pin_mut!(second);
+    match futures::future::select(first, second).await {
+        futures::future::Either::Left((Ok(response), _))
+        | futures::future::Either::Right((Ok(response), _)) => Ok(response),
+        futures::future::Either::Left((Err(_), second)) => second.await,
+        futures::future::Either::Right((Err(_), first)) => first.await,
+    }
+}
+
+/// Sends a request to github, retrying transient failures and rate limits (within the wait
+/// budget), and reauthenticating once if a GitHub App's token was rejected.
+///
+/// The response comes with its turn under the upstream concurrency limit, if there is one, which
+/// should be held until its body has been read.
+async fn send_with_retries(
+    upstream: &Upstream,
+    builder: reqwest::RequestBuilder,
+) -> Result<(reqwest::Response, Option<UpstreamPermit>), (StatusCode, String)> {
+    let mut request = builder.build().map_err(|err| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to build request to github: {:?}", err),
+        )
+    })?;
+    // Only retry requests which are safe to repeat.
+    let retryable = request.method() == reqwest::Method::GET;
+    let mut attempt = 0;
+    let mut rate_limit_wait_budget = upstream.rate_limit_wait_budget;
+    let mut rate_limit_retries = 0;
+    let mut reauthenticated = false;
+    let mut accepted_polls = 0;
+    let mut redirects = 0;
+    // Set once the default auth header's quota has been used up and we've fallen back to
+    // anonymous requests, to the error to give if that fails too.
+    let mut anonymous_fallback: Option<(StatusCode, String)> = None;
+    let original_url = request.url().clone();
+    let original_auth = request
+        .headers()
+        .get(reqwest::header::AUTHORIZATION)
+        .cloned();
+    let (response, permit) = loop {
+        attempt += 1;
+        let mut attempt_request = request
+            .try_clone()
+            .expect("Request bodies are always buffered, so can be cloned");
+        let token = upstream.token_pool.rotate(attempt_request.headers_mut());
+        let circuit = match &upstream.circuit_breaker {
+            Some(circuit_breaker) => Some(circuit_breaker.attempt()?),
+            None => None,
+        };
+        let permit = upstream.acquire().await?;
+        let started = Instant::now();
+        let result = execute_hedged(upstream, attempt_request, retryable).await;
+        if let Some(circuit) = circuit {
+            circuit.record(match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            });
+        }
+        upstream
+            .metrics
+            .observe_upstream(started, result.as_ref().ok());
+        if let (Some(token), Ok(response)) = (&token, &result) {
+            upstream.token_pool.observe(token, response);
+        }
+        let transient = match &result {
+            Ok(response) => matches!(
+                response.status(),
+                reqwest::StatusCode::BAD_GATEWAY
+                    | reqwest::StatusCode::SERVICE_UNAVAILABLE
+                    | reqwest::StatusCode::GATEWAY_TIMEOUT
+            ),
+            Err(_) => true,
+        };
+        if transient && retryable && attempt < upstream.retry_attempts {
+            let delay = upstream.retry_delay(attempt);
+            tracing::warn!(
+                url = %request.url(),
+                ?delay,
+                "Transient failure requesting from github, retrying"
+            );
+            drop(permit);
+            tokio::time::sleep(delay).await;
+            continue;
+        }
+        let mut response = result?;
+        if let Some(rate_limited) = &anonymous_fallback {
+            // Private repositories look missing to anonymous requests, and anonymous requests have
+            // their own, much smaller, rate limit, so any failure is best explained by the original
+            // one.
+            if response.status().is_client_error() || response.status().is_server_error() {
+                return Err(rate_limited.clone());
+            }
+            response.headers_mut().insert(
+                DEGRADED_HEADER,
+                reqwest::header::HeaderValue::from_static("anonymous"),
+            );
+        }
+        if response.status().is_client_error() || response.status().is_server_error() {
+            telemetry::record_upstream_failure(
+                response.status().as_u16(),
+                response
+                    .headers()
+                    .get("x-github-request-id")
+                    .and_then(|value| value.to_str().ok()),
+            );
+        }
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED && !reauthenticated {
+            reauthenticated = true;
+            let rejected = request.headers().get(reqwest::header::AUTHORIZATION);
+            if let (Some(github_app), Some(rejected)) = (&upstream.github_app, rejected) {
+                if let Some(replacement) = github_app.reauthenticate(rejected).await {
+                    request
+                        .headers_mut()
+                        .insert(reqwest::header::AUTHORIZATION, replacement);
+                    continue;
+                }
+            }
+        }
+        if upstream.redirects == RedirectPolicy::Follow
+            && is_followable_redirect(response.status().as_u16())
+        {
+            if redirects >= MAX_REDIRECTS {
+                return Err((
+                    StatusCode::BAD_GATEWAY,
+                    format!("github redirected {} too many times", original_url),
+                ));
+            }
+            if let Some(next) = redirected_request(&request, &response) {
+                redirects += 1;
+                request = next;
+                // Credentials only go to github itself, never e.g. to the storage a release asset
+                // is downloaded from, which would reject them anyway.
+                let to_github = is_github_host(&original_url, request.url());
+                let headers = request.headers_mut();
+                match &original_auth {
+                    Some(auth) if to_github => {
+                        headers.insert(reqwest::header::AUTHORIZATION, auth.clone());
+                    }
+                    _ => {
+                        headers.remove(reqwest::header::AUTHORIZATION);
+                    }
+                }
+                tracing::debug!(
+                    url = %redact::redact(request.url().as_str()),
+                    "Following redirect from github"
+                );
+                attempt = 0;
+                continue;
+            }
+        }
+        if response.status() == reqwest::StatusCode::ACCEPTED
+            && retryable
+            && accepted_polls < MAX_ACCEPTED_POLLS
+        {
+            // github is still computing the response (e.g. a repository's statistics) in the
+            // background, and will have it soon.
+            let delay = ACCEPTED_POLL_BASE_DELAY * (1 << accepted_polls);
+            accepted_polls += 1;
+            tracing::info!(
+                url = %response.url(),
+                ?delay,
+                "github is still computing the response, polling again"
+            );
+            drop(permit);
+            tokio::time::sleep(delay).await;
+            continue;
+        }
+        let Some(wait) = rate_limit_wait(&response) else {
+            break (response, permit);
+        };
+        if token.is_some() && upstream.token_pool.any_available() {
+            // Another token has requests left, so there's no need to wait.
+            continue;
+        }
+        if wait > rate_limit_wait_budget || rate_limit_retries >= MAX_RATE_LIMIT_RETRIES {
+            let quota_used_up = response
+                .headers()
+                .get("x-ratelimit-remaining")
+                .is_some_and(|remaining| remaining == "0");
+            let default_auth = request
+                .headers()
+                .get(reqwest::header::AUTHORIZATION)
+                .is_some_and(|auth| upstream.is_default_auth(auth));
+            let error = rate_limited_error(response, wait).await;
+            if upstream.anonymous_fallback
+                && retryable
+                && quota_used_up
+                && default_auth
+                && anonymous_fallback.is_none()
+            {
+                tracing::warn!(
+                    url = %request.url(),
+                    "The default auth header's quota is used up, retrying anonymously"
+                );
+                request.headers_mut().remove(reqwest::header::AUTHORIZATION);
+                anonymous_fallback = Some(error);
+                drop(permit);
+                continue;
+            }
+            return Err(error);
+        }
+        rate_limit_retries += 1;
+        tracing::warn!(
+            url = %response.url(),
+            ?wait,
+            "Rate limited by github, retrying"
+        );
+        rate_limit_wait_budget -= wait;
+        drop(permit);
+        tokio::time::sleep(wait).await;
+    };
+    Ok((response, permit))
+}
+
+/// How many redirects one request follows before we give up on it.
+const MAX_REDIRECTS: u32 = 10;
+
+fn is_followable_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+fn redirect_location(response: &reqwest::Response) -> Option<Url> {
+    let location = response
+        .headers()
+        .get(reqwest::header::LOCATION)?
+        .to_str()
+        .ok()?;
+    response.url().join(location).ok()
+}
+
+/// The request to make in place of `request`, which `response` redirected, if it says where to.
+fn redirected_request(
+    request: &reqwest::Request,
+    response: &reqwest::Response,
+) -> Option<reqwest::Request> {
+    let location = redirect_location(response)?;
+    let mut next = request.try_clone()?;
+    *next.url_mut() = location;
+    if response.status() == reqwest::StatusCode::SEE_OTHER {
+        *next.method_mut() = reqwest::Method::GET;
+        *next.body_mut() = None;
+        next.headers_mut().remove(reqwest::header::CONTENT_TYPE);
+        next.headers_mut().remove(reqwest::header::CONTENT_LENGTH);
+    }
+    Some(next)
+}
+
+/// Whether `url` is the host the proxy was configured to send requests to (which may be a GitHub
+/// Enterprise Server), or elsewhere on github.com if that's api.github.com, so may be sent
+/// credentials. A GitHub Enterprise Server's tokens mean nothing to github.com, so never go there.
+fn is_github_host(original_url: &Url, url: &Url) -> bool {
+    let (Some(host), Some(original_host)) = (url.host_str(), original_url.host_str()) else {
+        return false;
+    };
+    (url.scheme() == "https" || url.scheme() == original_url.scheme())
+        && (host == original_host
+            || (original_host == "api.github.com"
+                && (host == "github.com" || host.ends_with(".github.com"))))
+}
+
+/// The least we wait after being rate limited, even if github says we can retry immediately (e.g.
+/// `Retry-After: 0`, or a reset time which has already passed by our clock), so that we never retry
+/// in a tight loop.
+const MIN_RATE_LIMIT_WAIT: Duration = Duration::from_secs(1);
+
+/// How many times one request is retried after being rate limited, however much of the wait budget
+/// is left.
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// How many times a request answered with `202 Accepted`, because github is still computing the
+/// response, is made again before giving up. The delay before each doubles, from
+/// `ACCEPTED_POLL_BASE_DELAY`, so that's 15 seconds in all.
+const MAX_ACCEPTED_POLLS: u32 = 4;
+const ACCEPTED_POLL_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Works out how long GitHub has asked us to wait before retrying, if the response says we've been
+/// rate limited (either by the primary rate limit, or a secondary rate limit).
+fn rate_limit_wait(response: &reqwest::Response) -> Option<Duration> {
+    let status = response.status();
+    if status != reqwest::StatusCode::TOO_MANY_REQUESTS && status != reqwest::StatusCode::FORBIDDEN
+    {
+        return None;
+    }
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    if let Some(retry_after) = header("retry-after") {
+        return Some(Duration::from_secs(retry_after).max(MIN_RATE_LIMIT_WAIT));
+    }
+    if header("x-ratelimit-remaining") == Some(0) {
+        if let Some(reset) = header("x-ratelimit-reset") {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            return Some(
+                Duration::from_secs(reset)
+                    .saturating_sub(now)
+                    .max(MIN_RATE_LIMIT_WAIT),
+            );
+        }
+    }
+    None
+}
+
+async fn rate_limited_error(response: reqwest::Response, wait: Duration) -> (StatusCode, String) {
+    let reset_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        + wait;
+    let github_message = response
+        .text()
+        .await
+        .unwrap_or_else(|err| format!("Failed to read response body: {}", err));
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        serde_json::json!({
+            "message": "Rate limited by github",
+            "retry_after_seconds": wait.as_secs(),
+            "reset_at": reset_at.as_secs(),
+            "github_message": github_message,
+        })
+        .to_string(),
+    )
+}
+
+enum RequestableUrl {
+    GitHubApi {
+        base_url: Url,
+        path: String,
+        query: Option<String>,
+    },
+}
+
+impl RequestableUrl {
+    fn into_string(self) -> String {
+        match self {
+            RequestableUrl::GitHubApi {
+                base_url,
+                path,
+                query,
+            } => {
+                // A leading / would make the join discard any path in the base URL.
+                let mut url = base_url
+                    .join(path.trim_start_matches('/'))
+                    // TODO: Justify this unwrap.
+                    .unwrap();
+                url.set_query(query.as_deref());
+                url.to_string()
+            }
+        }
+    }
+}
+
+/// Gives up on proxied requests which haven't started responding within the request timeout, e.g.
+/// because github is hanging or a crawl has far more pages than expected. Responses streamed
+/// straight through are limited by the upstream timeout instead, once they've started.
+///
+/// Clients may ask for a shorter timeout with `X-Timeout-Ms`, e.g. to give up at the same moment
+/// as a dashboard does, in which case they're sent whatever is cached once it's up, however stale.
+async fn enforce_request_timeout<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let requested = match request.headers().get(TIMEOUT_HEADER) {
+        Some(value) => match value.to_str().ok().and_then(|value| value.parse().ok()) {
+            Some(millis) => Some(Duration::from_millis(millis)),
+            None => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("{} should be a number of milliseconds", TIMEOUT_HEADER),
+                )
+                .into_response()
+            }
+        },
+        None => None,
+    };
+    let Some(timeout) = [state.request_timeout, requested]
+        .into_iter()
+        .flatten()
+        .min()
+    else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path().to_owned();
+    // Responses racing the same deadline inside the handler, e.g. to fall back to what's cached,
+    // are polled before the deadline is checked here, so they win ties.
+    let deadline = Instant::now() + timeout;
+    let response = tokio::time::timeout_at(deadline.into(), next.run(request));
+    let response = match requested {
+        Some(_) => DEADLINE.scope(deadline, response).await,
+        None => response.await,
+    };
+    match response {
+        Ok(response) => response,
+        Err(_) => text_response(
+            StatusCode::GATEWAY_TIMEOUT,
+            format!("Timed out after {:?} handling {}", timeout, path),
+        )
+        .into_response(),
+    }
+}
+
+const TIMEOUT_HEADER: &str = "x-timeout-ms";
+
+tokio::task_local! {
+    /// When the client asked to have been answered by, with `X-Timeout-Ms`.
+    static DEADLINE: Instant;
+}
+
+/// Answers conditional requests with a 304 when the response's ETag matches one the client has.
+async fn not_modified<B>(request: Request<B>, next: Next<B>) -> Response {
+    let if_none_match = request
+        .headers()
+        .get(axum::http::header::IF_NONE_MATCH)
+        .cloned();
+    let response = next.run(request).await;
+    let (Some(if_none_match), Some(etag)) = (
+        if_none_match,
+        response.headers().get(axum::http::header::ETAG),
+    ) else {
+        return response;
+    };
+    let matches = if_none_match.to_str().is_ok_and(|if_none_match| {
+        if_none_match.trim() == "*"
+            || if_none_match.split(',').any(|candidate| {
+                let candidate = candidate.trim();
+                candidate.strip_prefix("W/").unwrap_or(candidate).as_bytes() == etag.as_bytes()
+            })
+    });
+    if !matches {
+        return response;
+    }
+    let mut headers = response.headers().clone();
+    headers.remove(axum::http::header::CONTENT_LENGTH);
+    headers.remove(axum::http::header::CONTENT_TYPE);
+    (StatusCode::NOT_MODIFIED, headers).into_response()
+}
+
+/// A JSON response body from GitHub.
+///
+/// Arrays are merged across pages when GitHub paginates them; any other JSON value is passed
+/// through untouched.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum OpaqueJson {
+    Array(Vec<serde_json::Value>),
+    Value(serde_json::Value),
+    /// A `204 No Content` or empty response, e.g. for the contributors of an empty repository.
+    #[serde(skip)]
+    Empty,
+}
+
+#[derive(Clone)]
+struct Upstream {
+    client: reqwest::Client,
+    /// How long a single request may spend waiting for rate limits to reset before we give up and
+    /// report the rate limit to the client.
+    rate_limit_wait_budget: Duration,
+    /// Whether requests made with the default auth header are retried anonymously once its quota
+    /// is used up.
+    anonymous_fallback: bool,
+    /// How many times to try a request which fails with a network error or a 502/503/504.
+    retry_attempts: u32,
+    retry_base_delay: Duration,
+    /// How long github may take to start responding before a second copy of a request is sent,
+    /// if we hedge requests.
+    hedge_after: Option<Duration>,
+    redirects: RedirectPolicy,
+    /// How long github may take to start responding, and then again to send the body.
+    timeout: Duration,
+    /// How many pages of a response to fetch at once, when github tells us how many there are.
+    page_fetch_concurrency: usize,
+    metrics: Arc<Metrics>,
+    token_pool: Arc<TokenPool>,
+    /// Supplies the default auth header, instead of the configured ones, if set.
+    github_app: Option<Arc<GitHubApp>>,
+    /// Limits how many requests are made to github at once, if set.
+    limit: Option<Arc<UpstreamLimit>>,
+    /// Stops requests to github while it's failing, if set.
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Records github's responses, or replays them instead of making requests, if set.
+    vcr: Option<Arc<Vcr>>,
+    /// Whether a client is waiting for these requests, so that they go ahead of background ones.
+    priority: Priority,
+}
+
+impl Upstream {
+    /// Waits for a turn to make a request to github, if they're limited.
+    async fn acquire(&self) -> Result<Option<UpstreamPermit>, (StatusCode, String)> {
+        match &self.limit {
+            Some(limit) => limit.acquire(self.priority).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether `header` is a default auth header, rather than one a client sent with its request.
+    fn is_default_auth(&self, header: &axum::http::HeaderValue) -> bool {
+        let github_app_header = self
+            .github_app
+            .as_ref()
+            .and_then(|github_app| github_app.auth_header());
+        github_app_header.as_ref() == Some(header) || self.token_pool.is_default(header)
+    }
+
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .retry_base_delay
+            .saturating_mul(1 << (attempt - 1).min(16));
+        // Jitter spreads out the retries of concurrent requests which failed together.
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+struct GitHubResponse {
+    values: OpaqueJson,
+    /// The ETag of every page which made up the response, or `None` if any page lacked one.
+    page_etags: Option<Vec<PageEtag>>,
+    truncated: bool,
+    link: Option<axum::http::header::HeaderValue>,
+    /// github's rate limit headers from the last page fetched. These aren't cached, as they'd be
+    /// out of date by the time the entry was read.
+    rate_limit: HeaderMap,
+}
+
+/// Bounds on how much of a paginated response is fetched from github.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+struct PaginationLimits {
+    max_pages: Option<usize>,
+    max_items: Option<usize>,
+}
+
+impl PaginationLimits {
+    /// The most pages which could be needed to reach these limits, if each has `per_page` items.
+    fn max_pages_needed(self, per_page: usize) -> Option<usize> {
+        let for_items = self
+            .max_items
+            .filter(|_| per_page > 0)
+            .map(|max_items| max_items.div_ceil(per_page));
+        match (self.max_pages, for_items) {
+            (Some(max_pages), Some(for_items)) => Some(max_pages.min(for_items)),
+            (max_pages, for_items) => max_pages.or(for_items),
+        }
+    }
+
+    /// Removes any `max_pages=` and `max_items=` parameters from `query`, which can tighten (but
+    /// not loosen) these limits.
+    fn take_from_query(
+        self,
+        query: &mut Option<String>,
+    ) -> Result<PaginationLimits, (StatusCode, String)> {
+        let parse = |name: &str, value: Option<String>, limit: Option<usize>| match value {
+            None => Ok(limit),
+            Some(value) => match value.parse::<usize>() {
+                Ok(value) => Ok(Some(limit.map_or(value, |limit| limit.min(value)))),
+                Err(err) => Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to parse {} from {:?}: {}", name, value, err),
+                )),
+            },
+        };
+        Ok(PaginationLimits {
+            max_pages: parse(
+                "max_pages",
+                take_query_param(query, "max_pages"),
+                self.max_pages,
+            )?,
+            max_items: parse(
+                "max_items",
+                take_query_param(query, "max_items"),
+                self.max_items,
+            )?,
+        })
+    }
+
+    /// Drops any items in `page` beyond `max_items`, returning whether any were dropped, and the
+    /// limits for the pages which follow it (or `None` if no more pages may be fetched).
+    fn apply_to_page(self, page: &mut Vec<serde_json::Value>) -> (bool, Option<PaginationLimits>) {
+        let dropped_items = self
+            .max_items
+            .is_some_and(|max_items| page.len() > max_items);
+        if let Some(max_items) = self.max_items {
+            page.truncate(max_items);
+        }
+        let max_pages = match self.max_pages {
+            Some(max_pages) if max_pages <= 1 => return (dropped_items, None),
+            max_pages => max_pages.map(|max_pages| max_pages - 1),
+        };
+        let max_items = match self.max_items {
+            Some(max_items) if max_items <= page.len() => return (dropped_items, None),
+            max_items => max_items.map(|max_items| max_items - page.len()),
+        };
+        (
+            dropped_items,
+            Some(PaginationLimits {
+                max_pages,
+                max_items,
+            }),
+        )
+    }
+}
+
+/// Removes every `name=` parameter from `query`, returning the last one's value.
+fn take_query_param(query: &mut Option<String>, name: &str) -> Option<String> {
+    let query_string = query.as_deref()?;
+    let mut value = None;
+    let remaining: Vec<_> = query_string
+        .split('&')
+        .filter(|param| match param.split_once('=') {
+            Some((param_name, param_value)) if param_name == name => {
+                value = Some(param_value.to_owned());
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    *query = (!remaining.is_empty()).then(|| remaining.join("&"));
+    value
+}
+
+#[derive(Clone)]
+struct PageEtag {
+    url: String,
+    etag: axum::http::header::HeaderValue,
+}
+
+impl OpaqueJson {
+    /// GraphQL reports most failures as a 200 with an `errors` field, which shouldn't be cached.
+    fn has_no_graphql_errors(&self) -> bool {
+        match self {
+            OpaqueJson::Value(serde_json::Value::Object(object)) => !object.contains_key("errors"),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlRequest {
+    query: String,
+    #[serde(default)]
+    variables: serde_json::Value,
+    #[serde(default)]
+    operation_name: Option<String>,
+}
+
+impl GraphQlRequest {
+    fn cache_hash(&self) -> [u8; 32] {
+        // Re-serializing normalises whitespace and object key order in the variables.
+        let canonical =
+            serde_json::to_vec(self).expect("Serializing JSON values to a Vec can't fail");
+        Sha256::digest(canonical).into()
+    }
+}
+
+/// How long responses for immutable resources are cached for, whatever the route asks for.
+const IMMUTABLE_MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Paths of resources which can't change once they exist, in which `*` matches any segment, and
+/// `{sha}` matches a full SHA (abbreviated ones could become ambiguous).
+const IMMUTABLE_PATHS: &[&str] = &[
+    "repos/*/*/commits/{sha}",
+    "repos/*/*/git/commits/{sha}",
+    "repos/*/*/git/trees/{sha}",
+    "repos/*/*/git/blobs/{sha}",
+    "repos/*/*/git/tags/{sha}",
+    "repos/*/*/releases/assets/*",
+    "gists/*/{sha}",
+];
+
+/// Whether `path` is for a content-addressed resource, which can be cached forever.
+fn is_immutable(path: &str) -> bool {
+    let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+    IMMUTABLE_PATHS.iter().any(|pattern| {
+        let patterns: Vec<_> = pattern.split('/').collect();
+        patterns.len() == segments.len()
+            && patterns
+                .iter()
+                .zip(&segments)
+                .all(|(pattern, segment)| match *pattern {
+                    "*" => !segment.is_empty(),
+                    // SHA-1, or SHA-256 for repositories which use it.
+                    "{sha}" => {
+                        matches!(segment.len(), 40 | 64)
+                            && segment.bytes().all(|byte| byte.is_ascii_hexdigit())
+                    }
+                    pattern => pattern == *segment,
+                })
+    })
+}
+
+#[derive(Clone, Copy)]
+struct CachePolicy {
+    /// How old a cached response may be before it's considered stale.
+    max_duration: Duration,
+    /// Whether stale responses may be served while they're refreshed in the background.
+    stale_while_revalidate: bool,
+}
+
+/// How long a cached route's responses stay fresh for, from its path: a number of minutes, or a
+/// duration with a unit, e.g. `90s`, `5m` or `2h`.
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+struct MaxAge {
+    duration: Duration,
+    /// As it appeared in the path, for linking back to the same route.
+    segment: String,
+}
+
+impl TryFrom<String> for MaxAge {
+    type Error = String;
+
+    fn try_from(segment: String) -> Result<MaxAge, String> {
+        let duration = if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+            config::parse_duration(&format!("{}m", segment))?
+        } else {
+            config::parse_duration(&segment)?
+        };
+        if duration.is_zero() {
+            return Err("Cache durations must be more than zero".to_owned());
+        }
+        Ok(MaxAge { duration, segment })
+    }
+}
+
+impl MaxAge {
+    /// The duration, within the limits of the client's API key, if it has one.
+    fn for_client(&self, api_key: Option<Extension<Arc<ApiKey>>>) -> Duration {
+        match api_key {
+            Some(Extension(api_key)) => api_key.clamp_ttl(self.duration),
+            None => self.duration,
+        }
+    }
+}
+
+/// Everything needed to fetch a cache entry again.
+#[derive(Clone)]
+struct Refresher {
+    request_headers: HeaderMap,
+    request: UpstreamRequest,
+}
+
+/// Request headers which carry credentials, so are never kept in the cache.
+const CREDENTIAL_HEADERS: [axum::http::header::HeaderName; 3] = [
+    axum::http::header::AUTHORIZATION,
+    axum::http::header::COOKIE,
+    axum::http::header::PROXY_AUTHORIZATION,
+];
+
+impl Refresher {
+    /// A copy to keep with a cache entry, without the credentials it was fetched with, so that
+    /// tokens never sit in the cache (or in the stores it's written out to).
+    fn without_credentials(&self) -> Refresher {
+        let mut refresher = self.clone();
+        for name in CREDENTIAL_HEADERS {
+            refresher.request_headers.remove(name);
+        }
+        refresher
+    }
+
+    /// Gives a cached entry's refresher credentials again, for refreshes which aren't made on
+    /// behalf of a client, e.g. of hot entries. Only entries fetched with the default credentials
+    /// for their path can be refreshed like this; the rest are refreshed by their clients' next
+    /// requests instead.
+    fn with_default_credentials(&self, state: &AppState, key: &CacheKey) -> Option<Refresher> {
+        let mut refresher = self.clone();
+        apply_default_auth_header(state, &key.path, &mut refresher.request_headers);
+        (state.authorization_hash(&refresher.request_headers) == key.authorization_hash)
+            .then_some(refresher)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+enum UpstreamRequest {
+    Rest {
+        path: String,
+        query: Option<String>,
+        #[serde(default)]
+        limits: PaginationLimits,
+        #[serde(default)]
+        single_page: bool,
+        /// Whether each issue's comments are embedded in it, for `?include=comments`.
+        #[serde(default)]
+        include_comments: bool,
+    },
+    GraphQl(GraphQlRequest),
+    AggregateIssues {
+        repos: Vec<String>,
+        query: Option<String>,
+        limits: PaginationLimits,
+    },
+}
+
+impl UpstreamRequest {
+    fn fetch(
+        &self,
+        state: &AppState,
+        request_headers: HeaderMap,
+    ) -> BoxFuture<'static, FetchResult> {
+        match self {
+            UpstreamRequest::Rest {
+                path,
+                query,
+                limits,
+                single_page,
+                include_comments,
+            } => {
+                let fetch = fetch_from_github(
+                    state.upstream.clone(),
+                    RequestableUrl::GitHubApi {
+                        base_url: state.github_api_base_url.clone(),
+                        path: path.clone(),
+                        query: query.clone(),
+                    },
+                    request_headers.clone(),
+                    *limits,
+                    *single_page,
+                );
+                if !include_comments {
+                    return fetch;
+                }
+                let upstream = state.upstream.clone();
+                let base_url = state.github_api_base_url.clone();
+                async move {
+                    embed_comments(upstream, base_url, request_headers, fetch.await?).await
+                }
+                .boxed()
+            }
+            UpstreamRequest::GraphQl(request) => fetch_graphql(
+                state.upstream.clone(),
+                &state.github_graphql_url,
+                request_headers,
+                Bytes::from(
+                    serde_json::to_vec(request)
+                        .expect("Serializing JSON values to a Vec can't fail"),
+                ),
+            )
+            .boxed(),
+            UpstreamRequest::AggregateIssues {
+                repos,
+                query,
+                limits,
+            } => fetch_aggregate_issues(
+                state.upstream.clone(),
+                state.github_api_base_url.clone(),
+                repos.clone(),
+                query.clone(),
+                request_headers,
+                *limits,
+            ),
+        }
+    }
+
+    fn cacheable(&self, values: &OpaqueJson) -> bool {
+        match self {
+            UpstreamRequest::Rest { .. } | UpstreamRequest::AggregateIssues { .. } => true,
+            UpstreamRequest::GraphQl(_) => values.has_no_graphql_errors(),
+        }
+    }
+}
+
+type FetchResult = Result<GitHubResponse, FetchError>;
+
+/// Why a response couldn't be fetched from github.
+struct FetchError {
+    status_code: StatusCode,
+    message: String,
+    /// Where github redirected the request to, if the redirect is being passed back to the client
+    /// (with --upstream-redirects=pass) rather than followed.
+    location: Option<axum::http::HeaderValue>,
+}
+
+impl From<(StatusCode, String)> for FetchError {
+    fn from((status_code, message): (StatusCode, String)) -> FetchError {
+        FetchError {
+            status_code,
+            message,
+            location: None,
+        }
+    }
+}
+
+impl FetchError {
+    /// The response for the client: the redirect, if it's one being passed back, or else the error.
+    fn to_response(&self) -> (StatusCode, HeaderMap, Bytes) {
+        let mut response = text_response(self.status_code, self.message.clone());
+        if let Some(location) = &self.location {
+            response
+                .1
+                .insert(axum::http::header::LOCATION, location.clone());
+        }
+        response
+    }
+}
+
+type SharedResponse = Shared<BoxFuture<'static, (StatusCode, HeaderMap, Bytes)>>;
+
+#[derive(Clone)]
+struct AppState {
+    upstream: Upstream,
+    cache: Arc<dyn CacheStore>,
+    /// How many times each cache entry has been read since it was last refreshed, if we're
+    /// refreshing popular entries in the background.
+    hits: Option<Arc<Mutex<HashMap<CacheKey, u64>>>>,
+    in_flight: Arc<Mutex<HashMap<CacheKey, SharedResponse>>>,
+    metrics: Arc<Metrics>,
+    /// Replaced whenever the config file is reloaded.
+    settings: Arc<RwLock<Arc<Settings>>>,
+    invalidate_cache_on_write: bool,
+    /// Whether we're terminating TLS ourselves.
+    tls: bool,
+    /// Whether `/readyz` checks that github is reachable and accepts the default auth header.
+    readyz_check_upstream: bool,
+    github_api_base_url: Url,
+    github_graphql_url: Url,
+    /// Keys the hashes of Authorization headers in cache keys, so that they can't be checked against
+    /// guessed tokens.
+    cache_key_salt: Arc<[u8]>,
+    client_rate_limiter: Arc<ClientRateLimiter>,
+    /// Set if clients may transform responses with jq programs.
+    jq_results: Option<JqResults>,
+    /// Set if cached responses are compressed as they're cached.
+    precompressed: Option<compression::Precompressed>,
+    /// How long proxied requests may take to start responding, if they're limited.
+    request_timeout: Option<Duration>,
+    /// Whether the page after each unmerged page served from the cache is fetched in advance.
+    prefetch_next_pages: bool,
+    /// Whether the faults configured in `Settings::chaos` are injected.
+    chaos: bool,
+}
+
+impl AppState {
+    /// The same state, for refreshing or warming the cache when no client is waiting.
+    fn in_background(&self) -> AppState {
+        let mut state = self.clone();
+        state.upstream.priority = Priority::Background;
+        state
+    }
+
+    fn authorization_hash(&self, headers: &HeaderMap) -> Option<[u8; 32]> {
+        let header = headers.get(axum::http::header::AUTHORIZATION)?;
+        // GitHub App installation tokens change every hour, but they all see the same data.
+        let header = match &self.upstream.github_app {
+            Some(github_app) if github_app.auth_header().as_ref() == Some(header) => {
+                b"github-app".as_slice()
+            }
+            _ => header.as_bytes(),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.cache_key_salt)
+            .expect("HMAC accepts keys of any length");
+        mac.update(header);
+        Some(mac.finalize().into_bytes().into())
+    }
+}
+
+/// Settings which can be changed by reloading the config file.
+struct Settings {
+    /// Requests without their own Authorization header rotate between these.
+    default_auth_headers: Vec<axum::http::header::HeaderValue>,
+    /// Required as a bearer token by admin endpoints, if set.
+    admin_token: Option<String>,
+    /// Signs github's webhook deliveries; webhooks are only accepted if this is set.
+    webhook_secret: Option<String>,
+    /// How long entries are kept after they go stale, so that they can be revalidated.
+    stale_retention: Duration,
+    /// How long after entries go stale they're served if refreshing them fails, if at all.
+    stale_if_error: Option<Duration>,
+    pagination_limits: PaginationLimits,
+    /// How long 404 and 410 responses are cached for, at most.
+    negative_ttl: Ttl,
+    /// How far the TTLs of entries may be stretched or shrunk, depending on how often they change.
+    adaptive_ttl_max: Option<Duration>,
+    adaptive_ttl_min: Option<Duration>,
+    never_cache: Vec<PathPattern>,
+    routes: Vec<RouteRule>,
+    allowed_paths: Vec<PathPattern>,
+    api_keys: Vec<Arc<ApiKey>>,
+    client_rate_limit: Option<ClientRateLimit>,
+    allowed_networks: Vec<Network>,
+    trusted_proxies: Vec<Network>,
+    cors: CorsPolicy,
+    warm_jobs: Vec<WarmJob>,
+    chaos: ChaosConfig,
+}
+
+impl Settings {
+    /// Reads settings from `config`, or from the command line options which override it.
+    fn new(config: &Config, args: &SettingsArgs) -> Result<Settings, String> {
+        let default_auth_headers = if args.default_auth_header.is_empty() {
+            config
+                .auth
+                .default_header
+                .iter()
+                .chain(&config.auth.default_headers)
+                .collect::<Vec<_>>()
+        } else {
+            args.default_auth_header.iter().collect()
+        };
+        let default_auth_headers = default_auth_headers
+            .into_iter()
+            .map(|value| {
+                value.parse().map_err(|err| {
+                    format!("Failed to parse default auth header as header: {:?}", err)
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        config.chaos.validate()?;
+        Ok(Settings {
+            default_auth_headers,
+            admin_token: args
+                .admin_token
+                .clone()
+                .or_else(|| config.auth.admin_token.clone()),
+            webhook_secret: args
+                .webhook_secret
+                .clone()
+                .or_else(|| config.auth.webhook_secret.clone()),
+            stale_retention: Duration::from_secs(
+                args.stale_retention_minutes
+                    .or(config.cache.stale_retention_minutes)
+                    .unwrap_or(60)
+                    * 60,
+            ),
+            stale_if_error: args.stale_if_error.or(config.cache.stale_if_error),
+            pagination_limits: PaginationLimits {
+                max_pages: args.max_pages.or(config.pagination.max_pages),
+                max_items: args.max_items.or(config.pagination.max_items),
+            },
+            negative_ttl: args
+                .negative_cache_ttl
+                .or(config.cache.negative_ttl)
+                .unwrap_or(Ttl::For(Duration::from_secs(60))),
+            adaptive_ttl_max: args.adaptive_ttl_max.or(config.cache.adaptive_ttl_max),
+            adaptive_ttl_min: args.adaptive_ttl_min.or(config.cache.adaptive_ttl_min),
+            never_cache: config
+                .never_cache
+                .iter()
+                .cloned()
+                .chain(args.never_cache.iter().cloned())
+                .collect(),
+            routes: config.routes.clone(),
+            allowed_paths: config.allowed_paths.clone(),
+            api_keys: config.api_keys.iter().cloned().map(Arc::new).collect(),
+            client_rate_limit: config.client_rate_limit,
+            allowed_networks: config.allowed_networks.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            cors: config.cors.clone(),
+            warm_jobs: config.warm.clone(),
+            chaos: config.chaos,
+        })
+    }
+
+    /// How long `value` stays fresh for, when it was requested to stay fresh for `requested`: longer
+    /// if it hasn't changed the last few times it was refreshed, or shorter if it has, within the
+    /// adaptive TTL bounds.
+    fn fresh_for(&self, value: &CacheValue, requested: Duration) -> Duration {
+        adapt_ttl(
+            requested,
+            value.stability,
+            self.adaptive_ttl_min,
+            self.adaptive_ttl_max,
+        )
+    }
+
+    /// The first of the route rules matching `path` which has a setting, for each setting.
+    fn route_setting<T>(&self, path: &str, setting: impl Fn(&RouteRule) -> Option<T>) -> Option<T> {
+        self.routes
+            .iter()
+            .filter(|rule| rule.path.matches(path))
+            .find_map(setting)
+    }
+
+    fn pagination_limits(&self, path: &str) -> PaginationLimits {
+        PaginationLimits {
+            max_pages: self
+                .route_setting(path, |rule| rule.max_pages)
+                .or(self.pagination_limits.max_pages),
+            max_items: self
+                .route_setting(path, |rule| rule.max_items)
+                .or(self.pagination_limits.max_items),
+        }
+    }
+
+    fn never_cache(&self, path: &str) -> bool {
+        self.never_cache.iter().any(|pattern| pattern.matches(path))
+    }
+
+    /// Whether the proxy serves `path` at all, to anyone.
+    fn serves(&self, path: &str) -> bool {
+        self.allowed_paths.is_empty()
+            || self
+                .allowed_paths
+                .iter()
+                .any(|pattern| pattern.matches(path))
+    }
+
+    /// How long responses for `path` are cached when requested through the plain route.
+    fn ttl(&self, path: &str) -> Ttl {
+        self.route_setting(path, |rule| rule.ttl)
+            .unwrap_or(Ttl::Never)
+    }
+}
+
+impl AppState {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    fn record_cache_lookup(&self, result: &'static str) {
+        self.metrics
+            .cache_lookups
+            .with_label_values(&[result])
+            .inc();
+        telemetry::record_cache_lookup(result);
+    }
+
+    fn reset_hits(&self, key: &CacheKey) {
+        if let Some(hits) = &self.hits {
+            hits.lock().unwrap().remove(key);
+        }
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Deserialize, Serialize)]
+struct CacheKey {
+    /// Identifies the credentials the response was fetched with, without storing them.
+    authorization_hash: Option<[u8; 32]>,
+    path: String,
+    query: Option<String>,
+    body_hash: Option<[u8; 32]>,
+}
+
+#[derive(Clone)]
+struct CacheValue {
+    body: SerializedBody,
+    page_etags: Option<Vec<PageEtag>>,
+    max_duration: Duration,
+    refresher: Refresher,
     generated_at: std::time::Instant,
+    /// How many refreshes in a row have found the response unchanged or, if negative, changed.
+    stability: i8,
+}
+
+/// Doubles `requested` for each refresh in a row which found no change, or halves it for each which
+/// found one, without going past `min` or `max` (or `requested`, if they aren't set).
+fn adapt_ttl(
+    requested: Duration,
+    stability: i8,
+    min: Option<Duration>,
+    max: Option<Duration>,
+) -> Duration {
+    let adapted = match u32::try_from(stability) {
+        Ok(unchanged) => requested.saturating_mul(1 << unchanged),
+        Err(_) => requested / (1 << stability.unsigned_abs()),
+    };
+    let longest = max.map_or(requested, |max| max.max(requested));
+    let shortest = min.map_or(requested, |min| min.min(requested));
+    adapted.clamp(shortest, longest)
+}
+
+/// How far `CacheValue::stability` goes in either direction, beyond which TTLs wouldn't change
+/// anyway.
+const MAX_STABILITY: i8 = 16;
+
+impl CacheValue {
+    /// The stability of the entry which replaces this one, depending on whether its body changed.
+    fn refreshed_stability(&self, changed: bool) -> i8 {
+        if !changed {
+            (self.stability + 1).min(MAX_STABILITY)
+        } else if self.stability > 0 {
+            0
+        } else {
+            (self.stability - 1).max(-MAX_STABILITY)
+        }
+    }
+
+    /// The cached response, telling the client how long it will stay fresh for, if it's considered
+    /// fresh for `max_duration`, so that browsers and CDNs in front of the proxy can cache it too.
+    fn to_response(&self, max_duration: Duration) -> (StatusCode, HeaderMap, Bytes) {
+        let (status_code, mut headers, body) = self.body.to_response();
+        let age = Instant::now().duration_since(self.generated_at);
+        let remaining = max_duration.saturating_sub(age);
+        headers.insert(
+            axum::http::header::CACHE_CONTROL,
+            format!("max-age={}", remaining.as_secs()).parse().unwrap(),
+        );
+        headers.insert(axum::http::header::AGE, age.as_secs().into());
+        // HTTP dates can't be after 9999, and caches treat anything over a year as a year anyway.
+        let expires = std::time::SystemTime::now() + remaining.min(IMMUTABLE_MAX_DURATION);
+        headers.insert(
+            axum::http::header::EXPIRES,
+            httpdate::fmt_http_date(expires).parse().unwrap(),
+        );
+        (status_code, headers, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(count: usize) -> Vec<serde_json::Value> {
+        (0..count).map(serde_json::Value::from).collect()
+    }
+
+    #[test]
+    fn max_ages_default_to_minutes() {
+        let max_age =
+            |segment: &str| MaxAge::try_from(segment.to_owned()).map(|max_age| max_age.duration);
+        assert_eq!(max_age("5"), Ok(Duration::from_secs(300)));
+        assert_eq!(max_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(max_age("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(max_age("65536"), Ok(Duration::from_secs(65536 * 60)));
+        for invalid in ["0", "0s", "", "5x", "99999999999999999999"] {
+            assert!(max_age(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn raw_responses_are_passed_through() {
+        let wants_raw = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                axum::http::header::ACCEPT,
+                axum::http::HeaderValue::from_static(accept),
+            );
+            wants_raw_media_type(&headers)
+        };
+        assert!(wants_raw("application/vnd.github.raw"));
+        assert!(wants_raw("application/vnd.github.v3.diff"));
+        assert!(wants_raw("text/plain, application/vnd.github.patch; q=0.9"));
+        assert!(wants_raw("application/octet-stream"));
+        assert!(!wants_raw("application/vnd.github+json"));
+        assert!(!wants_raw("application/vnd.github.v3"));
+        assert!(!wants_raw("application/vnd.github.raw+json"));
+        assert!(!wants_raw("application/json"));
+
+        assert!(is_archive("repos/o/r/tarball/main"));
+        assert!(is_archive("/repos/o/r/zipball"));
+        assert!(is_archive("repos/o/r/tarball/feature/branch"));
+        assert!(!is_archive("repos/o/r/contents/tarball"));
+    }
+
+    #[test]
+    fn credentials_only_follow_redirects_to_github() {
+        let api = Url::parse("https://api.github.com/repos/o/r").unwrap();
+        let to = |url: &str| is_github_host(&api, &Url::parse(url).unwrap());
+        assert!(to("https://api.github.com/repositories/1"));
+        assert!(to("https://github.com/o/r/archive/main.tar.gz"));
+        assert!(!to("https://objects.githubusercontent.com/asset"));
+        assert!(!to("https://evil-github.com/"));
+        assert!(!to("http://api.github.com/repositories/1"));
+
+        let enterprise = Url::parse("http://ghe.internal/api/v3/repos/o/r").unwrap();
+        let to = |url: &str| is_github_host(&enterprise, &Url::parse(url).unwrap());
+        assert!(to("http://ghe.internal/api/v3/repositories/1"));
+        assert!(!to("https://github.com/o/r/archive/main.tar.gz"));
+        assert!(!to("https://api.github.com/repositories/1"));
+    }
+
+    #[test]
+    fn empty_responses_have_no_content() {
+        let response = GitHubResponse {
+            values: OpaqueJson::Empty,
+            page_etags: None,
+            truncated: false,
+            link: None,
+            rate_limit: HeaderMap::new(),
+        };
+        let (status_code, _, body) = serialize_for_response(&response);
+        assert_eq!(status_code, StatusCode::NO_CONTENT);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn max_items_zero_keeps_nothing() {
+        let limits = PaginationLimits {
+            max_pages: None,
+            max_items: Some(0),
+        };
+        let mut page = items(3);
+        let (dropped_items, next_limits) = limits.apply_to_page(&mut page);
+        assert!(page.is_empty());
+        assert!(dropped_items);
+        assert!(next_limits.is_none());
+    }
+
+    #[test]
+    fn max_items_carries_over_to_following_pages() {
+        let limits = PaginationLimits {
+            max_pages: None,
+            max_items: Some(5),
+        };
+        let mut page = items(3);
+        let (dropped_items, next_limits) = limits.apply_to_page(&mut page);
+        assert_eq!(page.len(), 3);
+        assert!(!dropped_items);
+        let next_limits = next_limits.unwrap();
+        assert_eq!(next_limits.max_items, Some(2));
+
+        let mut page = items(3);
+        let (dropped_items, next_limits) = next_limits.apply_to_page(&mut page);
+        assert_eq!(page.len(), 2);
+        assert!(dropped_items);
+        assert!(next_limits.is_none());
+    }
+
+    #[test]
+    fn max_pages_zero_behaves_like_one() {
+        for max_pages in [0, 1] {
+            let limits = PaginationLimits {
+                max_pages: Some(max_pages),
+                max_items: None,
+            };
+            let mut page = items(3);
+            let (dropped_items, next_limits) = limits.apply_to_page(&mut page);
+            assert_eq!(page.len(), 3, "max_pages={max_pages}");
+            assert!(!dropped_items, "max_pages={max_pages}");
+            assert!(next_limits.is_none(), "max_pages={max_pages}");
+        }
+    }
+
+    #[test]
+    fn no_limits_continue_indefinitely() {
+        let mut page = items(3);
+        let (dropped_items, next_limits) = PaginationLimits::default().apply_to_page(&mut page);
+        assert_eq!(page.len(), 3);
+        assert!(!dropped_items);
+        let next_limits = next_limits.unwrap();
+        assert_eq!(next_limits.max_pages, None);
+        assert_eq!(next_limits.max_items, None);
+    }
+
+    #[test]
+    fn query_limits_only_tighten_configured_limits() {
+        let configured = PaginationLimits {
+            max_pages: Some(5),
+            max_items: Some(100),
+        };
+        let mut query = Some("state=closed&max_pages=10&max_items=20".to_owned());
+        let limits = configured.take_from_query(&mut query).unwrap();
+        assert_eq!(limits.max_pages, Some(5));
+        assert_eq!(limits.max_items, Some(20));
+        assert_eq!(query.as_deref(), Some("state=closed"));
+    }
+
+    #[test]
+    fn query_limits_apply_without_configured_limits() {
+        let mut query = Some("max_pages=2".to_owned());
+        let limits = PaginationLimits::default()
+            .take_from_query(&mut query)
+            .unwrap();
+        assert_eq!(limits.max_pages, Some(2));
+        assert_eq!(limits.max_items, None);
+        assert_eq!(query, None);
+    }
+
+    #[test]
+    fn invalid_query_limits_are_rejected() {
+        let mut query = Some("max_items=lots".to_owned());
+        let (status_code, _) = PaginationLimits::default()
+            .take_from_query(&mut query)
+            .err()
+            .unwrap();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn numbered_page_urls_lists_every_page_from_next_to_last() {
+        let urls = numbered_page_urls(
+            "https://api.github.com/repositories/1/issues?state=closed&page=2",
+            "https://api.github.com/repositories/1/issues?state=closed&page=4",
+        );
+        assert_eq!(
+            urls.unwrap(),
+            vec![
+                "https://api.github.com/repositories/1/issues?state=closed&page=2",
+                "https://api.github.com/repositories/1/issues?state=closed&page=3",
+                "https://api.github.com/repositories/1/issues?state=closed&page=4",
+            ]
+        );
+    }
+
+    #[test]
+    fn numbered_page_urls_requires_links_to_differ_only_by_page() {
+        assert!(numbered_page_urls(
+            "https://api.github.com/repositories/1/issues?page=2&after=abc",
+            "https://api.github.com/repositories/1/issues?page=4",
+        )
+        .is_none());
+        assert!(numbered_page_urls(
+            "https://api.github.com/repositories/1/issues?page=2",
+            "https://api.github.com/repositories/2/issues?page=4",
+        )
+        .is_none());
+        assert!(numbered_page_urls(
+            "https://api.github.com/repositories/1/issues?after=abc",
+            "https://api.github.com/repositories/1/issues?after=xyz",
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn max_pages_needed_accounts_for_items_per_page() {
+        let limits = PaginationLimits {
+            max_pages: Some(5),
+            max_items: Some(7),
+        };
+        assert_eq!(limits.max_pages_needed(2), Some(4));
+        assert_eq!(limits.max_pages_needed(1), Some(5));
+        assert_eq!(limits.max_pages_needed(0), Some(5));
+        assert_eq!(PaginationLimits::default().max_pages_needed(2), None);
+    }
+
+    #[test]
+    fn aggregated_repos_are_taken_from_the_query() {
+        let mut query = Some("state=open&repos=org/a,Org/b.rs%2Corg%2Fc&per_page=100".to_owned());
+        assert_eq!(
+            take_aggregated_repos(&mut query).unwrap(),
+            ["org/a", "Org/b.rs", "org/c"]
+        );
+        assert_eq!(query.as_deref(), Some("state=open&per_page=100"));
+
+        for invalid in [
+            "state=open",
+            "repos=",
+            "repos=org",
+            "repos=org/a/b",
+            "repos=../x",
+        ] {
+            let mut query = Some(invalid.to_owned());
+            assert!(take_aggregated_repos(&mut query).is_err(), "{}", invalid);
+        }
+    }
+
+    /// How the fake github links its pages together.
+    #[derive(Clone, Copy)]
+    enum PageLinkStyle {
+        /// `next` and `last` differ only in `page`, so pages can be fetched concurrently.
+        Numbered,
+        /// `next` carries a cursor which `last` doesn't, so each page must be followed in turn.
+        Cursor,
+    }
+
+    /// Serves `pages` pages of two items each at `/items`, recording when each page's request
+    /// starts and ends. Page 2 responds slowly, so that concurrent fetches finish out of order.
+    async fn serve_pages(pages: usize, style: PageLinkStyle) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new().route(
+            "/items",
+            get({
+                let events = events.clone();
+                move |Query(params): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+                    let page: usize = params.get("page").map_or(1, |page| page.parse().unwrap());
+                    events.lock().unwrap().push(format!("start {page}"));
+                    if page == 2 {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                    let host = headers[axum::http::header::HOST].to_str().unwrap();
+                    let base = format!("http://{host}/items");
+                    let mut response_headers = HeaderMap::new();
+                    if page < pages {
+                        let next = match style {
+                            PageLinkStyle::Numbered => format!("{base}?page={}", page + 1),
+                            PageLinkStyle::Cursor => {
+                                format!("{base}?page={}&after=cursor{page}", page + 1)
+                            }
+                        };
+                        let link = format!(
+                            "<{next}>; rel=\"next\", <{base}?page={pages}>; rel=\"last\""
+                        );
+                        response_headers.insert(axum::http::header::LINK, link.parse().unwrap());
+                    }
+                    events.lock().unwrap().push(format!("end {page}"));
+                    (
+                        response_headers,
+                        axum::Json(vec![page * 10, page * 10 + 1]),
+                    )
+                }
+            }),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let base_url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        (base_url, events)
+    }
+
+    fn test_upstream() -> Upstream {
+        Upstream {
+            client: reqwest::Client::new(),
+            rate_limit_wait_budget: Duration::ZERO,
+            anonymous_fallback: false,
+            retry_attempts: 1,
+            retry_base_delay: Duration::ZERO,
+            hedge_after: None,
+            redirects: RedirectPolicy::Follow,
+            timeout: Duration::from_secs(30),
+            page_fetch_concurrency: 4,
+            metrics: Arc::new(Metrics::new()),
+            token_pool: Arc::default(),
+            github_app: None,
+            limit: None,
+            circuit_breaker: None,
+            vcr: None,
+            priority: Priority::Interactive,
+        }
+    }
+
+    fn test_state(github_api_base_url: Url, settings: Settings) -> AppState {
+        AppState {
+            upstream: test_upstream(),
+            cache: Arc::new(MemoryStore::new(1024 * 1024, None)),
+            hits: None,
+            in_flight: Arc::default(),
+            metrics: Arc::new(Metrics::new()),
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            invalidate_cache_on_write: false,
+            tls: false,
+            readyz_check_upstream: false,
+            github_graphql_url: github_api_base_url.join("graphql").unwrap(),
+            github_api_base_url,
+            cache_key_salt: Arc::from(b"salt".as_slice()),
+            client_rate_limiter: Arc::default(),
+            jq_results: None,
+            precompressed: None,
+            request_timeout: None,
+            prefetch_next_pages: false,
+            chaos: false,
+        }
+    }
+
+    async fn fetch_items(base_url: Url, limits: PaginationLimits) -> GitHubResponse {
+        let upstream = test_upstream();
+        let url = RequestableUrl::GitHubApi {
+            base_url,
+            path: "items".to_owned(),
+            query: None,
+        };
+        match fetch_from_github(upstream, url, HeaderMap::new(), limits, false).await {
+            Ok(response) => response,
+            Err(err) => panic!("Fetch failed with {}: {}", err.status_code, err.message),
+        }
+    }
+
+    fn values(response: &GitHubResponse) -> serde_json::Value {
+        match &response.values {
+            OpaqueJson::Array(values) => serde_json::Value::from(values.clone()),
+            OpaqueJson::Value(value) => panic!("Expected an array, got {}", value),
+            OpaqueJson::Empty => panic!("Expected an array, got no content"),
+        }
+    }
+
+    fn position(events: &Mutex<Vec<String>>, event: &str) -> usize {
+        let events = events.lock().unwrap();
+        events
+            .iter()
+            .position(|e| e == event)
+            .unwrap_or_else(|| panic!("No {:?} in {:?}", event, events))
+    }
+
+    #[tokio::test]
+    async fn numbered_pages_are_fetched_concurrently_and_kept_in_order() {
+        let (base_url, events) = serve_pages(4, PageLinkStyle::Numbered).await;
+        let response = fetch_items(base_url, PaginationLimits::default()).await;
+        assert_eq!(
+            values(&response),
+            serde_json::json!([10, 11, 20, 21, 30, 31, 40, 41])
+        );
+        assert!(!response.truncated);
+        assert!(position(&events, "start 3") < position(&events, "end 2"));
+        assert!(position(&events, "start 4") < position(&events, "end 2"));
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_are_followed_in_turn() {
+        let (base_url, events) = serve_pages(4, PageLinkStyle::Cursor).await;
+        let response = fetch_items(base_url, PaginationLimits::default()).await;
+        assert_eq!(
+            values(&response),
+            serde_json::json!([10, 11, 20, 21, 30, 31, 40, 41])
+        );
+        assert!(!response.truncated);
+        assert!(position(&events, "end 2") < position(&events, "start 3"));
+    }
+
+    #[tokio::test]
+    async fn max_items_stops_fetching_numbered_pages_early() {
+        let (base_url, events) = serve_pages(5, PageLinkStyle::Numbered).await;
+        let limits = PaginationLimits {
+            max_pages: None,
+            max_items: Some(3),
+        };
+        let response = fetch_items(base_url, limits).await;
+        assert_eq!(values(&response), serde_json::json!([10, 11, 20]));
+        assert!(response.truncated);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["start 1", "end 1", "start 2", "end 2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn max_pages_truncates_cursor_pages() {
+        let (base_url, events) = serve_pages(5, PageLinkStyle::Cursor).await;
+        let limits = PaginationLimits {
+            max_pages: Some(2),
+            max_items: None,
+        };
+        let response = fetch_items(base_url, limits).await;
+        assert_eq!(values(&response), serde_json::json!([10, 11, 20, 21]));
+        assert!(response.truncated);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["start 1", "end 1", "start 2", "end 2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_requests_are_hedged() {
+        // Only the first request hangs.
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/slow",
+            get({
+                let requests = requests.clone();
+                move || async move {
+                    let request = requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    if request == 0 {
+                        tokio::time::sleep(Duration::from_secs(30)).await;
+                    }
+                    format!("\"response {request}\"")
+                }
+            }),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}/slow", server.local_addr());
+        tokio::spawn(server);
+
+        let upstream = Upstream {
+            hedge_after: Some(Duration::from_millis(50)),
+            ..test_upstream()
+        };
+        let started = Instant::now();
+        let (response, _) = send_with_retries(&upstream, upstream.client.get(&url))
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "\"response 1\"");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(upstream.metrics.hedged_requests.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn uncached_routes_use_default_auth_headers() {
+        let echo_auth = |headers: HeaderMap| async move {
+            let auth = headers
+                .get(axum::http::header::AUTHORIZATION)
+                .map(|auth| auth.to_str().unwrap().to_owned());
+            axum::Json(serde_json::json!({ "auth": auth }))
+        };
+        let app = Router::new()
+            .route("/repos/a/b", get(echo_auth))
+            .route("/users/a", get(echo_auth))
+            .route("/graphql", post(echo_auth));
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let base_url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let config: Config = toml::from_str(
+            r#"
+            [[routes]]
+            path = "repos/**"
+            default_auth_header = "token route"
+            "#,
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "github-issue-proxy",
+            "--default-auth-header",
+            "token default",
+        ])
+        .unwrap();
+        let state = test_state(
+            base_url,
+            Settings::new(&config, &cli.serve.settings).unwrap(),
+        );
+        let auth = |(status_code, _, body): (StatusCode, HeaderMap, Bytes)| {
+            assert_eq!(status_code, StatusCode::OK);
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["auth"].clone()
+        };
+        let plain = |path: &str, headers: HeaderMap| {
+            rest_response(state.clone(), path.to_owned(), None, headers)
+        };
+        assert_eq!(
+            auth(plain("repos/a/b", HeaderMap::new()).await),
+            "token route"
+        );
+        assert_eq!(
+            auth(plain("users/a", HeaderMap::new()).await),
+            "token default"
+        );
+        let mut client_auth = HeaderMap::new();
+        client_auth.insert(
+            axum::http::header::AUTHORIZATION,
+            "token client".parse().unwrap(),
+        );
+        assert_eq!(auth(plain("repos/a/b", client_auth).await), "token client");
+        let graphql = graphql_handler(
+            State(state.clone()),
+            HeaderMap::new(),
+            Bytes::from_static(b"{}"),
+        )
+        .await;
+        assert_eq!(auth(graphql), "token default");
+    }
+
+    #[test]
+    fn writes_pass_on_end_to_end_headers() {
+        let mut response_headers = reqwest::header::HeaderMap::new();
+        for (name, value) in [
+            ("content-type", "application/json; charset=utf-8"),
+            ("location", "https://api.github.com/repos/a/b/issues/1"),
+            ("x-ratelimit-remaining", "4999"),
+            ("connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+            ("content-encoding", "gzip"),
+        ] {
+            response_headers.insert(name, value.parse().unwrap());
+        }
+        let headers = end_to_end_headers(&response_headers);
+        let mut names: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["content-type", "location", "x-ratelimit-remaining"]);
+    }
+
+    #[test]
+    fn content_addressed_paths_are_immutable() {
+        let sha = "0123456789abcdef0123456789ABCDEF01234567";
+        assert!(is_immutable(&format!("repos/a/b/commits/{sha}")));
+        assert!(is_immutable(&format!("/repos/a/b/git/trees/{sha}")));
+        assert!(is_immutable("repos/a/b/releases/assets/123"));
+        assert!(is_immutable(&format!("gists/abc/{sha}")));
+    }
+
+    #[test]
+    fn mutable_paths_are_not_immutable() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        assert!(!is_immutable("repos/a/b/commits/main"));
+        assert!(!is_immutable("repos/a/b/commits/0123456"));
+        assert!(!is_immutable(&format!("repos/a/b/commits/{sha}/status")));
+        assert!(!is_immutable("repos/a/b/releases/assets"));
+        assert!(!is_immutable("repos/a/b/issues"));
+        assert!(!is_immutable("gists/abc"));
+    }
+
+    fn rewritten_link(link: &str, github_api_base_url: &str, proxy_url: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(axum::http::header::LINK, link.parse().unwrap());
+        rewrite_link_header(
+            &mut headers,
+            &Url::parse(github_api_base_url).unwrap(),
+            proxy_url,
+        );
+        headers[axum::http::header::LINK]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn links_are_rewritten_to_unmerged_proxy_pages() {
+        assert_eq!(
+            rewritten_link(
+                "<https://api.github.com/repositories/1/issues?state=closed&page=2>; rel=\"next\", \
+                 <https://api.github.com/repositories/1/issues?state=closed&page=5>; rel=\"last\"",
+                "https://api.github.com/",
+                "https://proxy.example/cached/5/",
+            ),
+            "<https://proxy.example/cached/5/repositories/1/issues?state=closed&page=2&merge_pages=false>; rel=\"next\", \
+             <https://proxy.example/cached/5/repositories/1/issues?state=closed&page=5&merge_pages=false>; rel=\"last\""
+        );
+    }
+
+    #[test]
+    fn links_without_a_query_are_rewritten() {
+        assert_eq!(
+            rewritten_link(
+                "<https://api.github.com/user/repos>; rel=\"next\"",
+                "https://api.github.com/",
+                "/",
+            ),
+            "</user/repos?merge_pages=false>; rel=\"next\""
+        );
+    }
+
+    #[test]
+    fn ttls_adapt_within_bounds() {
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+        let (min, max) = (Some(minutes(2)), Some(minutes(60)));
+        assert_eq!(adapt_ttl(minutes(5), 0, min, max), minutes(5));
+        assert_eq!(adapt_ttl(minutes(5), 2, min, max), minutes(20));
+        assert_eq!(adapt_ttl(minutes(5), MAX_STABILITY, min, max), minutes(60));
+        assert_eq!(
+            adapt_ttl(minutes(5), -1, min, max),
+            Duration::from_secs(150)
+        );
+        assert_eq!(adapt_ttl(minutes(5), -MAX_STABILITY, min, max), minutes(2));
+        // TTLs are only adapted in the directions which have bounds.
+        assert_eq!(adapt_ttl(minutes(5), 3, None, None), minutes(5));
+        assert_eq!(adapt_ttl(minutes(5), -3, None, max), minutes(5));
+        // Nor are they ever adapted away from what was requested.
+        assert_eq!(adapt_ttl(minutes(90), 3, min, max), minutes(90));
+    }
+
+    #[test]
+    fn next_page_is_keyed_like_the_rewritten_link() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::LINK,
+            "<https://api.github.com/repositories/1/issues?state=closed&page=3>; rel=\"next\", \
+             <https://api.github.com/repositories/1/issues?state=closed&page=5>; rel=\"last\""
+                .parse()
+                .unwrap(),
+        );
+        let github_api_base_url = Url::parse("https://api.github.com/").unwrap();
+        assert_eq!(
+            next_page(&headers, &github_api_base_url),
+            Some((
+                "repositories/1/issues".to_owned(),
+                "state=closed&page=3&merge_pages=false".to_owned()
+            ))
+        );
+        headers.insert(
+            axum::http::header::LINK,
+            "<https://api.github.com/repositories/1/issues?page=1>; rel=\"prev\""
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(next_page(&headers, &github_api_base_url), None);
+    }
+
+    #[test]
+    fn links_keep_commas_in_urls() {
+        assert_eq!(
+            rewritten_link(
+                "<https://api.github.com/repos/a/b/issues?labels=bug,help&page=2>; rel=\"next\"",
+                "https://api.github.com/",
+                "/",
+            ),
+            "</repos/a/b/issues?labels=bug,help&page=2&merge_pages=false>; rel=\"next\""
+        );
+    }
+
+    #[test]
+    fn enterprise_links_drop_the_api_prefix() {
+        assert_eq!(
+            rewritten_link(
+                "<https://GHE.example.com:443/api/v3/repos/a/b/issues?page=2>; rel=\"next\"",
+                "https://ghe.example.com/api/v3/",
+                "http://proxy.example/",
+            ),
+            "<http://proxy.example/repos/a/b/issues?page=2&merge_pages=false>; rel=\"next\""
+        );
+    }
+
+    #[test]
+    fn links_elsewhere_are_left_alone() {
+        for link in [
+            "<https://uploads.github.com/repos/a/b/releases?page=2>; rel=\"next\"",
+            "<http://api.github.com/repos/a/b/issues?page=2>; rel=\"next\"",
+            "<https://ghe.example.com/other/repos/a/b/issues?page=2>; rel=\"next\"",
+        ] {
+            let base_url = if link.contains("ghe.example.com") {
+                "https://ghe.example.com/api/v3/"
+            } else {
+                "https://api.github.com/"
+            };
+            assert_eq!(rewritten_link(link, base_url, "/"), link);
+        }
+    }
+
+    #[test]
+    fn existing_merge_pages_parameters_are_replaced() {
+        assert_eq!(
+            rewritten_link(
+                "<https://api.github.com/repos/a/b/issues?merge_pages=true&page=2&merge_pages>; rel=\"next\"",
+                "https://api.github.com/",
+                "/",
+            ),
+            "</repos/a/b/issues?page=2&merge_pages=false>; rel=\"next\""
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_are_problems() {
+        let detail = serde_json::json!({
+            "message": "Rate limited by github",
+            "retry_after_seconds": 30,
+            "status": 403,
+        })
+        .to_string();
+        let ((status_code, headers, body), _) =
+            telemetry::collect_request_outcome("abc-123".to_owned(), async {
+                telemetry::record_upstream_failure(403, Some("C0DE:1234"));
+                problem_response(StatusCode::TOO_MANY_REQUESTS, &detail)
+            })
+            .await;
+        assert_eq!(status_code, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers["content-type"], "application/problem+json");
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "rate-limited",
+                "title": "Too Many Requests",
+                // github's fields don't override the problem's own.
+                "status": 429,
+                "detail": "Rate limited by github",
+                "retry_after_seconds": 30,
+                "upstream_status": 403,
+                "upstream_request_id": "C0DE:1234",
+                "correlation_id": "abc-123",
+            })
+        );
+
+        let (_, _, body) = problem_response(StatusCode::BAD_GATEWAY, "Not JSON");
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["type"], "bad-gateway");
+        assert_eq!(problem["detail"], "Not JSON");
+        assert!(problem.get("correlation_id").is_none());
+    }
 }
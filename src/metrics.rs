@@ -0,0 +1,148 @@
+//! Prometheus metrics, served from `/metrics`.
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use prometheus::core::Collector;
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// Requests to the proxy, by route and response status.
+    pub(crate) requests: IntCounterVec,
+    /// Lookups in the cache, by whether they were a hit, served stale, or a miss.
+    pub(crate) cache_lookups: IntCounterVec,
+    /// Requests to github, by response status (or "error" if there was no response).
+    upstream_requests: IntCounterVec,
+    upstream_latency: Histogram,
+    /// Second copies of requests to github sent because it was slow to respond to the first.
+    pub(crate) hedged_requests: IntCounter,
+    /// The remaining rate limit github reported in its latest response, by rate limit resource.
+    rate_limit_remaining: IntGaugeVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Metrics {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "github_issue_proxy_requests_total",
+                "Requests served by the proxy.",
+            ),
+            &["route", "status"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "github_issue_proxy_cache_lookups_total",
+                "Cache lookups, by whether they were a hit, served stale, a miss, refreshed at the client's request, or bypassed the cache.",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let upstream_requests = IntCounterVec::new(
+            Opts::new(
+                "github_issue_proxy_upstream_requests_total",
+                "Requests made to github.",
+            ),
+            &["status"],
+        )
+        .unwrap();
+        let upstream_latency = Histogram::with_opts(HistogramOpts::new(
+            "github_issue_proxy_upstream_request_duration_seconds",
+            "How long github took to respond to each request.",
+        ))
+        .unwrap();
+        let hedged_requests = IntCounter::new(
+            "github_issue_proxy_upstream_hedged_requests_total",
+            "Second copies of requests sent because github was slow to respond to the first.",
+        )
+        .unwrap();
+        let rate_limit_remaining = IntGaugeVec::new(
+            Opts::new(
+                "github_issue_proxy_rate_limit_remaining",
+                "The remaining github rate limit, as of its latest response.",
+            ),
+            &["resource"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry
+            .register(Box::new(upstream_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(hedged_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rate_limit_remaining.clone()))
+            .unwrap();
+        Metrics {
+            registry,
+            requests,
+            cache_lookups,
+            upstream_requests,
+            upstream_latency,
+            hedged_requests,
+            rate_limit_remaining,
+        }
+    }
+
+    /// Records the outcome of a request to github which was sent at `started`.
+    pub(crate) fn observe_upstream(&self, started: Instant, response: Option<&reqwest::Response>) {
+        self.upstream_latency
+            .observe(started.elapsed().as_secs_f64());
+        let Some(response) = response else {
+            self.upstream_requests.with_label_values(&["error"]).inc();
+            return;
+        };
+        self.upstream_requests
+            .with_label_values(&[response.status().as_str()])
+            .inc();
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        if let Some(remaining) =
+            header("x-ratelimit-remaining").and_then(|value| value.parse().ok())
+        {
+            let resource = header("x-ratelimit-resource").unwrap_or("core");
+            self.rate_limit_remaining
+                .with_label_values(&[resource])
+                .set(remaining);
+        }
+    }
+
+    /// The remaining rate limit github reported in its latest response, by rate limit resource.
+    pub(crate) fn rate_limit_remaining(&self) -> BTreeMap<String, i64> {
+        self.rate_limit_remaining
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter_map(|metric| {
+                let resource = metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == "resource")?;
+                Some((
+                    resource.get_value().to_owned(),
+                    metric.get_gauge().get_value() as i64,
+                ))
+            })
+            .collect()
+    }
+
+    pub(crate) fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .expect("Encoding metrics as text can't fail")
+    }
+}
@@ -0,0 +1,411 @@
+//! An OpenAPI description of the proxy's own routes, served from `/openapi.json`, so that client
+//! generators and API gateways can be pointed at it.
+
+use serde_json::{json, Value};
+
+/// The document, describing every route whether or not this deployment enables it.
+pub(crate) fn document() -> Value {
+    let proxied_get = |summary: &str, parameters: Vec<Value>| {
+        json!({
+            "summary": summary,
+            "parameters": parameters,
+            "responses": proxied_responses(),
+        })
+    };
+    let mut rest_parameters = vec![github_path()];
+    rest_parameters.extend(response_parameters());
+    let mut cached_parameters = vec![max_age(), github_path()];
+    cached_parameters.extend(response_parameters());
+    let mut aggregate_parameters = vec![json!({
+        "name": "repos",
+        "in": "query",
+        "required": true,
+        "description": "Comma-separated `owner/repo`s whose issues to merge, at most 100.",
+        "schema": {"type": "string"},
+    })];
+    aggregate_parameters.extend(response_parameters());
+    let mut cached_aggregate_parameters = vec![max_age()];
+    cached_aggregate_parameters.extend(aggregate_parameters.clone());
+    let write = |summary: &str| {
+        json!({
+            "summary": summary,
+            "description": "Only if the proxy was started with --allow-writes.",
+            "parameters": [github_path()],
+            "requestBody": {"content": {"application/json": {"schema": {}}}},
+            "responses": proxied_responses(),
+        })
+    };
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "github-issue-proxy",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "A caching proxy for the GitHub REST and GraphQL APIs. Paths after the route prefix are GitHub API paths, e.g. `repos/owner/repo/issues`, and array responses have every page merged.",
+        },
+        "security": [{}, {"proxyKey": []}],
+        "paths": {
+            "/{path}": {
+                "get": proxied_get("Fetch a GitHub API path, cached if its TTL is configured.", rest_parameters.clone()),
+                "post": write("Forward a write to GitHub."),
+                "put": write("Forward a write to GitHub."),
+                "patch": write("Forward a write to GitHub."),
+                "delete": write("Forward a write to GitHub."),
+            },
+            "/cached/{max_age}/{path}": {
+                "get": proxied_get("Fetch a GitHub API path, caching the response for max_age.", cached_parameters.clone()),
+                "delete": {
+                    "summary": "Purge the cached responses for a path, or with a query string, just that one.",
+                    "parameters": [max_age(), github_path()],
+                    "security": [{"adminToken": []}],
+                    "responses": {"200": purged()},
+                },
+            },
+            "/swr/{max_age}/{path}": {
+                "get": proxied_get("Like /cached, but serve stale responses while refreshing them in the background.", cached_parameters),
+            },
+            "/stream/{path}": {
+                "get": proxied_get("Fetch a GitHub API path, streaming pages as they arrive rather than merging them first.", vec![github_path(), format()]),
+            },
+            "/aggregate/issues": {
+                "get": proxied_get("Merge the issues of several repositories, newest first.", aggregate_parameters),
+            },
+            "/cached/{max_age}/aggregate/issues": {
+                "get": proxied_get("Merge the issues of several repositories, caching the response for max_age.", cached_aggregate_parameters),
+            },
+            "/batch": {
+                "post": {
+                    "summary": "Fetch several GitHub API paths at once, as if each were requested from /{path}.",
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/json": {"schema": {
+                            "type": "array",
+                            "maxItems": 100,
+                            "items": {"type": "string", "description": "A GitHub API path, with any query string."},
+                        }}},
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Each path's response.",
+                            "content": {"application/json": {"schema": {
+                                "type": "object",
+                                "additionalProperties": {
+                                    "type": "object",
+                                    "properties": {
+                                        "status": {"type": "integer"},
+                                        "cache": {"type": "string", "nullable": true},
+                                        "body": {},
+                                    },
+                                },
+                            }}},
+                        },
+                        "400": problem("The body wasn't a list of at most 100 paths."),
+                    },
+                },
+            },
+            "/graphql": {"post": graphql("Forward a GraphQL query to GitHub.", vec![])},
+            "/cached/{max_age}/graphql": {
+                "post": graphql("Forward a GraphQL query to GitHub, caching the response for max_age.", vec![max_age()]),
+            },
+            "/webhook": {
+                "post": {
+                    "summary": "Receive a GitHub webhook delivery, purging cached responses for its repository.",
+                    "security": [{}],
+                    "parameters": [{
+                        "name": "X-Hub-Signature-256",
+                        "in": "header",
+                        "required": true,
+                        "schema": {"type": "string"},
+                    }],
+                    "requestBody": {"content": {"application/json": {"schema": {}}}},
+                    "responses": {"200": purged(), "401": text("Missing or incorrect signature.")},
+                },
+            },
+            "/admin": {
+                "get": {
+                    "summary": "A dashboard of the cache and rate limit.",
+                    "security": [{}],
+                    "responses": {"200": {"description": "An HTML page.", "content": {"text/html": {}}}},
+                },
+            },
+            "/admin/cache/stats": {
+                "get": admin("Summarise the cache and the remaining rate limit.", vec![], json_object()),
+            },
+            "/admin/cache/entries": {
+                "get": admin("List cached responses, most recently fetched first.", entry_parameters(false), json_object()),
+                "delete": admin("Purge cached responses.", entry_parameters(true), purged()),
+            },
+            "/admin/cache/refresh": {
+                "post": admin("Fetch cached responses afresh.", entry_parameters(true), json_object()),
+            },
+            "/admin/warm": {
+                "post": {
+                    "summary": "Fetch responses into the cache ahead of time.",
+                    "security": [{"adminToken": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/json": {"schema": {
+                            "type": "array",
+                            "maxItems": 1000,
+                            "items": {
+                                "type": "object",
+                                "required": ["path"],
+                                "properties": {
+                                    "path": {"type": "string", "description": "A GitHub API path, with any query string."},
+                                    "ttl": {"type": "string", "description": "e.g. `10m`; by default the route rules' TTL for the path."},
+                                },
+                            },
+                        }}},
+                    },
+                    "responses": {
+                        "200": json_object(),
+                        "400": text("The body wasn't a list of at most 1000 paths to warm."),
+                        "401": text("Missing or incorrect admin token."),
+                    },
+                },
+            },
+            "/admin/purge": {
+                "post": admin("Purge every cached response for a repository.", vec![json!({
+                    "name": "repo",
+                    "in": "query",
+                    "required": true,
+                    "description": "`owner/repo`",
+                    "schema": {"type": "string"},
+                })], purged()),
+            },
+            "/metrics": {
+                "get": admin("Prometheus metrics.", vec![], text("Metrics in Prometheus' text format.")),
+            },
+            "/healthz": {"get": probe("Whether the proxy is running.")},
+            "/readyz": {"get": probe("Whether the proxy is ready to serve requests, which may include reaching GitHub.")},
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document.",
+                    "security": [{}],
+                    "responses": {"200": {"description": "An OpenAPI document.", "content": {"application/json": {}}}},
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "Problem": {
+                    "type": "object",
+                    "description": "An RFC 9457 problem. Members of GitHub's own error responses, e.g. `documentation_url`, are included too.",
+                    "properties": {
+                        "type": {"type": "string", "description": "e.g. `not-found`, `rate-limited` or `bad-gateway`."},
+                        "title": {"type": "string"},
+                        "status": {"type": "integer"},
+                        "detail": {"type": "string"},
+                        "upstream_status": {"type": "integer", "description": "The status of GitHub's failed response, if there was one."},
+                        "upstream_request_id": {"type": "string", "description": "GitHub's X-GitHub-Request-Id for that response."},
+                        "correlation_id": {"type": "string", "description": "Also returned as X-Request-Id, which clients may provide."},
+                    },
+                },
+            },
+            "securitySchemes": {
+                "proxyKey": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-Proxy-Key",
+                    "description": "Required once API keys are configured.",
+                },
+                "adminToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "Required by admin routes once an admin token is configured.",
+                },
+            },
+        },
+    })
+}
+
+fn github_path() -> Value {
+    json!({
+        "name": "path",
+        "in": "path",
+        "required": true,
+        "description": "A GitHub API path, e.g. `repos/owner/repo/issues`, which may contain slashes. Query parameters other than the proxy's are forwarded to GitHub.",
+        "schema": {"type": "string"},
+        "x-wildcard": true,
+    })
+}
+
+fn max_age() -> Value {
+    json!({
+        "name": "max_age",
+        "in": "path",
+        "required": true,
+        "description": "How long responses stay fresh: a number of minutes, or a duration with a unit, e.g. `90s`, `5m` or `2h`.",
+        "schema": {"type": "string", "pattern": "^[0-9]+[smhd]?$"},
+    })
+}
+
+fn format() -> Value {
+    query(
+        "format",
+        "How to render the response. Atom feeds need lists of issues, and iCalendar feeds lists of milestones.",
+        json!({"type": "string", "enum": ["json", "ndjson", "atom", "ical"], "default": "json"}),
+    )
+}
+
+/// The parameters which change how merged responses are fetched or rendered, rather than being
+/// forwarded to GitHub.
+fn response_parameters() -> Vec<Value> {
+    let string = json!({"type": "string"});
+    let positive = json!({"type": "integer", "minimum": 1});
+    vec![
+        format(),
+        query("merge_pages", "Whether to merge every page, or return one page with its Link header rewritten to point at the proxy.", json!({"type": "boolean", "default": true})),
+        query("max_pages", "Stop fetching after this many pages.", positive.clone()),
+        query("max_items", "Stop fetching after this many items.", positive.clone()),
+        query("proxy_page", "Return this page of the merged response, counting from 1.", positive.clone()),
+        query("proxy_per_page", "How many items each proxy_page has.", positive),
+        query("include", "Embed each issue's comments in a list of issues.", json!({"type": "string", "enum": ["comments"]})),
+        query("exclude", "Drop pull requests from a list of issues.", json!({"type": "string", "enum": ["pull_requests"]})),
+        query("sort", "Sort the merged response by a field, e.g. `created_at` or `user.login`.", string.clone()),
+        query("direction", "Which way to sort.", json!({"type": "string", "enum": ["asc", "desc"]})),
+        query("fields", "Keep only these comma-separated fields of each item, e.g. `number,title,user.login`.", string.clone()),
+        query("transform", "A jq program to reshape the response with. Only if the proxy was started with --allow-jq-transforms.", string),
+        json!({
+            "name": "X-Timeout-Ms",
+            "in": "header",
+            "description": "Give up after this many milliseconds, returning whatever is cached, however stale, or else a 504.",
+            "schema": {"type": "integer", "minimum": 0},
+        }),
+        json!({
+            "name": "filter",
+            "in": "query",
+            "description": "`filter.<field>=<value>` keeps only items whose field matches, e.g. `filter.label=bug`.",
+            "style": "form",
+            "explode": true,
+            "schema": {"type": "object", "additionalProperties": {"type": "string"}},
+        }),
+    ]
+}
+
+fn entry_parameters(path_required: bool) -> Vec<Value> {
+    vec![
+        json!({
+            "name": "path",
+            "in": "query",
+            "required": path_required,
+            "description": "The GitHub API path of the responses.",
+            "schema": {"type": "string"},
+        }),
+        query(
+            "query",
+            "Only the response with this query string. An empty one matches responses without one.",
+            json!({"type": "string"}),
+        ),
+    ]
+}
+
+fn query(name: &str, description: &str, schema: Value) -> Value {
+    json!({"name": name, "in": "query", "description": description, "schema": schema})
+}
+
+fn proxied_responses() -> Value {
+    json!({
+        "200": {
+            "description": "GitHub's response, with every page of arrays merged.",
+            "content": {
+                "application/json": {},
+                "application/x-ndjson": {},
+                "application/atom+xml": {},
+                "text/calendar": {},
+            },
+        },
+        "3XX": text("Only with --upstream-redirects=pass: github redirected the request to the URL in the body and Location header."),
+        "400": problem("A proxy parameter was invalid."),
+        "401": problem("Missing or unknown X-Proxy-Key."),
+        "403": problem("The path isn't served by the proxy or allowed for this key."),
+        "429": problem("Too many requests from this client."),
+        "502": problem("GitHub's response couldn't be used."),
+        "503": problem("Too many requests are waiting to be sent to GitHub."),
+        "504": problem("GitHub, or the proxy, took too long to respond."),
+    })
+}
+
+fn graphql(summary: &str, parameters: Vec<Value>) -> Value {
+    json!({
+        "summary": summary,
+        "parameters": parameters,
+        "requestBody": {
+            "required": true,
+            "content": {"application/json": {"schema": {
+                "type": "object",
+                "required": ["query"],
+                "properties": {"query": {"type": "string"}, "variables": {"type": "object"}},
+            }}},
+        },
+        "responses": {"200": {"description": "GitHub's response.", "content": {"application/json": {}}}},
+    })
+}
+
+fn admin(summary: &str, parameters: Vec<Value>, response: Value) -> Value {
+    json!({
+        "summary": summary,
+        "parameters": parameters,
+        "security": [{"adminToken": []}],
+        "responses": {"200": response, "401": text("Missing or incorrect admin token.")},
+    })
+}
+
+fn probe(summary: &str) -> Value {
+    json!({
+        "summary": summary,
+        "security": [{}],
+        "responses": {"200": text("ok"), "503": problem("Not ready.")},
+    })
+}
+
+fn json_object() -> Value {
+    json!({"description": "A JSON object.", "content": {"application/json": {"schema": {"type": "object"}}}})
+}
+
+fn purged() -> Value {
+    json!({
+        "description": "How many cached responses were purged.",
+        "content": {"application/json": {"schema": {
+            "type": "object",
+            "properties": {"purged": {"type": "integer"}},
+        }}},
+    })
+}
+
+fn problem(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {"application/problem+json": {"schema": {"$ref": "#/components/schemas/Problem"}}},
+    })
+}
+
+fn text(description: &str) -> Value {
+    json!({"description": description, "content": {"text/plain": {}}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_parameters_are_declared() {
+        let document = document();
+        for (path, operations) in document["paths"].as_object().unwrap() {
+            for (method, operation) in operations.as_object().unwrap() {
+                for segment in path.split('/') {
+                    let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}'))
+                    else {
+                        continue;
+                    };
+                    let declared = operation["parameters"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .any(|parameter| parameter["name"] == name && parameter["in"] == "path");
+                    assert!(declared, "{} {} doesn't declare {}", method, path, name);
+                }
+            }
+        }
+    }
+}
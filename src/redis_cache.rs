@@ -0,0 +1,128 @@
+//! A cache store backed by Redis, so that several replicas of the proxy can share one cache.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::cache::{key_digest, CacheStore, EntryCipher, StoredEntry};
+use crate::{CacheKey, CacheValue};
+
+const KEY_PREFIX: &str = "github-issue-proxy:";
+
+/// Stores each entry as a Redis hash holding both the entry and its key, so that keys can be listed
+/// without fetching every entry's (potentially large) body.
+///
+/// Redis errors are logged and treated as cache misses, rather than failing requests.
+pub(crate) struct RedisStore {
+    connection: ConnectionManager,
+    cipher: Option<EntryCipher>,
+}
+
+impl RedisStore {
+    pub(crate) async fn connect(
+        url: &str,
+        cipher: Option<EntryCipher>,
+    ) -> Result<RedisStore, redis::RedisError> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(RedisStore { connection, cipher })
+    }
+
+    fn redis_key(key: &CacheKey) -> String {
+        format!("{KEY_PREFIX}{}", key_digest(key))
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &CacheKey) -> Option<Arc<CacheValue>> {
+        let mut connection = self.connection.clone();
+        let entry: Option<Vec<u8>> = match connection.hget(Self::redis_key(key), "entry").await {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::error!(%err, "Failed to read cache entry from redis");
+                return None;
+            }
+        };
+        let entry = match StoredEntry::from_bytes(&entry?, self.cipher.as_ref()) {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::error!(%err, "Failed to parse cache entry from redis");
+                return None;
+            }
+        };
+        let (_, value, _) = entry.into_entry()?;
+        Some(Arc::new(value))
+    }
+
+    async fn insert(&self, key: CacheKey, value: Arc<CacheValue>, retention: Duration) {
+        let entry = match StoredEntry::new(&key, &value, retention).to_bytes(self.cipher.as_ref()) {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::error!(%err, "Failed to serialize cache entry");
+                return;
+            }
+        };
+        let redis_key = Self::redis_key(&key);
+        let key = serde_json::to_vec(&key).expect("Serializing JSON values to a Vec can't fail");
+        let mut connection = self.connection.clone();
+        let result: redis::RedisResult<()> = redis::pipe()
+            .atomic()
+            .hset_multiple(&redis_key, &[("key", key), ("entry", entry)])
+            .ignore()
+            .expire(&redis_key, retention.as_secs().max(1) as i64)
+            .ignore()
+            .query_async(&mut connection)
+            .await;
+        if let Err(err) = result {
+            tracing::error!(%err, "Failed to write cache entry to redis");
+        }
+    }
+
+    async fn remove(&self, key: &CacheKey) {
+        let mut connection = self.connection.clone();
+        let result: redis::RedisResult<()> = connection.del(Self::redis_key(key)).await;
+        if let Err(err) = result {
+            tracing::error!(%err, "Failed to remove cache entry from redis");
+        }
+    }
+
+    async fn keys(&self) -> Vec<CacheKey> {
+        let mut connection = self.connection.clone();
+        let redis_keys: Vec<String> = {
+            let mut iter = match connection
+                .scan_match::<_, String>(format!("{KEY_PREFIX}*"))
+                .await
+            {
+                Ok(iter) => iter,
+                Err(err) => {
+                    tracing::error!(%err, "Failed to list cache entries in redis");
+                    return Vec::new();
+                }
+            };
+            let mut redis_keys = Vec::new();
+            while let Some(redis_key) = iter.next_item().await {
+                redis_keys.push(redis_key);
+            }
+            redis_keys
+        };
+        let mut keys = Vec::with_capacity(redis_keys.len());
+        for redis_key in redis_keys {
+            let key: Option<Vec<u8>> = match connection.hget(&redis_key, "key").await {
+                Ok(key) => key,
+                Err(err) => {
+                    tracing::error!(%err, "Failed to read cache key from redis");
+                    continue;
+                }
+            };
+            // The entry may have expired since we listed it.
+            if let Some(key) = key.and_then(|key| serde_json::from_slice(&key).ok()) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
@@ -0,0 +1,160 @@
+//! A cache store backed by SQLite, for single-node deployments which want their cache to survive
+//! restarts without holding every entry in memory.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::cache::{key_digest, CacheStore, EntryCipher, StoredEntry};
+use crate::{CacheKey, CacheValue};
+
+/// Stores each entry as a row, alongside its path and a hash of its authorization header so that
+/// entries can be found by path or by token when inspecting the database.
+///
+/// SQLite errors are logged and treated as cache misses, rather than failing requests.
+pub(crate) struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+    cipher: Option<EntryCipher>,
+}
+
+impl SqliteStore {
+    pub(crate) fn open(
+        path: &Path,
+        cipher: Option<EntryCipher>,
+    ) -> Result<SqliteStore, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS cache_entries (
+                key_digest TEXT PRIMARY KEY,
+                key TEXT NOT NULL,
+                path TEXT NOT NULL,
+                auth_hash TEXT,
+                entry BLOB NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS cache_entries_expires_at ON cache_entries (expires_at);",
+        )?;
+        Ok(SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+            cipher,
+        })
+    }
+
+    /// Runs `f` against the database on a blocking thread, logging any error as `action`.
+    async fn with_connection<T, F>(&self, action: &'static str, f: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        let result = tokio::task::spawn_blocking(move || f(&connection.lock().unwrap()))
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|result| result.map_err(|err| err.to_string()));
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                tracing::error!(%err, "Failed to {} in sqlite", action);
+                None
+            }
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[async_trait]
+impl CacheStore for SqliteStore {
+    async fn get(&self, key: &CacheKey) -> Option<Arc<CacheValue>> {
+        let key_digest = key_digest(key);
+        let entry: Vec<u8> = self
+            .with_connection("read cache entry", move |connection| {
+                connection
+                    .query_row(
+                        "SELECT entry FROM cache_entries WHERE key_digest = ?1 AND expires_at > ?2",
+                        params![key_digest, unix_seconds(SystemTime::now())],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })
+            .await??;
+        let entry = match StoredEntry::from_bytes(&entry, self.cipher.as_ref()) {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::error!(%err, "Failed to parse cache entry from sqlite");
+                return None;
+            }
+        };
+        let (_, value, _) = entry.into_entry()?;
+        Some(Arc::new(value))
+    }
+
+    async fn insert(&self, key: CacheKey, value: Arc<CacheValue>, retention: Duration) {
+        let entry = match StoredEntry::new(&key, &value, retention).to_bytes(self.cipher.as_ref()) {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::error!(%err, "Failed to serialize cache entry");
+                return;
+            }
+        };
+        let key_digest = key_digest(&key);
+        let auth_hash = key.authorization_hash.map(hex::encode);
+        let path = key.path.clone();
+        let key = serde_json::to_string(&key).expect("Serializing JSON values can't fail");
+        let now = SystemTime::now();
+        self.with_connection("write cache entry", move |connection| {
+            // Expired rows are never read, so clear them out as we go to bound the database's size.
+            connection.execute(
+                "DELETE FROM cache_entries WHERE expires_at <= ?1",
+                params![unix_seconds(now)],
+            )?;
+            connection.execute(
+                "INSERT OR REPLACE INTO cache_entries
+                    (key_digest, key, path, auth_hash, entry, expires_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    key_digest,
+                    key,
+                    path,
+                    auth_hash,
+                    entry,
+                    unix_seconds(now + retention)
+                ],
+            )
+        })
+        .await;
+    }
+
+    async fn remove(&self, key: &CacheKey) {
+        let key_digest = key_digest(key);
+        self.with_connection("remove cache entry", move |connection| {
+            connection.execute(
+                "DELETE FROM cache_entries WHERE key_digest = ?1",
+                params![key_digest],
+            )
+        })
+        .await;
+    }
+
+    async fn keys(&self) -> Vec<CacheKey> {
+        let keys: Vec<String> = self
+            .with_connection("list cache entries", |connection| {
+                connection
+                    .prepare("SELECT key FROM cache_entries WHERE expires_at > ?1")?
+                    .query_map(params![unix_seconds(SystemTime::now())], |row| row.get(0))?
+                    .collect()
+            })
+            .await
+            .unwrap_or_default();
+        keys.iter()
+            .filter_map(|key| serde_json::from_str(key).ok())
+            .collect()
+    }
+}
@@ -0,0 +1,201 @@
+//! Logging, and exporting tracing spans over OTLP so that slow paginated fetches can be inspected in
+//! a tracing backend such as Jaeger or Tempo.
+
+use std::cell::{Cell, RefCell};
+use std::io::IsTerminal;
+
+use axum::http::header::HeaderMap;
+use axum::http::Request;
+use futures::Future;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::env_parse;
+use crate::redact::RedactedStderr;
+
+/// Logs to stderr, with secrets redacted, filtered by `$RUST_LOG` (defaulting to `info`) and
+/// formatted according to `$LOG_FORMAT` (`text`, `pretty` or `json`).
+///
+/// Also exports spans over OTLP/HTTP if `$OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or
+/// `$OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub(crate) fn init() {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let ansi = std::io::stderr().is_terminal();
+    let format: Box<dyn Layer<Registry> + Send + Sync> =
+        match env_parse::<String>("LOG_FORMAT").as_deref() {
+            None | Some("text") => tracing_subscriber::fmt::layer()
+                .with_ansi(ansi)
+                .with_writer(RedactedStderr)
+                .boxed(),
+            Some("pretty") => tracing_subscriber::fmt::layer()
+                .pretty()
+                .with_ansi(ansi)
+                .with_writer(RedactedStderr)
+                .boxed(),
+            Some("json") => tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(RedactedStderr)
+                .boxed(),
+            Some(format) => panic!("Unknown $LOG_FORMAT: {:?}", format),
+        };
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(&directives).unwrap_or_else(|err| {
+            panic!("Failed to parse $RUST_LOG from {:?}: {}", directives, err)
+        }),
+        Err(_) => EnvFilter::new("info"),
+    };
+
+    let endpoint = match (
+        env_parse::<String>("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"),
+        env_parse::<String>("OTEL_EXPORTER_OTLP_ENDPOINT"),
+    ) {
+        (Some(endpoint), _) => Some(endpoint),
+        (None, Some(endpoint)) => Some(format!("{}/v1/traces", endpoint.trim_end_matches('/'))),
+        (None, None) => None,
+    };
+    let otlp = endpoint.map(|endpoint| {
+        let service_name = env_parse::<String>("OTEL_SERVICE_NAME")
+            .unwrap_or_else(|| "github-issue-proxy".to_owned());
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(&endpoint),
+            )
+            .with_trace_config(
+                opentelemetry::sdk::trace::config()
+                    .with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)
+            .unwrap_or_else(|err| panic!("Failed to export traces to {}: {}", endpoint, err));
+        tracing_opentelemetry::layer().with_tracer(tracer)
+    });
+
+    tracing_subscriber::registry()
+        .with(format)
+        .with(otlp)
+        .with(filter)
+        .init();
+}
+
+/// Flushes any spans which haven't been exported yet.
+pub(crate) fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// A span for handling `request`, continuing the trace from its `traceparent` header, if any.
+pub(crate) fn request_span<B>(request: &Request<B>) -> tracing::Span {
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    span.set_parent(parent);
+    span
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// What happened while handling a request, for its log line.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct RequestOutcome {
+    /// Whether the response was served from the cache, if the route is cached.
+    pub(crate) cache: Option<&'static str>,
+    pub(crate) upstream_pages: usize,
+}
+
+/// What error responses tell the client about the request they're for, so that failures can be
+/// handled programmatically and reported.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorContext {
+    /// Identifies the request in the proxy's logs.
+    pub(crate) correlation_id: String,
+    /// The status of the last response from github which failed, if any did.
+    pub(crate) upstream_status: Option<u16>,
+    /// github's `X-GitHub-Request-Id` for that response.
+    pub(crate) upstream_request_id: Option<String>,
+}
+
+tokio::task_local! {
+    static REQUEST_OUTCOME: Cell<RequestOutcome>;
+    static ERROR_CONTEXT: RefCell<ErrorContext>;
+}
+
+/// Runs `future`, collecting what's recorded about the request it's handling, which is identified
+/// by `correlation_id`.
+///
+/// Work shared between requests (such as a fetch which several requests are waiting on) is
+/// attributed to whichever request happens to drive it.
+pub(crate) async fn collect_request_outcome<F: Future>(
+    correlation_id: String,
+    future: F,
+) -> (F::Output, RequestOutcome) {
+    let error_context = RefCell::new(ErrorContext {
+        correlation_id,
+        ..ErrorContext::default()
+    });
+    REQUEST_OUTCOME
+        .scope(
+            Cell::default(),
+            ERROR_CONTEXT.scope(error_context, async {
+                let output = future.await;
+                (output, REQUEST_OUTCOME.with(Cell::get))
+            }),
+        )
+        .await
+}
+
+/// What to tell the client about the request being handled if it fails, or `None` outside of
+/// `collect_request_outcome`.
+pub(crate) fn error_context() -> Option<ErrorContext> {
+    ERROR_CONTEXT
+        .try_with(|context| context.borrow().clone())
+        .ok()
+}
+
+pub(crate) fn record_upstream_failure(status: u16, request_id: Option<&str>) {
+    let _ = ERROR_CONTEXT.try_with(|context| {
+        let mut context = context.borrow_mut();
+        context.upstream_status = Some(status);
+        context.upstream_request_id = request_id.map(str::to_owned);
+    });
+}
+
+pub(crate) fn record_cache_lookup(result: &'static str) {
+    update_request_outcome(|outcome| outcome.cache = Some(result));
+}
+
+pub(crate) fn record_upstream_pages(pages: usize) {
+    update_request_outcome(|outcome| outcome.upstream_pages += pages);
+}
+
+/// Does nothing outside of `collect_request_outcome`, e.g. for background refreshes.
+fn update_request_outcome(f: impl FnOnce(&mut RequestOutcome)) {
+    let _ = REQUEST_OUTCOME.try_with(|outcome| {
+        let mut updated = outcome.get();
+        f(&mut updated);
+        outcome.set(updated);
+    });
+}
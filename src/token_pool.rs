@@ -0,0 +1,130 @@
+//! Rotates requests made with the default auth header between several tokens, so that deployments
+//! serving anonymous clients get the sum of the tokens' rate limits.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+use crate::rate_limit_wait;
+
+#[derive(Default)]
+pub(crate) struct TokenPool {
+    /// The default auth headers. Requests are made with the first (so that's what their cache
+    /// entries are keyed by), and swapped for whichever is next in turn just before they're sent.
+    tokens: RwLock<Vec<HeaderValue>>,
+    next: AtomicUsize,
+    /// When each token which has run out of requests can be used again.
+    exhausted_until: Mutex<HashMap<HeaderValue, SystemTime>>,
+}
+
+impl TokenPool {
+    /// Replaces the pool's tokens, e.g. when the config file is reloaded.
+    pub(crate) fn set_tokens(&self, tokens: Vec<HeaderValue>) {
+        *self.tokens.write().unwrap() = tokens;
+    }
+
+    /// If `headers` are authorized with the default auth header, swaps it for the next token which
+    /// hasn't run out of requests, returning the token used. If every token has run out, the
+    /// request is left as it is, to be rate limited.
+    pub(crate) fn rotate(&self, headers: &mut HeaderMap) -> Option<HeaderValue> {
+        let tokens = self.tokens.read().unwrap();
+        if tokens.len() < 2 || headers.get(AUTHORIZATION) != tokens.first() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let token = (0..tokens.len())
+            .map(|offset| &tokens[(start + offset) % tokens.len()])
+            .find(|token| self.available(token))?;
+        headers.insert(AUTHORIZATION, token.clone());
+        Some(token.clone())
+    }
+
+    /// Records whether `token` has run out of requests, from github's response to a request made
+    /// with it.
+    pub(crate) fn observe(&self, token: &HeaderValue, response: &reqwest::Response) {
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        let until = match rate_limit_wait(response) {
+            Some(wait) => SystemTime::now() + wait,
+            None => match (header("x-ratelimit-remaining"), header("x-ratelimit-reset")) {
+                (Some(0), Some(reset)) => UNIX_EPOCH + Duration::from_secs(reset),
+                _ => return,
+            },
+        };
+        tracing::info!(?until, "Token ran out of requests, rotating to the next");
+        self.exhausted_until
+            .lock()
+            .unwrap()
+            .insert(token.clone(), until);
+    }
+
+    /// Whether `header` is one of the default auth headers, rather than a client's own.
+    pub(crate) fn is_default(&self, header: &HeaderValue) -> bool {
+        self.tokens.read().unwrap().contains(header)
+    }
+
+    /// Whether any token can be used now, so that a rate limited request can be retried straight
+    /// away with it.
+    pub(crate) fn any_available(&self) -> bool {
+        let tokens = self.tokens.read().unwrap();
+        tokens.len() > 1 && tokens.iter().any(|token| self.available(token))
+    }
+
+    fn available(&self, token: &HeaderValue) -> bool {
+        self.exhausted_until
+            .lock()
+            .unwrap()
+            .get(token)
+            .is_none_or(|until| *until <= SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authorization(headers: &HeaderMap) -> &str {
+        headers.get(AUTHORIZATION).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn rotates_default_tokens_skipping_exhausted_ones() {
+        let pool = TokenPool::default();
+        pool.set_tokens(vec![
+            HeaderValue::from_static("token a"),
+            HeaderValue::from_static("token b"),
+        ]);
+        let mut used = Vec::new();
+        for _ in 0..4 {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_static("token a"));
+            pool.rotate(&mut headers);
+            used.push(authorization(&headers).to_owned());
+        }
+        assert_eq!(used, ["token a", "token b", "token a", "token b"]);
+
+        pool.exhausted_until.lock().unwrap().insert(
+            HeaderValue::from_static("token b"),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        for _ in 0..2 {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_static("token a"));
+            pool.rotate(&mut headers);
+            assert_eq!(authorization(&headers), "token a");
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("token client"));
+        assert_eq!(pool.rotate(&mut headers), None);
+        assert_eq!(authorization(&headers), "token client");
+    }
+}
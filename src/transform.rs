@@ -0,0 +1,724 @@
+//! Reshapes responses (mostly merged arrays) as they're served, so that one cached entry can answer
+//! differently shaped requests for the same data.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use jaq_core::load::{Arena, File, Loader};
+use jaq_core::{Compiler, Ctx, RcIter};
+use jaq_json::Val;
+
+use crate::{take_query_param, text_response, SerializedBody};
+
+/// How many bytes of jq programs' output are kept, so that they needn't be run again on the same
+/// response.
+const JQ_RESULTS_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// How many values a jq program may output, beyond which it's probably not going to stop.
+const JQ_MAX_OUTPUTS: usize = 10_000;
+/// How long a jq program may run for, beyond which it's probably not going to stop either.
+const JQ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The output of jq programs, keyed by the ETag of the response each ran on and the program.
+pub(crate) type JqResults = moka::sync::Cache<(HeaderValue, String), Result<Bytes, String>>;
+
+pub(crate) fn jq_results() -> JqResults {
+    moka::sync::Cache::builder()
+        .max_capacity(JQ_RESULTS_MAX_BYTES)
+        .weigher(
+            |(_, program): &(HeaderValue, String), output: &Result<Bytes, String>| {
+                let output_len = match output {
+                    Ok(output) => output.len(),
+                    Err(err) => err.len(),
+                };
+                u32::try_from(program.len() + output_len).unwrap_or(u32::MAX)
+            },
+        )
+        .build()
+}
+
+/// What to do to the items of an array response, from the query parameters which aren't passed on
+/// to github.
+#[derive(Clone, Default)]
+pub(crate) struct Transform {
+    /// Drops pull requests, which github's issues endpoints include, for `?exclude=pull_requests`.
+    exclude_pull_requests: bool,
+    /// Keeps only the items matching every filter, e.g. `?filter.label=bug&filter.state=open`.
+    filters: Vec<Filter>,
+    /// Orders the items across every merged page, e.g. `?sort=updated_at&direction=desc`.
+    sort: Option<Sort>,
+    /// Prunes each item down to these fields, e.g. `?fields=number,title,labels.name`.
+    fields: Option<Projection>,
+    /// Runs a jq program over the whole response, once everything else is done, e.g.
+    /// `?transform=map({number, title})`.
+    jq: Option<JqProgram>,
+}
+
+#[derive(Clone)]
+struct JqProgram {
+    program: String,
+    results: JqResults,
+}
+
+/// Matches items whose `field` (or its plural, e.g. `labels` for `label`) has `value`. Fields may
+/// be nested, e.g. `user.login`.
+#[derive(Clone)]
+struct Filter {
+    field: Vec<String>,
+    value: String,
+}
+
+/// How github sorts issues by default: newest first.
+pub(crate) const NEWEST_FIRST: Sort = Sort {
+    field: Vec::new(),
+    descending: true,
+};
+
+/// The fields to keep within an object. Each is kept whole if it maps to `None`, or else pruned
+/// in turn (as is each element, if it's an array).
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Projection(BTreeMap<String, Option<Projection>>);
+
+#[derive(Clone)]
+pub(crate) struct Sort {
+    /// A field of each item, which may be nested, e.g. `user.login`. Empty means `created_at`.
+    field: Vec<String>,
+    descending: bool,
+}
+
+impl Transform {
+    /// Removes the parameters controlling the transform from `query`. jq programs are only allowed
+    /// if there's somewhere to keep their results.
+    pub(crate) fn take_from_query(
+        query: &mut Option<String>,
+        jq_results: Option<&JqResults>,
+    ) -> Result<Transform, (StatusCode, String)> {
+        let exclude_pull_requests = match take_query_param(query, "exclude").as_deref() {
+            None => false,
+            Some("pull_requests") => true,
+            Some(value) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to parse exclude from {:?}", value),
+                ))
+            }
+        };
+        Ok(Transform {
+            exclude_pull_requests,
+            filters: Filter::take_from_query(query)?,
+            sort: Sort::take_from_query(query)?,
+            fields: Projection::take_from_query(query)?,
+            jq: JqProgram::take_from_query(query, jq_results)?,
+        })
+    }
+
+    fn transforms_items(&self) -> bool {
+        self.exclude_pull_requests
+            || !self.filters.is_empty()
+            || self.sort.is_some()
+            || self.fields.is_some()
+    }
+
+    fn apply_to_items(&self, items: &mut Vec<serde_json::Value>) {
+        if self.exclude_pull_requests {
+            items.retain(|item| item.get("pull_request").is_none());
+        }
+        items.retain(|item| self.filters.iter().all(|filter| filter.matches(item)));
+        if let Some(sort) = &self.sort {
+            sort.apply(items);
+        }
+        // Last, as filtering and sorting may use fields which aren't kept.
+        if let Some(fields) = &self.fields {
+            for item in items {
+                fields.apply(item);
+            }
+        }
+    }
+
+    /// Transforms a successful response. Only the jq program applies to responses which aren't
+    /// arrays, and other responses are left as they are.
+    pub(crate) async fn apply(
+        &self,
+        (status_code, mut headers, mut body): (StatusCode, HeaderMap, Bytes),
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        if !status_code.is_success() || status_code == StatusCode::NO_CONTENT {
+            return (status_code, headers, body);
+        }
+        if self.transforms_items() {
+            if let Ok(mut items) = serde_json::from_slice::<Vec<serde_json::Value>>(&body) {
+                self.apply_to_items(&mut items);
+                let bytes = serde_json::to_vec(&items).expect("Serializing JSON values can't fail");
+                let transformed = SerializedBody::from_bytes(Bytes::from(bytes), false);
+                headers.insert(axum::http::header::ETAG, transformed.etag);
+                body = transformed.bytes;
+            }
+        }
+        if let Some(jq) = &self.jq {
+            let output = match headers.get(axum::http::header::ETAG).cloned() {
+                Some(etag) => {
+                    let key = (etag, jq.program.clone());
+                    match jq.results.get(&key) {
+                        Some(output) => output,
+                        None => {
+                            let output = jq.run_within(body.clone(), JQ_TIMEOUT).await;
+                            jq.results.insert(key, output.clone());
+                            output
+                        }
+                    }
+                }
+                None => jq.run_within(body.clone(), JQ_TIMEOUT).await,
+            };
+            match output {
+                Ok(output) => {
+                    let transformed = SerializedBody::from_bytes(output, false);
+                    headers.insert(axum::http::header::ETAG, transformed.etag);
+                    body = transformed.bytes;
+                }
+                Err(err) => return text_response(StatusCode::BAD_REQUEST, err),
+            }
+        }
+        (status_code, headers, body)
+    }
+}
+
+impl JqProgram {
+    /// Removes the `transform` parameter from `query`, checking that it's a valid jq program.
+    fn take_from_query(
+        query: &mut Option<String>,
+        results: Option<&JqResults>,
+    ) -> Result<Option<JqProgram>, (StatusCode, String)> {
+        let Some(program) = take_query_param(query, "transform") else {
+            return Ok(None);
+        };
+        let Some(results) = results else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "jq transforms aren't enabled on this proxy".to_owned(),
+            ));
+        };
+        let program = url::form_urlencoded::parse(format!("transform={}", program).as_bytes())
+            .map(|(_, program)| program.into_owned())
+            .next()
+            .unwrap_or_default();
+        JqProgram::compile(&program, |_| ()).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+        Ok(Some(JqProgram {
+            program,
+            results: results.clone(),
+        }))
+    }
+
+    /// Compiles `program`, and runs `with_filter` on it (as the filter borrows from the arena it
+    /// was loaded into).
+    fn compile<T>(
+        program: &str,
+        with_filter: impl FnOnce(jaq_core::Filter<jaq_core::Native<Val>>) -> T,
+    ) -> Result<T, String> {
+        let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+        let arena = Arena::default();
+        let modules = loader
+            .load(
+                &arena,
+                File {
+                    code: program,
+                    path: (),
+                },
+            )
+            .map_err(|_| format!("Failed to parse transform {:?}", program))?;
+        let filter = Compiler::default()
+            .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+            .compile(modules)
+            .map_err(|errs| {
+                let undefined: Vec<_> = errs
+                    .into_iter()
+                    .flat_map(|(_, errs)| errs)
+                    .map(|(name, _)| name)
+                    .collect();
+                format!("Transform uses undefined {}", undefined.join(", "))
+            })?;
+        Ok(with_filter(filter))
+    }
+
+    /// Runs the program on `body` on a thread of its own, so that slow programs don't hold up the
+    /// runtime, giving up on it after `timeout`. jaq can't be interrupted, so the thread of a
+    /// program which never stops is left to it, but timeouts are cached like any other result so
+    /// that the program isn't run again on the same response.
+    async fn run_within(&self, body: Bytes, timeout: Duration) -> Result<Bytes, String> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let program = self.clone();
+        std::thread::Builder::new()
+            .name("jq".to_owned())
+            .spawn(move || {
+                let _ = sender.send(program.run(&body));
+            })
+            .map_err(|err| format!("Failed to run transform: {}", err))?;
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(_)) => Err("Transform failed".to_owned()),
+            Err(_) => Err(format!(
+                "Transform didn't finish within {}s",
+                timeout.as_secs_f64()
+            )),
+        }
+    }
+
+    /// Runs the program on `body`. Programs which output a single value return it, and others
+    /// return an array of their outputs.
+    fn run(&self, body: &Bytes) -> Result<Bytes, String> {
+        let input: serde_json::Value = serde_json::from_slice(body)
+            .map_err(|err| format!("Failed to parse response to transform: {}", err))?;
+        let outputs = JqProgram::compile(&self.program, |filter| {
+            let inputs = RcIter::new(core::iter::empty());
+            filter
+                .run((Ctx::new([], &inputs), Val::from(input)))
+                .take(JQ_MAX_OUTPUTS + 1)
+                .map(|output| {
+                    output
+                        .map(serde_json::Value::from)
+                        .map_err(|err| format!("Transform failed: {}", err))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })??;
+        if outputs.len() > JQ_MAX_OUTPUTS {
+            return Err(format!(
+                "Transform output more than {} values",
+                JQ_MAX_OUTPUTS
+            ));
+        }
+        let output = match <[_; 1]>::try_from(outputs) {
+            Ok([output]) => output,
+            Err(outputs) => serde_json::Value::Array(outputs),
+        };
+        Ok(Bytes::from(
+            serde_json::to_vec(&output).expect("Serializing JSON values can't fail"),
+        ))
+    }
+}
+
+impl Filter {
+    /// Removes every `filter.` parameter from `query`.
+    fn take_from_query(query: &mut Option<String>) -> Result<Vec<Filter>, (StatusCode, String)> {
+        let Some(query_string) = query.as_deref() else {
+            return Ok(Vec::new());
+        };
+        let mut filters = Vec::new();
+        let mut remaining = Vec::new();
+        for param in query_string.split('&') {
+            let Some((name, value)) = url::form_urlencoded::parse(param.as_bytes()).next() else {
+                remaining.push(param);
+                continue;
+            };
+            let Some(field) = name.strip_prefix("filter.") else {
+                remaining.push(param);
+                continue;
+            };
+            if field.split('.').any(str::is_empty) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to parse a field to filter by from {:?}", name),
+                ));
+            }
+            filters.push(Filter {
+                field: field.split('.').map(str::to_owned).collect(),
+                value: value.into_owned(),
+            });
+        }
+        *query = (!remaining.is_empty()).then(|| remaining.join("&"));
+        Ok(filters)
+    }
+
+    fn matches(&self, item: &serde_json::Value) -> bool {
+        let (first, rest) = self.field.split_first().expect("Fields are never empty");
+        let mut value = match item.get(format!("{}s", first)) {
+            Some(plural) => plural,
+            None => &item[first],
+        };
+        for segment in rest {
+            value = &value[segment];
+        }
+        self.value_matches(value)
+    }
+
+    /// Compares strings case-insensitively (as github does labels and logins), arrays by whether
+    /// any element matches, and objects by their `login`, `name` or `title`, e.g. users, labels
+    /// and milestones.
+    fn value_matches(&self, value: &serde_json::Value) -> bool {
+        match value {
+            serde_json::Value::Null => self.value == "none",
+            serde_json::Value::Bool(value) => value.to_string() == self.value,
+            serde_json::Value::Number(value) => value.to_string() == self.value,
+            serde_json::Value::String(value) => value.eq_ignore_ascii_case(&self.value),
+            serde_json::Value::Array(values) => {
+                values.iter().any(|value| self.value_matches(value))
+            }
+            serde_json::Value::Object(object) => ["login", "name", "title"]
+                .iter()
+                .filter_map(|key| object.get(*key))
+                .any(|value| self.value_matches(value)),
+        }
+    }
+}
+
+impl Projection {
+    /// Removes the `fields` parameter from `query`, e.g. `number,title,labels.name`.
+    fn take_from_query(
+        query: &mut Option<String>,
+    ) -> Result<Option<Projection>, (StatusCode, String)> {
+        let Some(fields) = take_query_param(query, "fields") else {
+            return Ok(None);
+        };
+        // Browsers percent-encode the commas.
+        let fields = url::form_urlencoded::parse(format!("fields={}", fields).as_bytes())
+            .map(|(_, fields)| fields.into_owned())
+            .next()
+            .unwrap_or_default();
+        let mut projection = Projection::default();
+        for field in fields
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let segments: Vec<_> = field.split('.').collect();
+            if segments.iter().any(|segment| segment.is_empty()) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to parse a field to keep from {:?}", field),
+                ));
+            }
+            projection.insert(&segments);
+        }
+        if projection.0.is_empty() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "fields must list at least one field".to_owned(),
+            ));
+        }
+        Ok(Some(projection))
+    }
+
+    fn insert(&mut self, segments: &[&str]) {
+        let Some((first, rest)) = segments.split_first() else {
+            return;
+        };
+        let entry = self
+            .0
+            .entry((*first).to_owned())
+            .or_insert_with(|| Some(Projection::default()));
+        match entry {
+            // Already kept whole.
+            None => {}
+            Some(_) if rest.is_empty() => *entry = None,
+            Some(projection) => projection.insert(rest),
+        }
+    }
+
+    fn apply(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(object) => {
+                object.retain(|field, _| self.0.contains_key(field));
+                for (field, value) in object.iter_mut() {
+                    if let Some(Some(projection)) = self.0.get(field) {
+                        projection.apply(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    self.apply(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Sort {
+    /// Reads the `sort` and `direction` parameters from `query`. github's own ways of sorting
+    /// issues (`created`, `updated` and `comments`) are left in the query, so that github sorts
+    /// each page (and pagination limits keep the right items), and the merged pages are sorted the
+    /// same way. Sorting by any other field is only done here, so those parameters are removed.
+    pub(crate) fn take_from_query(
+        query: &mut Option<String>,
+    ) -> Result<Option<Sort>, (StatusCode, String)> {
+        let param = |name: &str| {
+            url::form_urlencoded::parse(query.as_deref().unwrap_or_default().as_bytes())
+                .filter(|(param_name, _)| param_name == name)
+                .map(|(_, value)| value.into_owned())
+                .last()
+        };
+        let Some(sort) = param("sort") else {
+            return Ok(None);
+        };
+        let direction = param("direction");
+        let field = match sort.as_str() {
+            "created" => "created_at",
+            "updated" => "updated_at",
+            "comments" => "comments",
+            field => {
+                take_query_param(query, "sort");
+                take_query_param(query, "direction");
+                field
+            }
+        };
+        if field.split('.').any(str::is_empty) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Failed to parse a field to sort by from {:?}", sort),
+            ));
+        }
+        let descending = match direction.as_deref() {
+            None | Some("desc") => true,
+            Some("asc") => false,
+            Some(direction) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to parse direction from {:?}", direction),
+                ))
+            }
+        };
+        Ok(Some(Sort {
+            field: field.split('.').map(str::to_owned).collect(),
+            descending,
+        }))
+    }
+
+    /// Sorts `items` stably, with items lacking the field last.
+    pub(crate) fn apply(&self, items: &mut [serde_json::Value]) {
+        let created_at = ["created_at".to_owned()];
+        let field = if self.field.is_empty() {
+            &created_at[..]
+        } else {
+            &self.field[..]
+        };
+        let key = |item: &serde_json::Value| {
+            field
+                .iter()
+                .try_fold(item, |value, segment| value.get(segment))
+                .filter(|value| !value.is_null())
+                .cloned()
+        };
+        items.sort_by_cached_key(|item| {
+            let key = key(item);
+            (key.is_none(), SortKey(key, self.descending))
+        });
+    }
+}
+
+/// Orders JSON values of the same type by their values: numbers numerically, and strings (e.g.
+/// ISO 8601 timestamps) lexicographically.
+#[derive(PartialEq)]
+struct SortKey(Option<serde_json::Value>, bool);
+
+impl Eq for SortKey {}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &SortKey) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &SortKey) -> std::cmp::Ordering {
+        use serde_json::Value;
+        let ordering = match (&self.0, &other.0) {
+            (Some(Value::Number(a)), Some(Value::Number(b))) => a
+                .as_f64()
+                .unwrap_or_default()
+                .total_cmp(&b.as_f64().unwrap_or_default()),
+            (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+            (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+            _ => std::cmp::Ordering::Equal,
+        };
+        if self.1 {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excludes_pull_requests() {
+        let mut query = Some("state=open&exclude=pull_requests".to_owned());
+        let transform = Transform::take_from_query(&mut query, None).unwrap();
+        assert_eq!(query.as_deref(), Some("state=open"));
+        let mut items = vec![
+            serde_json::json!({"number": 1}),
+            serde_json::json!({"number": 2, "pull_request": {}}),
+        ];
+        transform.apply_to_items(&mut items);
+        assert_eq!(items, [serde_json::json!({"number": 1})]);
+
+        let mut query = Some("exclude=issues".to_owned());
+        assert!(Transform::take_from_query(&mut query, None).is_err());
+    }
+
+    #[test]
+    fn filters_by_every_field() {
+        let mut query = Some(
+            "filter.label=Bug&per_page=100&filter.assignee=alice&filter.user.login=bob".to_owned(),
+        );
+        let transform = Transform::take_from_query(&mut query, None).unwrap();
+        assert_eq!(query.as_deref(), Some("per_page=100"));
+        let mut items = vec![
+            serde_json::json!({
+                "number": 1,
+                "labels": [{"name": "bug"}],
+                "assignees": [{"login": "carol"}, {"login": "alice"}],
+                "user": {"login": "bob"},
+            }),
+            serde_json::json!({
+                "number": 2,
+                "labels": [{"name": "bug"}],
+                "assignees": [],
+                "user": {"login": "bob"},
+            }),
+            serde_json::json!({
+                "number": 3,
+                "labels": [{"name": "bug"}],
+                "assignees": [{"login": "alice"}],
+                "user": {"login": "dave"},
+            }),
+        ];
+        transform.apply_to_items(&mut items);
+        let numbers: Vec<_> = items.iter().map(|item| item["number"].clone()).collect();
+        assert_eq!(numbers, [1]);
+
+        let mut query = Some("filter.milestone=none&filter.state=open".to_owned());
+        let transform = Transform::take_from_query(&mut query, None).unwrap();
+        let mut items = vec![
+            serde_json::json!({"state": "open", "milestone": null}),
+            serde_json::json!({"state": "open", "milestone": {"title": "v1"}}),
+            serde_json::json!({"state": "closed", "milestone": null}),
+        ];
+        transform.apply_to_items(&mut items);
+        assert_eq!(items.len(), 1);
+    }
+
+    fn numbers(items: &[serde_json::Value]) -> Vec<u64> {
+        items
+            .iter()
+            .map(|item| item["number"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sorts_like_github_or_by_any_field() {
+        let mut items = vec![
+            serde_json::json!({"number": 1, "created_at": "2024-01-02T00:00:00Z", "comments": 5, "user": {"login": "b"}}),
+            serde_json::json!({"number": 2, "created_at": "2024-01-03T00:00:00Z", "comments": 0}),
+            serde_json::json!({"number": 3, "created_at": "2024-01-01T00:00:00Z", "comments": 10, "user": {"login": "a"}}),
+        ];
+        let sort = |query: &str| {
+            let mut query = Some(query.to_owned());
+            let sort = Sort::take_from_query(&mut query).unwrap().unwrap();
+            (sort, query)
+        };
+
+        NEWEST_FIRST.apply(&mut items);
+        assert_eq!(numbers(&items), [2, 1, 3]);
+
+        let (comments, query) = sort("state=all&sort=comments&direction=asc");
+        assert_eq!(
+            query.as_deref(),
+            Some("state=all&sort=comments&direction=asc")
+        );
+        comments.apply(&mut items);
+        assert_eq!(numbers(&items), [2, 1, 3]);
+
+        let (logins, query) = sort("sort=user.login&direction=asc&state=all");
+        assert_eq!(query.as_deref(), Some("state=all"));
+        logins.apply(&mut items);
+        assert_eq!(numbers(&items), [3, 1, 2]);
+
+        let (logins, _) = sort("sort=user.login");
+        logins.apply(&mut items);
+        assert_eq!(numbers(&items), [1, 3, 2]);
+
+        let mut query = Some("sort=comments&direction=up".to_owned());
+        assert!(Sort::take_from_query(&mut query).is_err());
+    }
+
+    #[test]
+    fn keeps_only_the_fields_asked_for() {
+        let mut query =
+            Some("fields=number%2Clabels.name,assignee.login,user,user.login".to_owned());
+        let transform = Transform::take_from_query(&mut query, None).unwrap();
+        assert_eq!(query, None);
+        let mut items = vec![serde_json::json!({
+            "number": 1,
+            "title": "Broken",
+            "labels": [{"name": "bug", "color": "f00"}, {"name": "p1", "color": "0f0"}],
+            "assignee": {"login": "alice", "id": 1},
+            "user": {"login": "bob", "id": 2},
+        })];
+        transform.apply_to_items(&mut items);
+        assert_eq!(
+            items,
+            [serde_json::json!({
+                "number": 1,
+                "labels": [{"name": "bug"}, {"name": "p1"}],
+                "assignee": {"login": "alice"},
+                "user": {"login": "bob", "id": 2},
+            })]
+        );
+
+        for invalid in ["fields=", "fields=labels..name"] {
+            let mut query = Some(invalid.to_owned());
+            assert!(
+                Transform::take_from_query(&mut query, None).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn jq_programs_reshape_whole_responses() {
+        let results = jq_results();
+        let mut query = Some(
+            "transform=map(select(.state%20==%20%22open%22)%20|%20.number)&exclude=pull_requests"
+                .to_owned(),
+        );
+        let transform = Transform::take_from_query(&mut query, Some(&results)).unwrap();
+        let body = serde_json::json!([
+            {"number": 1, "state": "open"},
+            {"number": 2, "state": "closed"},
+            {"number": 3, "state": "open", "pull_request": {}},
+        ]);
+        let response = (
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::from(body.to_string()),
+        );
+        let (status_code, _, body) = transform.apply(response).await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body, "[1]");
+        results.run_pending_tasks();
+        assert_eq!(results.entry_count(), 1);
+
+        let mut query = Some("transform=.[]%20|".to_owned());
+        assert!(Transform::take_from_query(&mut query, Some(&results)).is_err());
+        let mut query = Some("transform=.".to_owned());
+        assert!(Transform::take_from_query(&mut query, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn jq_programs_which_never_stop_time_out() {
+        for program in ["last(range(1e15))", "def f: f; f"] {
+            let program = JqProgram {
+                program: program.to_owned(),
+                results: jq_results(),
+            };
+            let err = program
+                .run_within(Bytes::from_static(b"[]"), Duration::from_millis(100))
+                .await
+                .unwrap_err();
+            assert_eq!(err, "Transform didn't finish within 0.1s");
+        }
+    }
+}
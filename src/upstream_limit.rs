@@ -0,0 +1,199 @@
+//! Limits how many requests are made to github at once, queueing the rest, so that a burst of
+//! cache misses (each of which may fan out into many pages) can't open hundreds of connections.
+//!
+//! Requests made on behalf of a waiting client go ahead of background ones (refreshing and warming
+//! the cache), which may also only use half of the limit, so that background work never holds up
+//! real users for long.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use axum::http::StatusCode;
+use tokio::sync::oneshot;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// A client is waiting for the response.
+    #[default]
+    Interactive,
+    /// Refreshing or warming the cache, which nobody is waiting for.
+    Background,
+}
+
+pub(crate) struct UpstreamLimit {
+    queues: Arc<Mutex<Queues>>,
+    /// How many interactive requests may wait for a turn before more are turned away.
+    max_queued: usize,
+}
+
+struct Queues {
+    max_concurrent: usize,
+    max_background: usize,
+    in_use: usize,
+    background_in_use: usize,
+    interactive: VecDeque<oneshot::Sender<UpstreamPermit>>,
+    background: VecDeque<oneshot::Sender<UpstreamPermit>>,
+}
+
+/// A turn to make a request to github, which lasts until it's dropped.
+pub(crate) struct UpstreamPermit {
+    /// Taken if the permit was never handed over, so has already been given back.
+    queues: Option<Arc<Mutex<Queues>>>,
+    priority: Priority,
+}
+
+impl UpstreamLimit {
+    pub(crate) fn new(max_concurrent: usize, max_queued: usize) -> UpstreamLimit {
+        UpstreamLimit {
+            queues: Arc::new(Mutex::new(Queues {
+                max_concurrent,
+                max_background: (max_concurrent / 2).max(1),
+                in_use: 0,
+                background_in_use: 0,
+                interactive: VecDeque::new(),
+                background: VecDeque::new(),
+            })),
+            max_queued,
+        }
+    }
+
+    /// Waits for a turn to make a request to github, or fails if too many interactive requests are
+    /// waiting already.
+    pub(crate) async fn acquire(
+        &self,
+        priority: Priority,
+    ) -> Result<UpstreamPermit, (StatusCode, String)> {
+        let receiver = {
+            let mut queues = self.queues.lock().unwrap();
+            if queues.can_start(priority) {
+                return Ok(queues.start(&self.queues, priority));
+            }
+            let (sender, receiver) = oneshot::channel();
+            match priority {
+                Priority::Interactive => {
+                    // Forget requests which were cancelled while they waited.
+                    queues.interactive.retain(|waiter| !waiter.is_closed());
+                    if queues.interactive.len() >= self.max_queued {
+                        return Err((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "Too many requests are waiting to be sent to github; try again shortly"
+                                .to_owned(),
+                        ));
+                    }
+                    queues.interactive.push_back(sender);
+                }
+                Priority::Background => {
+                    queues.background.retain(|waiter| !waiter.is_closed());
+                    queues.background.push_back(sender);
+                }
+            }
+            receiver
+        };
+        Ok(receiver
+            .await
+            .expect("Waiters are only dropped once they've been sent a permit"))
+    }
+}
+
+impl Queues {
+    fn can_start(&self, priority: Priority) -> bool {
+        match priority {
+            Priority::Interactive => self.in_use < self.max_concurrent,
+            Priority::Background => {
+                self.in_use < self.max_concurrent
+                    && self.background_in_use < self.max_background
+                    && self.interactive.is_empty()
+                    && self.background.is_empty()
+            }
+        }
+    }
+
+    fn start(&mut self, queues: &Arc<Mutex<Queues>>, priority: Priority) -> UpstreamPermit {
+        self.in_use += 1;
+        if priority == Priority::Background {
+            self.background_in_use += 1;
+        }
+        UpstreamPermit {
+            queues: Some(queues.clone()),
+            priority,
+        }
+    }
+
+    fn finish(&mut self, priority: Priority) {
+        self.in_use -= 1;
+        if priority == Priority::Background {
+            self.background_in_use -= 1;
+        }
+    }
+
+    /// Hands out turns which have been given back, interactive requests first.
+    fn dispatch(&mut self, queues: &Arc<Mutex<Queues>>) {
+        while self.in_use < self.max_concurrent {
+            let (waiter, priority) = if let Some(waiter) = self.interactive.pop_front() {
+                (waiter, Priority::Interactive)
+            } else if self.background_in_use < self.max_background {
+                match self.background.pop_front() {
+                    Some(waiter) => (waiter, Priority::Background),
+                    None => return,
+                }
+            } else {
+                return;
+            };
+            let permit = self.start(queues, priority);
+            if let Err(mut permit) = waiter.send(permit) {
+                // The request was cancelled while it waited.
+                permit.queues = None;
+                self.finish(priority);
+            }
+        }
+    }
+}
+
+impl Drop for UpstreamPermit {
+    fn drop(&mut self) {
+        let Some(queues) = self.queues.take() else {
+            return;
+        };
+        let mut locked = queues.lock().unwrap();
+        locked.finish(self.priority);
+        locked.dispatch(&queues);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn interactive_requests_go_first_and_sheds_the_rest() {
+        let limit = Arc::new(UpstreamLimit::new(3, 1));
+        let queue = |priority| {
+            let limit = limit.clone();
+            tokio::spawn(async move { limit.acquire(priority).await })
+        };
+        let first = limit.acquire(Priority::Interactive).await.unwrap();
+        // Background requests may only use half of the limit.
+        let background = limit.acquire(Priority::Background).await.unwrap();
+        let queued_background = queue(Priority::Background);
+        while limit.queues.lock().unwrap().background.is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let _second = limit.acquire(Priority::Interactive).await.unwrap();
+        let queued_interactive = queue(Priority::Interactive);
+        while limit.queues.lock().unwrap().interactive.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let (status_code, _) = limit.acquire(Priority::Interactive).await.err().unwrap();
+        assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+
+        // The first turn given back goes to the interactive request, even though the background
+        // one was waiting first.
+        drop(background);
+        let _third = queued_interactive.await.unwrap().unwrap();
+        assert!(!queued_background.is_finished());
+        drop(first);
+        drop(queued_background.await.unwrap().unwrap());
+        assert_eq!(limit.queues.lock().unwrap().in_use, 2);
+    }
+}
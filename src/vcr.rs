@@ -0,0 +1,260 @@
+//! Records github's responses to disk, and replays them without touching github, for demos,
+//! offline development, and deterministic tests of the proxy's own clients.
+//!
+//! Each response is kept in its own JSON file, named for the request it answered: its method,
+//! URL, body, and the headers which change what github responds with. Credentials aren't part of
+//! the name, so responses recorded with one token are replayed for any other (or none).
+
+use std::path::PathBuf;
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Request headers which change github's response, so are part of what it's recorded as.
+const VARYING_HEADERS: [&str; 3] = ["accept", "if-none-match", "x-github-api-version"];
+
+/// Response headers which aren't worth recording, or shouldn't be.
+const UNRECORDED_HEADERS: [&str; 4] = ["set-cookie", "date", "content-length", "transfer-encoding"];
+
+pub(crate) struct Vcr {
+    dir: PathBuf,
+    replaying: bool,
+}
+
+/// Identifies a request, which each recorded response is filed under.
+pub(crate) struct RecordedRequest {
+    method: String,
+    url: String,
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Recording {
+    method: String,
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// The body, if it's UTF-8, or else `body_hex`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_hex: Option<String>,
+}
+
+impl Vcr {
+    /// Records every response from github into `dir`, creating it if need be.
+    pub(crate) fn record(dir: PathBuf) -> std::io::Result<Vcr> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Vcr {
+            dir,
+            replaying: false,
+        })
+    }
+
+    /// Answers every request to github with the response recorded for it in `dir`.
+    pub(crate) fn replay(dir: PathBuf) -> Vcr {
+        Vcr {
+            dir,
+            replaying: true,
+        }
+    }
+
+    pub(crate) fn replaying(&self) -> bool {
+        self.replaying
+    }
+
+    /// The response recorded for `request`, or a 502 if there isn't one.
+    pub(crate) async fn play(
+        &self,
+        request: &RecordedRequest,
+    ) -> Result<reqwest::Response, (StatusCode, String)> {
+        let path = self.dir.join(&request.name);
+        let recording = match tokio::fs::read(&path).await {
+            Ok(recording) => recording,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err((
+                    StatusCode::BAD_GATEWAY,
+                    format!(
+                        "Nothing was recorded for {} {} (in {})",
+                        request.method,
+                        request.url,
+                        path.display()
+                    ),
+                ))
+            }
+            Err(err) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to read {}: {}", path.display(), err),
+                ))
+            }
+        };
+        let recording: Recording = serde_json::from_slice(&recording).map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to parse {}: {}", path.display(), err),
+            )
+        })?;
+        let body = match (recording.body, recording.body_hex) {
+            (Some(body), _) => body.into_bytes(),
+            (None, Some(body_hex)) => hex::decode(body_hex).map_err(|err| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to decode body of {}: {}", path.display(), err),
+                )
+            })?,
+            (None, None) => Vec::new(),
+        };
+        let mut response = axum::http::Response::builder().status(recording.status);
+        for (name, value) in &recording.headers {
+            response = response.header(name, value);
+        }
+        let response = response.body(body).map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Invalid response in {}: {}", path.display(), err),
+            )
+        })?;
+        Ok(reqwest::Response::from(response))
+    }
+
+    /// Writes `body` to disk as the response to `request`, and returns a response like the one it
+    /// was read from to carry on with.
+    pub(crate) async fn save(
+        &self,
+        request: &RecordedRequest,
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+        body: Vec<u8>,
+    ) -> reqwest::Response {
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .filter(|(name, _)| !UNRECORDED_HEADERS.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+        let (text, body_hex) = match std::str::from_utf8(&body) {
+            Ok(text) => (Some(text.to_owned()), None),
+            Err(_) => (None, Some(hex::encode(&body))),
+        };
+        let recording = Recording {
+            method: request.method.clone(),
+            url: request.url.clone(),
+            status: status.as_u16(),
+            headers: headers.clone(),
+            body: text,
+            body_hex,
+        };
+        let path = self.dir.join(&request.name);
+        let recording =
+            serde_json::to_vec_pretty(&recording).expect("Recordings can always be serialized");
+        if let Err(err) = tokio::fs::write(&path, recording).await {
+            tracing::warn!(path = %path.display(), "Failed to record response: {}", err);
+        }
+        let mut response = axum::http::Response::builder().status(status.as_u16());
+        for (name, value) in &headers {
+            response = response.header(name, value);
+        }
+        reqwest::Response::from(
+            response
+                .body(body)
+                .expect("The response was built from a valid one"),
+        )
+    }
+}
+
+impl RecordedRequest {
+    pub(crate) fn new(request: &reqwest::Request) -> RecordedRequest {
+        let mut hash = Sha256::new();
+        hash.update(request.method().as_str());
+        hash.update([0]);
+        hash.update(request.url().as_str());
+        for name in VARYING_HEADERS {
+            for value in request.headers().get_all(name) {
+                hash.update([0]);
+                hash.update(name);
+                hash.update(value.as_bytes());
+            }
+        }
+        if let Some(body) = request.body().and_then(|body| body.as_bytes()) {
+            hash.update([0]);
+            hash.update(body);
+        }
+        RecordedRequest {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            name: format!("{}.json", hex::encode(hash.finalize())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_recorded_responses() {
+        let dir = std::env::temp_dir().join(format!("vcr-{:x}", rand::random::<u64>()));
+        let client = reqwest::Client::new();
+        let request = client
+            .get("https://api.github.com/repos/a/b/issues?page=2")
+            .header("accept", "application/vnd.github.raw+json")
+            .header("authorization", "token secret")
+            .build()
+            .unwrap();
+        let recorded = RecordedRequest::new(&request);
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("etag", "\"abc\"".parse().unwrap());
+        headers.insert("set-cookie", "session=1".parse().unwrap());
+        let recorder = Vcr::record(dir.clone()).unwrap();
+        let response = recorder
+            .save(
+                &recorded,
+                reqwest::StatusCode::OK,
+                &headers,
+                b"[1, 2]".to_vec(),
+            )
+            .await;
+        assert_eq!(response.text().await.unwrap(), "[1, 2]");
+        recorder
+            .save(
+                &RecordedRequest::new(&client.get("https://api.github.com/zen").build().unwrap()),
+                reqwest::StatusCode::OK,
+                &reqwest::header::HeaderMap::new(),
+                vec![0xff, 0],
+            )
+            .await;
+
+        let player = Vcr::replay(dir.clone());
+        // Credentials don't change which response is replayed.
+        let unauthenticated = client
+            .get("https://api.github.com/repos/a/b/issues?page=2")
+            .header("accept", "application/vnd.github.raw+json")
+            .build()
+            .unwrap();
+        let response = player
+            .play(&RecordedRequest::new(&unauthenticated))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["etag"], "\"abc\"");
+        assert!(!response.headers().contains_key("set-cookie"));
+        assert_eq!(response.text().await.unwrap(), "[1, 2]");
+        let zen = client.get("https://api.github.com/zen").build().unwrap();
+        let response = player.play(&RecordedRequest::new(&zen)).await.unwrap();
+        assert_eq!(response.bytes().await.unwrap().as_ref(), [0xff, 0]);
+
+        let json = client
+            .get("https://api.github.com/repos/a/b/issues?page=2")
+            .build()
+            .unwrap();
+        let (status_code, _) = player
+            .play(&RecordedRequest::new(&json))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status_code, StatusCode::BAD_GATEWAY);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}